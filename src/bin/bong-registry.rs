@@ -0,0 +1,39 @@
+//! The registry that bong servers register with, and that the server browser lists the games of.
+//!
+//! Run it with `cargo run --bin bong-registry -- --bind 0.0.0.0:5597`.
+use bong::registry;
+use clap::Parser;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+/// How long a game is listed after its last heartbeat, by default.
+const EXPIRY: Duration = Duration::from_secs(15);
+
+/// Lists the open bong games.
+#[derive(Parser, Debug)]
+#[clap(name = "bong-registry", version)]
+struct Cli {
+    /// The address to listen on.
+    #[clap(long, default_value = registry::DEFAULT_REGISTRY, value_name = "ADDRESS")]
+    bind: SocketAddr,
+    /// How many seconds a game is listed after its last heartbeat.
+    #[clap(long, default_value_t = EXPIRY.as_secs(), value_name = "SECS")]
+    expiry: u64,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let socket = match UdpSocket::bind(cli.bind) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Failed to bind {}: {}", cli.bind, e);
+            std::process::exit(1);
+        }
+    };
+    println!("Listening on {}", cli.bind);
+
+    if let Err(e) = registry::serve(socket, Duration::from_secs(cli.expiry)) {
+        eprintln!("Failed to run the registry: {}", e);
+        std::process::exit(1);
+    }
+}
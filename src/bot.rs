@@ -0,0 +1,270 @@
+//! A computer controlled opponent, for playing without a second person.
+//!
+//! A bot game is a host game on the local machine, with the bot in the second player slot.
+//! The bot only exists on the server, which moves its paddle directly.
+use crate::game::{Ball, Paddle, Paused, Target, Team};
+use crate::level::CurrentLevel;
+use crate::lobby::Players;
+use crate::rounds::RoundOverR;
+use crate::GameState;
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use carrier_pigeon::{CId, Server};
+use heron::*;
+use rand::Rng;
+use std::f32::consts::PI;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The connection id of the bot, which no real connection gets.
+pub const BOT_CID: CId = CId::MAX;
+/// The radius of the ball.
+const BALL_RADIUS: f32 = 10.0;
+/// How far ahead the bot predicts the ball.
+const PREDICT_TIME: f32 = 3.0;
+/// The time step of the prediction.
+const PREDICT_STEP: f32 = 1.0 / 240.0;
+/// How fast the bot can move and turn its paddle, the same as a player.
+const MOVE_SPEED: f32 = 14.0 * 60.0;
+const TURN_SPEED: f32 = PI / 72.0 * 60.0;
+
+pub struct BotPlugin;
+
+impl Plugin for BotPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Difficulty>()
+            .add_system_set(SystemSet::on_enter(GameState::Game).with_system(setup_brain))
+            .add_system_set(SystemSet::on_update(GameState::Game).with_system(drive_bot));
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, Default)]
+pub enum Difficulty {
+    Easy,
+    #[default]
+    Medium,
+    Hard,
+}
+
+impl Difficulty {
+    /// Gets the next difficulty, wrapping around.
+    pub fn next(&self) -> Self {
+        match self {
+            Difficulty::Easy => Difficulty::Medium,
+            Difficulty::Medium => Difficulty::Hard,
+            Difficulty::Hard => Difficulty::Easy,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Difficulty::Easy => "Easy",
+            Difficulty::Medium => "Medium",
+            Difficulty::Hard => "Hard",
+        }
+    }
+
+    /// Gets how long the bot takes to notice where the ball is going.
+    fn reaction(&self) -> Duration {
+        match self {
+            Difficulty::Easy => Duration::from_millis(450),
+            Difficulty::Medium => Duration::from_millis(200),
+            Difficulty::Hard => Duration::from_millis(60),
+        }
+    }
+
+    /// Gets how far off the bot's guess of where the ball will be can be.
+    fn aim_error(&self) -> f32 {
+        match self {
+            Difficulty::Easy => 90.0,
+            Difficulty::Medium => 40.0,
+            Difficulty::Hard => 10.0,
+        }
+    }
+
+    /// Gets how much the bot turns its paddle to aim at the other target.
+    fn rotation(&self) -> f32 {
+        match self {
+            Difficulty::Easy => 0.0,
+            Difficulty::Medium => 0.5,
+            Difficulty::Hard => 1.0,
+        }
+    }
+}
+
+impl FromStr for Difficulty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "easy" => Ok(Difficulty::Easy),
+            "medium" => Ok(Difficulty::Medium),
+            "hard" => Ok(Difficulty::Hard),
+            _ => Err(format!("unknown difficulty \"{}\"", s)),
+        }
+    }
+}
+
+/// Marks the game as a game against a bot, with the bot's difficulty.
+///
+/// Inserted by the menu, and read when the lobby sets up the players.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct BotGame(pub Difficulty);
+
+impl BotGame {
+    pub fn name(&self) -> String {
+        format!("Bot ({})", self.0.label())
+    }
+}
+
+/// Where the bot wants its paddle to be.
+struct BotBrain {
+    /// Ticks between the times that the bot looks at the ball.
+    timer: Timer,
+    y: f32,
+    /// The rotation around the z axis.
+    angle: f32,
+}
+
+fn setup_brain(mut commands: Commands, bot: Option<Res<BotGame>>) {
+    if let Some(bot) = bot {
+        commands.insert_resource(BotBrain {
+            timer: Timer::new(bot.0.reaction(), true),
+            y: 0.0,
+            angle: 0.0,
+        });
+    }
+}
+
+fn drive_bot(
+    server: Option<Res<Server>>,
+    bot: Option<Res<BotGame>>,
+    brain: Option<ResMut<BotBrain>>,
+    round_over: Option<Res<RoundOverR>>,
+    paused: Option<Res<Paused>>,
+    time: Res<Time>,
+    players: Res<Players>,
+    level: Res<CurrentLevel>,
+    q_ball: Query<(&Transform, &Velocity), With<Ball>>,
+    q_target: Query<(&Transform, &Target)>,
+    q_static: Query<(&GlobalTransform, &CollisionShape), (Without<Ball>, Without<Paddle>)>,
+    mut q_paddle: Query<(&mut Transform, &Paddle), (Without<Ball>, Without<Target>)>,
+) {
+    let (bot, mut brain) = match (server, bot, brain) {
+        (Some(_), Some(bot), Some(brain)) => (bot, brain),
+        _ => return,
+    };
+    if paused.is_some() {
+        return;
+    }
+    let team = match players.team_of(BOT_CID) {
+        Some(team) => team,
+        None => return,
+    };
+    let mut paddle = match q_paddle.iter_mut().find(|(_, p)| p.0 == team) {
+        Some((paddle, _)) => paddle,
+        None => return,
+    };
+
+    if brain.timer.tick(time.delta()).just_finished() {
+        let paddle_x = paddle.translation.x;
+        let boxes: Vec<_> = q_static
+            .iter()
+            .filter_map(|(t, shape)| match shape {
+                CollisionShape::Cuboid { half_extends, .. } => {
+                    Some((t.translation.xy(), half_extends.xy()))
+                }
+                _ => None,
+            })
+            .collect();
+
+        // Go for the ball that gets to the paddle first.
+        let incoming = q_ball
+            .iter()
+            .filter(|(t, v)| (paddle_x - t.translation.x) * v.linear.x > 0.0)
+            .filter_map(|(t, v)| {
+                predict(t.translation.xy(), v.linear.xy(), paddle_x, &boxes)
+            })
+            .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        match incoming {
+            Some((_, pos, vel)) if round_over.is_none() => {
+                let mut rng = rand::thread_rng();
+                let error = bot.0.aim_error();
+                brain.y = pos.y + rng.gen_range(-error, error);
+
+                let other = q_target
+                    .iter()
+                    .find(|(_, target)| target.0 == team.other())
+                    .map(|(t, _)| t.translation.xy());
+                brain.angle = match other {
+                    Some(other) => aim(team, pos, vel, other) * bot.0.rotation(),
+                    None => 0.0,
+                };
+            }
+            // Wait in the middle.
+            _ => {
+                brain.y = 0.0;
+                brain.angle = 0.0;
+            }
+        }
+    }
+
+    let dt = time.delta_seconds();
+    let max_y = level.0.size.y / 2.0 - 40.0;
+    let y = paddle.translation.y;
+    let step = (brain.y.clamp(-max_y, max_y) - y).clamp(-MOVE_SPEED * dt, MOVE_SPEED * dt);
+    paddle.translation.y = y + step;
+
+    let (x, y, z) = paddle.rotation.to_euler(EulerRot::XYZ);
+    let angle = brain.angle.clamp(-PI / 8.0, PI / 8.0);
+    let z = z + (angle - z).clamp(-TURN_SPEED * dt, TURN_SPEED * dt);
+    paddle.rotation = Quat::from_euler(EulerRot::XYZ, x, y, z);
+}
+
+/// Follows the ball until it gets to `x`, bouncing it off the `boxes`.
+///
+/// Returns the time that it takes, and the position and velocity of the ball at `x`.
+fn predict(
+    mut pos: Vec2,
+    mut vel: Vec2,
+    x: f32,
+    boxes: &[(Vec2, Vec2)],
+) -> Option<(f32, Vec2, Vec2)> {
+    let mut t = 0.0;
+    while t < PREDICT_TIME {
+        let next = pos + vel * PREDICT_STEP;
+        t += PREDICT_STEP;
+        if (pos.x - x) * (next.x - x) <= 0.0 {
+            return Some((t, next, vel));
+        }
+
+        let hit = boxes.iter().find_map(|(center, half)| {
+            let half = *half + Vec2::splat(BALL_RADIUS);
+            let d = next - *center;
+            let overlap = half - d.abs();
+            (overlap.x > 0.0 && overlap.y > 0.0).then_some(overlap)
+        });
+        match hit {
+            // Bounce off the side that the ball is the least inside of.
+            Some(overlap) if overlap.x < overlap.y => vel.x = -vel.x,
+            Some(_) => vel.y = -vel.y,
+            None => pos = next,
+        }
+    }
+    None
+}
+
+/// Gets the rotation of the paddle of `team` that sends the ball at `pos` to the `target`.
+fn aim(team: Team, pos: Vec2, vel: Vec2, target: Vec2) -> f32 {
+    // The paddle's normal has to be halfway between where the ball comes from and where it goes.
+    let normal = (target - pos).normalize_or_zero() - vel.normalize_or_zero();
+    if normal == Vec2::ZERO {
+        return 0.0;
+    }
+    let facing = match team {
+        Team::Left => Vec2::X,
+        Team::Right => -Vec2::X,
+    };
+    facing.angle_between(normal)
+}
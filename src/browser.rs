@@ -0,0 +1,539 @@
+//! Registers the game with the registry, and the server browser that lists the registered games.
+//!
+//! See [`registry`](crate::registry) for the protocol.
+use crate::bot::BotGame;
+use crate::discovery::{Packet, DISCOVERY_PORT};
+use crate::level::{Levels, SelectedLevel};
+use crate::lobby::{LobbyPassword, Players};
+use crate::messages::ProtocolVersion;
+use crate::{GameIp, GameState, MultiplayerType, Name};
+use bevy::prelude::PositionType::Absolute;
+use bevy::prelude::*;
+use bong::registry::{self, GameInfo, Listing, Reply, Request, HEARTBEAT_INTERVAL, MAX_PACKET};
+use carrier_pigeon::{Client, Server};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// How often the server browser asks the registry for the games.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(3);
+
+pub struct BrowserPlugin {
+    /// Whether the game is running without a window or audio.
+    pub headless: bool,
+}
+
+impl Plugin for BrowserPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(heartbeat);
+
+        if !self.headless {
+            app.add_system_set(SystemSet::on_enter(GameState::Browser).with_system(setup_browser))
+                .add_system_set(
+                    SystemSet::on_update(GameState::Browser)
+                        .with_system(refresh)
+                        .with_system(update_list)
+                        .with_system(handle_ui),
+                )
+                .add_system_set(SystemSet::on_exit(GameState::Browser).with_system(clean_up));
+        }
+    }
+}
+
+/// The address of the registry.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct Registry(pub SocketAddr);
+
+/// Registers the game while it can be joined.
+///
+/// The game is deregistered when it stops being joinable and when the app exits. If the process is
+/// killed, the registry drops the game once the heartbeats stop.
+pub struct Heartbeat {
+    socket: UdpSocket,
+    registry: SocketAddr,
+    port: u16,
+    timer: Timer,
+}
+
+impl Heartbeat {
+    fn send(&self, request: &Request) {
+        if let Err(e) = self.socket.send_to(&registry::to_bytes(request), self.registry) {
+            warn!("Failed to reach the registry at {}: {}", self.registry, e);
+        }
+    }
+
+    /// Removes the game from the registry.
+    pub fn deregister(&self) {
+        self.send(&Request::Deregister { port: self.port });
+    }
+}
+
+/// Registers the game with the registry while this is a server that others can join.
+fn heartbeat(
+    server: Option<Res<Server>>,
+    multiplayer_type: Option<Res<MultiplayerType>>,
+    bot: Option<Res<BotGame>>,
+    heartbeat: Option<ResMut<Heartbeat>>,
+    registry: Res<Registry>,
+    time: Res<Time>,
+    ip: Res<GameIp>,
+    name: Res<Name>,
+    players: Option<Res<Players>>,
+    selected: Res<SelectedLevel>,
+    levels: Res<Levels>,
+    version: Res<ProtocolVersion>,
+    password: Res<LobbyPassword>,
+    mut commands: Commands,
+) {
+    let joinable = matches!(
+        multiplayer_type.as_deref(),
+        Some(MultiplayerType::Server | MultiplayerType::Host)
+    ) && bot.is_none()
+        && server.is_some();
+    let mut heartbeat = match heartbeat {
+        Some(heartbeat) if joinable => heartbeat,
+        None if joinable => {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).and_then(|socket| {
+                socket.set_nonblocking(true)?;
+                Ok(socket)
+            });
+            match socket {
+                Ok(socket) => {
+                    let mut timer = Timer::new(HEARTBEAT_INTERVAL, true);
+                    // Register right away.
+                    timer.set_elapsed(HEARTBEAT_INTERVAL);
+                    commands.insert_resource(Heartbeat {
+                        socket,
+                        registry: registry.0,
+                        port: ip.0.port(),
+                        timer,
+                    });
+                }
+                Err(e) => warn!("Failed to open a socket for the registry: {}", e),
+            }
+            return;
+        }
+        Some(heartbeat) => {
+            heartbeat.deregister();
+            commands.remove_resource::<Heartbeat>();
+            return;
+        }
+        None => return,
+    };
+
+    if heartbeat.timer.tick(time.delta()).just_finished() {
+        let level = levels.0.iter().find(|level| level.id == selected.0);
+        let info = GameInfo {
+            name: name.0.clone(),
+            port: heartbeat.port,
+            players: players.as_ref().map_or(0, |players| players.count()),
+            level: level.map_or(&selected.0, |level| &level.name).clone(),
+            version: version.0,
+            password: password.0.is_some(),
+        };
+        heartbeat.send(&Request::Register(info));
+    }
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// All server browser items have this so that they can be cleaned up easily.
+struct BrowserItem;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// The node that the games are listed in.
+struct GameList;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A marker for the text that says whether the registry answered.
+struct StatusLabel;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+enum BrowserButton {
+    /// Joins the game with the address.
+    Join(SocketAddr),
+    Refresh,
+    Back,
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// The text that describes the game with the address.
+struct GameLabel(SocketAddr);
+
+/// The state of the server browser.
+struct Browser {
+    socket: Option<UdpSocket>,
+    timer: Timer,
+    /// When the last list request was sent.
+    requested: Option<Instant>,
+    /// Whether the registry has answered since the screen was opened.
+    answered: bool,
+    games: Vec<Listing>,
+    /// The latencies of the hosts, measured with a LAN discovery probe.
+    pings: HashMap<IpAddr, Duration>,
+    /// The hosts that were probed, with the id of the probe and when it was sent.
+    probes: HashMap<IpAddr, (u32, Instant)>,
+    next_id: u32,
+    /// Whether the list has to be shown again.
+    changed: bool,
+}
+
+impl Browser {
+    fn label(&self, listing: &Listing, version: &ProtocolVersion) -> String {
+        let info = &listing.info;
+        let ping = match self.pings.get(&listing.addr.ip()) {
+            Some(ping) => format!("{}ms", ping.as_millis()),
+            None => "?ms".to_owned(),
+        };
+        let mut label = format!(
+            "{} - {}/2 players - {} - {}",
+            info.name, info.players, info.level, ping
+        );
+        if info.password {
+            label.push_str(" - password");
+        }
+        if info.version != version.0 {
+            label.push_str(" - other version");
+        }
+        label
+    }
+}
+
+fn setup_browser(mut commands: Commands, assets: Res<AssetServer>) {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).and_then(|socket| {
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    });
+    let socket = match socket {
+        Ok(socket) => Some(socket),
+        Err(e) => {
+            warn!("Failed to open a socket for the registry: {}", e);
+            None
+        }
+    };
+    let mut timer = Timer::new(REFRESH_INTERVAL, true);
+    // Ask right away.
+    timer.set_elapsed(REFRESH_INTERVAL);
+    commands.insert_resource(Browser {
+        socket,
+        timer,
+        requested: None,
+        answered: false,
+        games: vec![],
+        pings: HashMap::new(),
+        probes: HashMap::new(),
+        next_id: 0,
+        changed: true,
+    });
+
+    let font = assets.load("FiraMono-Medium.ttf");
+    let text_style = TextStyle {
+        font,
+        color: Color::BLACK,
+        font_size: 60.0,
+    };
+    let button_style = Style {
+        size: Size::new(Val::Px(400.0), Val::Px(100.0)),
+        margin: Rect::all(Val::Px(20.0)),
+        justify_content: JustifyContent::Center,
+        align_items: AlignItems::Center,
+        ..default()
+    };
+
+    // parent
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: Absolute,
+                margin: Rect::all(Val::Auto),
+                padding: Rect::all(Val::Px(10.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                align_items: AlignItems::Center,
+                align_self: AlignSelf::Center,
+                size: Size {
+                    width: Val::Percent(100.0),
+                    height: Val::Auto,
+                },
+                ..default()
+            },
+            color: Color::CRIMSON.into(),
+            ..default()
+        })
+        .insert(BrowserItem)
+        .with_children(|parent| {
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(
+                    "Server Browser",
+                    text_style.clone(),
+                    TextAlignment::default(),
+                ),
+                ..default()
+            });
+
+            parent
+                .spawn_bundle(TextBundle {
+                    style: Style {
+                        margin: Rect::all(Val::Px(10.0)),
+                        ..default()
+                    },
+                    text: Text::with_section(
+                        "",
+                        TextStyle {
+                            font_size: 40.0,
+                            color: Color::WHITE,
+                            ..text_style.clone()
+                        },
+                        TextAlignment::default(),
+                    ),
+                    ..default()
+                })
+                .insert(StatusLabel);
+
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::ColumnReverse,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    color: Color::NONE.into(),
+                    ..default()
+                })
+                .insert(GameList);
+
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Row,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    color: Color::NONE.into(),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    for (label, button) in [
+                        ("Refresh", BrowserButton::Refresh),
+                        ("Back", BrowserButton::Back),
+                    ] {
+                        parent
+                            .spawn_bundle(ButtonBundle {
+                                color: UiColor(Color::rgb_u8(255, 255, 255)),
+                                style: button_style.clone(),
+                                ..default()
+                            })
+                            .insert(button)
+                            .with_children(|parent| {
+                                parent.spawn_bundle(TextBundle {
+                                    text: Text::with_section(
+                                        label,
+                                        text_style.clone(),
+                                        TextAlignment::default(),
+                                    ),
+                                    ..default()
+                                });
+                            });
+                    }
+                });
+        });
+}
+
+/// Asks the registry for the games, pings their hosts, and collects the answers.
+fn refresh(time: Res<Time>, registry: Res<Registry>, mut browser: ResMut<Browser>) {
+    let browser = &mut *browser;
+    let socket = match &browser.socket {
+        Some(socket) => socket,
+        None => return,
+    };
+
+    if browser.timer.tick(time.delta()).just_finished() {
+        if let Err(e) = socket.send_to(&registry::to_bytes(&Request::List), registry.0) {
+            warn!("Failed to reach the registry at {}: {}", registry.0, e);
+        }
+        browser.requested = Some(Instant::now());
+
+        // The hosts that answer LAN discovery probes can be pinged.
+        for ip in browser.games.iter().map(|game| game.addr.ip()) {
+            let id = browser.next_id;
+            browser.next_id = browser.next_id.wrapping_add(1);
+            if socket
+                .send_to(&Packet::Probe { id }.to_bytes(), (ip, DISCOVERY_PORT))
+                .is_ok()
+            {
+                browser.probes.insert(ip, (id, Instant::now()));
+            }
+        }
+    }
+
+    let mut buf = [0; MAX_PACKET];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(recv) => recv,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            // Unreachable hosts are reported on some platforms.
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+            Err(e) => {
+                warn!("Failed to receive from the registry: {}", e);
+                break;
+            }
+        };
+
+        if from == registry.0 {
+            if let Some(Reply::Games(games)) = registry::from_bytes(&buf[..len]) {
+                if !browser.answered || games != browser.games {
+                    browser.changed = true;
+                }
+                browser.answered = true;
+                browser.games = games;
+            }
+        } else if let Some(Packet::Info { id, .. }) = Packet::from_bytes(&buf[..len]) {
+            if let Some((probe, sent)) = browser.probes.get(&from.ip()) {
+                if *probe == id {
+                    browser.pings.insert(from.ip(), sent.elapsed());
+                }
+            }
+        }
+    }
+}
+
+/// Shows the games, and whether the registry answers.
+fn update_list(
+    mut commands: Commands,
+    mut browser: ResMut<Browser>,
+    registry: Res<Registry>,
+    version: Res<ProtocolVersion>,
+    assets: Res<AssetServer>,
+    q_list: Query<(Entity, Option<&Children>), With<GameList>>,
+    mut q_label: Query<(&mut Text, &GameLabel)>,
+    mut q_status: Query<&mut Text, (With<StatusLabel>, Without<GameLabel>)>,
+) {
+    let status = if browser.socket.is_none() {
+        "Can't open a socket".to_owned()
+    } else if browser.answered && browser.games.is_empty() {
+        "No open games".to_owned()
+    } else if browser.answered {
+        format!("{} open games", browser.games.len())
+    } else if browser
+        .requested
+        .is_some_and(|requested| requested.elapsed() > REFRESH_INTERVAL)
+    {
+        format!("The registry at {} doesn't answer", registry.0)
+    } else {
+        format!("Asking the registry at {}...", registry.0)
+    };
+    for mut text in q_status.iter_mut() {
+        text.sections[0].value = status.clone();
+    }
+
+    // The pings change all the time, so they are updated without rebuilding the list.
+    for (mut text, label) in q_label.iter_mut() {
+        if let Some(listing) = browser.games.iter().find(|game| game.addr == label.0) {
+            text.sections[0].value = browser.label(listing, &version);
+        }
+    }
+
+    if !browser.changed {
+        return;
+    }
+    browser.changed = false;
+    let (list, children) = match q_list.get_single() {
+        Ok(list) => list,
+        Err(_) => return,
+    };
+    if let Some(children) = children {
+        for child in children.iter() {
+            commands.entity(*child).despawn_recursive();
+        }
+    }
+
+    let font = assets.load("FiraMono-Medium.ttf");
+    let text_style = TextStyle {
+        font,
+        color: Color::BLACK,
+        font_size: 40.0,
+    };
+    commands.entity(list).with_children(|parent| {
+        for listing in browser.games.iter() {
+            // Games with another version can't be joined.
+            let color = if listing.info.version == version.0 {
+                Color::rgb_u8(255, 255, 255)
+            } else {
+                Color::rgb_u8(150, 150, 150)
+            };
+
+            parent
+                .spawn_bundle(ButtonBundle {
+                    color: UiColor(color),
+                    style: Style {
+                        size: Size::new(Val::Px(1400.0), Val::Px(70.0)),
+                        margin: Rect::all(Val::Px(5.0)),
+                        justify_content: JustifyContent::Center,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    ..default()
+                })
+                .insert(BrowserButton::Join(listing.addr))
+                .with_children(|parent| {
+                    parent
+                        .spawn_bundle(TextBundle {
+                            text: Text::with_section(
+                                browser.label(listing, &version),
+                                text_style.clone(),
+                                TextAlignment::default(),
+                            ),
+                            ..default()
+                        })
+                        .insert(GameLabel(listing.addr));
+                });
+        }
+    });
+}
+
+fn handle_ui(
+    q_interaction: Query<(&Interaction, &BrowserButton), Changed<Interaction>>,
+    mut game_state: ResMut<State<GameState>>,
+    mut browser: ResMut<Browser>,
+    version: Res<ProtocolVersion>,
+    mut commands: Commands,
+) {
+    for (interaction, button) in q_interaction.iter() {
+        if *interaction != Interaction::Clicked {
+            continue;
+        }
+        match *button {
+            BrowserButton::Join(addr) => {
+                let compatible = browser
+                    .games
+                    .iter()
+                    .any(|game| game.addr == addr && game.info.version == version.0);
+                if !compatible {
+                    continue;
+                }
+                commands.insert_resource(GameIp(addr));
+                commands.insert_resource(MultiplayerType::Client);
+                commands.remove_resource::<BotGame>();
+                // Destroy the client/server when returning to the menu.
+                commands.remove_resource::<Client>();
+                commands.remove_resource::<Server>();
+                let _ = game_state.set(GameState::Lobby);
+                return;
+            }
+            BrowserButton::Refresh => {
+                let duration = browser.timer.duration();
+                browser.timer.set_elapsed(duration);
+            }
+            BrowserButton::Back => {
+                let _ = game_state.set(GameState::Menu);
+                return;
+            }
+        }
+    }
+}
+
+fn clean_up(mut commands: Commands, q_items: Query<Entity, With<BrowserItem>>) {
+    for e in q_items.iter() {
+        commands.entity(e).despawn_recursive();
+    }
+    commands.remove_resource::<Browser>();
+}
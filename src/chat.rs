@@ -0,0 +1,333 @@
+//! The text chat, in the lobby and during the game.
+//!
+//! Clients send [`ChatSend`], and the server relays it to everyone as a [`ChatLine`] with the name
+//! of the sender. Everyone that joins gets a [`ChatHistory`] with the last lines.
+use crate::controls::{Action, ActionInput, Controls};
+use crate::lobby::{is_local, Players};
+use crate::messages::{ChatHistory, ChatLine, ChatSend, MAX_CHAT_LEN};
+use crate::GameState;
+use bevy::prelude::PositionType::Absolute;
+use bevy::prelude::*;
+use bevy::window::ReceivedCharacter;
+use carrier_pigeon::{CId, Client, Server};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How many lines are kept, and sent to the players that join.
+const HISTORY_LEN: usize = 20;
+/// How many lines are shown at once.
+const SHOWN_LINES: usize = 8;
+/// How many messages a connection may send within [`RATE_WINDOW`].
+const RATE_LIMIT: usize = 5;
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+/// How long the chat stays on screen during the game after a new line.
+const SHOW_DURATION: Duration = Duration::from_secs(5);
+
+pub struct ChatPlugin {
+    /// Whether the game is running without a window or audio.
+    pub headless: bool,
+}
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChatRelay>().add_system(relay_chat);
+
+        if !self.headless {
+            app.init_resource::<Chat>()
+                .add_system(receive_chat)
+                .add_system(type_chat);
+            for state in [GameState::Lobby, GameState::Game, GameState::GameOver] {
+                app.add_system_set(SystemSet::on_enter(state).with_system(setup_chat))
+                    .add_system_set(SystemSet::on_update(state).with_system(update_chat))
+                    .add_system_set(SystemSet::on_exit(state).with_system(clean_up));
+            }
+        }
+    }
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// All chat items have this so that they can be cleaned up easily.
+struct ChatItem;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A marker for the text with the last lines.
+struct LinesText;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A marker for the text that is being typed.
+struct InputText;
+
+/// The chat history and rate limits of the server.
+#[derive(Clone, Debug, Default)]
+struct ChatRelay {
+    lines: VecDeque<ChatLine>,
+    /// The connections that got the history.
+    greeted: HashSet<CId>,
+    /// When each connection sent its last messages.
+    sent: HashMap<CId, VecDeque<Instant>>,
+}
+
+/// The chat lines that this client got.
+#[derive(Clone, Debug, Default)]
+struct Chat {
+    lines: VecDeque<ChatLine>,
+    /// When the last line arrived.
+    last_line: Option<Instant>,
+}
+
+/// The chat message that is being typed.
+///
+/// While it exists, the keys don't steer the paddle.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct ChatInput(String);
+
+/// Cleans up a chat message, or returns `None` if there is nothing left to send.
+fn sanitize(text: &str) -> Option<String> {
+    let text: String = text
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_CHAT_LEN)
+        .collect();
+    (!text.is_empty()).then_some(text)
+}
+
+/// Relays the chat messages of the clients, and sends the history to everyone that joins.
+fn relay_chat(
+    server: Option<Res<Server>>,
+    players: Option<Res<Players>>,
+    mut relay: ResMut<ChatRelay>,
+) {
+    let (server, players) = match (server, players) {
+        (Some(server), Some(players)) => (server, players),
+        // The next server starts with an empty chat.
+        _ => {
+            if !relay.lines.is_empty() || !relay.greeted.is_empty() {
+                *relay = ChatRelay::default();
+            }
+            return;
+        }
+    };
+    let relay = &mut *relay;
+
+    relay
+        .greeted
+        .retain(|cid| players.everyone().any(|(c, _)| c == cid));
+    relay
+        .sent
+        .retain(|cid, _| players.everyone().any(|(c, _)| c == cid));
+    for (cid, name) in players.everyone().filter(|(cid, _)| !is_local(*cid)) {
+        if relay.greeted.insert(*cid) {
+            let history = ChatHistory(relay.lines.iter().cloned().collect());
+            if let Err(e) = server.send_to(*cid, &history) {
+                warn!("Failed to send the chat history to {}: {}", name, e);
+            }
+        }
+    }
+
+    for msg in server.recv::<ChatSend>() {
+        let name = match players.everyone().find(|(cid, _)| *cid == msg.cid) {
+            Some((_, name)) => name.clone(),
+            None => continue,
+        };
+        let text = match sanitize(&msg.m.0) {
+            Some(text) => text,
+            None => continue,
+        };
+
+        let sent = relay.sent.entry(msg.cid).or_default();
+        while sent.front().is_some_and(|t| t.elapsed() > RATE_WINDOW) {
+            sent.pop_front();
+        }
+        if sent.len() >= RATE_LIMIT {
+            info!("Dropping a chat message from {}: too many messages", name);
+            continue;
+        }
+        sent.push_back(Instant::now());
+
+        let line = ChatLine {
+            cid: msg.cid,
+            name,
+            text,
+        };
+        if let Err(e) = server.broadcast(&line) {
+            warn!("Failed to broadcast a chat message: {}", e);
+        }
+        relay.lines.push_back(line);
+        if relay.lines.len() > HISTORY_LEN {
+            relay.lines.pop_front();
+        }
+    }
+}
+
+/// Collects the chat lines that the server sends.
+fn receive_chat(client: Option<Res<Client>>, mut chat: ResMut<Chat>) {
+    let client = match client {
+        Some(client) => client,
+        None => return,
+    };
+
+    if let Some(history) = client.recv::<ChatHistory>().last() {
+        chat.lines = history.m.0.iter().cloned().collect();
+    }
+    for line in client.recv::<ChatLine>() {
+        chat.lines.push_back(line.m.clone());
+        chat.last_line = Some(Instant::now());
+    }
+    while chat.lines.len() > HISTORY_LEN {
+        chat.lines.pop_front();
+    }
+}
+
+/// Opens the chat with the chat key, applies typed characters and backspace, and sends the
+/// message with enter. Escape closes it without sending.
+fn type_chat(
+    state: Res<State<GameState>>,
+    client: Option<Res<Client>>,
+    input: ActionInput,
+    keys: Res<Input<KeyCode>>,
+    mut chars: EventReader<ReceivedCharacter>,
+    chat_input: Option<ResMut<ChatInput>>,
+    mut commands: Commands,
+) {
+    let chatting = matches!(
+        state.current(),
+        GameState::Lobby | GameState::Game | GameState::GameOver
+    );
+    let client = match client {
+        Some(client) if chatting && client.open() => client,
+        _ => {
+            chars.iter().for_each(drop);
+            if chat_input.is_some() {
+                commands.remove_resource::<ChatInput>();
+            }
+            return;
+        }
+    };
+
+    let mut chat_input = match chat_input {
+        Some(chat_input) => chat_input,
+        None => {
+            chars.iter().for_each(drop);
+            if [0, 1].into_iter().any(|player| input.just_pressed(player, Action::Chat)) {
+                commands.insert_resource(ChatInput::default());
+            }
+            return;
+        }
+    };
+
+    for c in chars.iter() {
+        // Control characters (backspace, enter) are handled through `keys`.
+        if !c.char.is_control() && chat_input.0.chars().count() < MAX_CHAT_LEN {
+            chat_input.0.push(c.char);
+        }
+    }
+    if keys.just_pressed(KeyCode::Back) {
+        chat_input.0.pop();
+    }
+
+    if keys.just_pressed(KeyCode::Return) {
+        if let Some(text) = sanitize(&chat_input.0) {
+            if let Err(e) = client.send(&ChatSend(text)) {
+                warn!("Failed to send a chat message: {}", e);
+            }
+        }
+        commands.remove_resource::<ChatInput>();
+    } else if keys.just_pressed(KeyCode::Escape) {
+        commands.remove_resource::<ChatInput>();
+    }
+}
+
+fn setup_chat(mut commands: Commands, assets: Res<AssetServer>) {
+    let text_style = TextStyle {
+        font: assets.load("FiraMono-Medium.ttf"),
+        color: Color::BLACK,
+        font_size: 30.0,
+    };
+
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: Absolute,
+                position: Rect {
+                    left: Val::Px(10.0),
+                    bottom: Val::Px(10.0),
+                    ..default()
+                },
+                padding: Rect::all(Val::Px(10.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                size: Size::new(Val::Percent(40.0), Val::Auto),
+                display: Display::None,
+                ..default()
+            },
+            color: Color::rgba(1.0, 1.0, 1.0, 0.7).into(),
+            ..default()
+        })
+        .insert(ChatItem)
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(TextBundle {
+                    text: Text::with_section("", text_style.clone(), TextAlignment::default()),
+                    ..default()
+                })
+                .insert(LinesText);
+            parent
+                .spawn_bundle(TextBundle {
+                    text: Text::with_section("", text_style, TextAlignment::default()),
+                    ..default()
+                })
+                .insert(InputText);
+        });
+}
+
+/// Shows the last lines and the typed message.
+///
+/// The chat is always shown in the lobby, but during the game only while typing or after a new line.
+fn update_chat(
+    state: Res<State<GameState>>,
+    client: Option<Res<Client>>,
+    chat: Res<Chat>,
+    chat_input: Option<Res<ChatInput>>,
+    controls: Res<Controls>,
+    mut q_panel: Query<&mut Style, With<ChatItem>>,
+    mut q_lines: Query<&mut Text, (With<LinesText>, Without<InputText>)>,
+    mut q_input: Query<&mut Text, (With<InputText>, Without<LinesText>)>,
+) {
+    let recent = chat
+        .last_line
+        .is_some_and(|last| last.elapsed() < SHOW_DURATION);
+    let shown = client.is_some_and(|client| client.open())
+        && (*state.current() == GameState::Lobby || chat_input.is_some() || recent);
+    for mut style in q_panel.iter_mut() {
+        style.display = if shown { Display::Flex } else { Display::None };
+    }
+    if !shown {
+        return;
+    }
+
+    let skip = chat.lines.len().saturating_sub(SHOWN_LINES);
+    let lines: Vec<_> = chat
+        .lines
+        .iter()
+        .skip(skip)
+        .map(|line| format!("{}: {}", line.name, line.text))
+        .collect();
+    for mut text in q_lines.iter_mut() {
+        text.sections[0].value = lines.join("\n");
+    }
+
+    let input_txt = match (&chat_input, controls.players[0].keys.get(&Action::Chat)) {
+        (Some(chat_input), _) => format!("> {}_", chat_input.0),
+        (None, Some(key)) => format!("Press {:?} to chat", key),
+        (None, None) => String::new(),
+    };
+    for mut text in q_input.iter_mut() {
+        text.sections[0].value = input_txt.clone();
+    }
+}
+
+fn clean_up(mut commands: Commands, q_items: Query<Entity, With<ChatItem>>) {
+    for e in q_items.iter() {
+        commands.entity(e).despawn_recursive();
+    }
+}
@@ -0,0 +1,136 @@
+//! The command line interface.
+use crate::bot::Difficulty;
+use crate::generator::{Pattern, Symmetry};
+use bevy::log::Level;
+use clap::{CommandFactory, ErrorKind, Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// A game that is a combination of breakout and pong.
+///
+/// Without a subcommand, the game opens the menu.
+#[derive(Parser, Clone, Debug)]
+#[clap(name = "bong", version)]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// Runs a dedicated server without a window or audio. Only works with `server`.
+    #[clap(long, global = true)]
+    pub headless: bool,
+    /// The id of the level that the server plays.
+    #[clap(long, global = true, value_name = "ID")]
+    pub level: Option<String>,
+    /// Generates the bricks from this seed, instead of using the level's. Implies --random-layout.
+    #[clap(long, global = true)]
+    pub seed: Option<u64>,
+    /// Only logs messages of this level and above.
+    #[clap(long, global = true, default_value = "info", value_name = "LEVEL")]
+    pub log_level: Level,
+    /// The directory that the settings and controls are saved in.
+    #[clap(long, global = true, value_name = "DIR")]
+    pub config: Option<PathBuf>,
+    /// The registry that servers register with, and that the server browser lists the games of.
+    #[clap(long, global = true, value_name = "ADDRESS")]
+    pub registry: Option<SocketAddr>,
+
+    /// Starts the game this many seconds after both players are ready, instead of waiting for the
+    /// host to press start. The headless server starts right away without it.
+    #[clap(long, global = true, value_name = "SECS")]
+    pub countdown: Option<u64>,
+    /// A player name that the server doesn't let join. Can be given more than once.
+    #[clap(long = "ban", global = true, value_name = "NAME")]
+    pub bans: Vec<String>,
+    /// How many spectators the server accepts.
+    #[clap(long, global = true, value_name = "N")]
+    pub max_spectators: Option<usize>,
+    /// How many rounds a team has to win to win the match.
+    #[clap(long, global = true, value_name = "N")]
+    pub first_to: Option<u32>,
+    /// Keeps the broken bricks broken between rounds.
+    #[clap(long, global = true)]
+    pub keep_bricks: bool,
+    /// Swaps the players' sides for every rematch.
+    #[clap(long, global = true)]
+    pub swap_sides: bool,
+    /// Replaces the bricks of the level with randomly generated ones.
+    #[clap(long, global = true)]
+    pub random_layout: bool,
+    /// How the halves of a random layout match: mirror, rotational or fair.
+    #[clap(long, global = true, value_name = "MODE")]
+    pub symmetry: Option<Symmetry>,
+    /// How the bricks of a random layout are arranged: scatter, rows or clusters.
+    #[clap(long, global = true)]
+    pub pattern: Option<Pattern>,
+    /// How many percent of the spots of a random layout have a brick.
+    #[clap(long, global = true, value_name = "PERCENT")]
+    pub density: Option<u8>,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Runs a server that two players can join.
+    Server {
+        /// The address to listen on. Defaults to the one in the settings.
+        #[clap(long, value_name = "ADDRESS")]
+        bind: Option<SocketAddr>,
+        /// The password that players need to join.
+        #[clap(long)]
+        password: Option<String>,
+        /// Generates an invite code that players need to join, and logs it.
+        #[clap(long, conflicts_with = "password")]
+        invite: bool,
+    },
+    /// Runs a server and plays on it.
+    Host {
+        /// The address to listen on. Defaults to the one in the settings.
+        #[clap(long, value_name = "ADDRESS")]
+        bind: Option<SocketAddr>,
+        /// Your name. Defaults to the one in the settings.
+        #[clap(long)]
+        name: Option<String>,
+        /// The password that players need to join.
+        #[clap(long)]
+        password: Option<String>,
+        /// Generates an invite code that players need to join. It is shown in the lobby.
+        #[clap(long, conflicts_with = "password")]
+        invite: bool,
+    },
+    /// Joins a server.
+    Client {
+        /// The address of the server. Defaults to the one in the settings.
+        #[clap(long, value_name = "ADDRESS")]
+        connect: Option<SocketAddr>,
+        /// Your name. Defaults to the one in the settings.
+        #[clap(long)]
+        name: Option<String>,
+        /// The password or invite code of the server.
+        #[clap(long)]
+        password: Option<String>,
+    },
+    /// Plays against a bot on this machine.
+    Bot {
+        /// How good the bot is: easy, medium or hard.
+        #[clap(long, default_value = "medium")]
+        difficulty: Difficulty,
+        /// Your name. Defaults to the one in the settings.
+        #[clap(long)]
+        name: Option<String>,
+    },
+}
+
+impl Cli {
+    /// Parses the command line args, and exits with the help or an error if they are invalid.
+    pub fn parse_args() -> Self {
+        let cli = Cli::parse();
+        if cli.headless && !matches!(cli.command, None | Some(Command::Server { .. })) {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--headless only works with the server subcommand",
+                )
+                .exit();
+        }
+        cli
+    }
+}
@@ -0,0 +1,148 @@
+//! Persists user data between launches.
+use bong::registry::DEFAULT_REGISTRY;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// The file in the config directory that the settings are saved to.
+const SETTINGS_FILE: &str = "settings.ron";
+
+/// The user's settings, as saved in the config directory.
+///
+/// The command line args override these for one launch, without changing the file.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct Settings {
+    pub name: String,
+    /// The address of the last server, without the port.
+    pub address: IpAddr,
+    pub port: u16,
+    /// The volume of all sounds, from 0 to 1.
+    pub master_volume: f32,
+    /// The volume of the sound effects, from 0 to 1, before the master volume.
+    pub sfx_volume: f32,
+    pub window_mode: WindowMode,
+    /// The size of the window in logical pixels. Unused in fullscreen.
+    pub resolution: (u32, u32),
+    pub vsync: bool,
+    /// The registry that servers register with, and that the server browser lists the games of.
+    pub registry: SocketAddr,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            name: "Player".into(),
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 5599,
+            master_volume: 1.0,
+            sfx_volume: 1.0,
+            window_mode: WindowMode::Windowed,
+            resolution: (1280, 720),
+            vsync: true,
+            registry: DEFAULT_REGISTRY.parse().unwrap(),
+        }
+    }
+}
+
+impl Settings {
+    /// Loads the settings, or the default ones if they haven't been saved before.
+    pub fn load() -> Self {
+        load(SETTINGS_FILE).unwrap_or_default()
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        save(SETTINGS_FILE, self)
+    }
+
+    /// Gets the address and port of the last server.
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+
+    /// Gets the volume that sound effects are played at.
+    pub fn effects_volume(&self) -> f32 {
+        self.master_volume * self.sfx_volume
+    }
+}
+
+/// How the window is shown.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub enum WindowMode {
+    Windowed,
+    /// A window without borders that covers the screen.
+    Borderless,
+    Fullscreen,
+}
+
+impl WindowMode {
+    /// Gets the next window mode, wrapping around.
+    pub fn next(&self) -> Self {
+        match self {
+            WindowMode::Windowed => WindowMode::Borderless,
+            WindowMode::Borderless => WindowMode::Fullscreen,
+            WindowMode::Fullscreen => WindowMode::Windowed,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            WindowMode::Windowed => "Windowed",
+            WindowMode::Borderless => "Borderless",
+            WindowMode::Fullscreen => "Fullscreen",
+        }
+    }
+}
+
+impl From<WindowMode> for bevy::window::WindowMode {
+    fn from(mode: WindowMode) -> Self {
+        match mode {
+            WindowMode::Windowed => bevy::window::WindowMode::Windowed,
+            WindowMode::Borderless => bevy::window::WindowMode::BorderlessFullscreen,
+            WindowMode::Fullscreen => bevy::window::WindowMode::Fullscreen,
+        }
+    }
+}
+
+/// The directory that was passed on the command line, instead of the platform's config directory.
+static CONFIG_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Stores the config files in `dir` instead of the default directory. Has to be called before
+/// anything is loaded.
+pub fn set_config_dir(dir: PathBuf) {
+    let _ = CONFIG_DIR.set(dir);
+}
+
+/// Gets the directory where bong stores its config files.
+pub fn config_dir() -> Option<PathBuf> {
+    match CONFIG_DIR.get() {
+        Some(dir) => Some(dir.clone()),
+        None => dirs::config_dir().map(|dir| dir.join("bong")),
+    }
+}
+
+/// Loads a value from the RON file with the given name in the config directory.
+///
+/// Returns `None` if it hasn't been saved before, or can't be read.
+pub fn load<T: DeserializeOwned>(file: &str) -> Option<T> {
+    let contents = fs::read_to_string(config_dir()?.join(file)).ok()?;
+    ron::from_str(&contents).ok()
+}
+
+/// Saves a value to the RON file with the given name in the config directory.
+pub fn save<T: Serialize>(file: &str, value: &T) -> io::Result<()> {
+    let dir = config_dir().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "No config directory on this platform",
+        )
+    })?;
+    fs::create_dir_all(&dir)?;
+    let contents = ron::ser::to_string_pretty(value, Default::default())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fs::write(dir.join(file), contents)
+}
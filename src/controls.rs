@@ -0,0 +1,563 @@
+//! The actions that the players can take, the keys and buttons that they are bound to, and the
+//! screen for rebinding them.
+use crate::config;
+use crate::GameState;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::PositionType::Absolute;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::marker::PhantomData;
+
+/// The file in the config directory that the bindings are saved to.
+const CONTROLS_FILE: &str = "controls.ron";
+/// The color of a binding that is used for more than one action.
+const CONFLICT_COLOR: Color = Color::rgb(0.8, 0.0, 0.0);
+
+pub struct ControlsPlugin;
+
+impl Plugin for ControlsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(SystemSet::on_enter(GameState::Controls).with_system(setup_controls))
+            .add_system_set(
+                SystemSet::on_update(GameState::Controls)
+                    .with_system(handle_ui)
+                    .with_system(capture_binding)
+                    .with_system(update_labels),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Controls).with_system(clean_up));
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub enum Action {
+    MoveUp,
+    MoveDown,
+    /// Rotates the paddle counterclockwise.
+    RotateCcw,
+    /// Rotates the paddle clockwise.
+    RotateCw,
+    /// Pauses a game without other people in it.
+    Pause,
+    Chat,
+}
+
+impl Action {
+    pub const ALL: [Action; 6] = [
+        Action::MoveUp,
+        Action::MoveDown,
+        Action::RotateCcw,
+        Action::RotateCw,
+        Action::Pause,
+        Action::Chat,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Action::MoveUp => "Move Up",
+            Action::MoveDown => "Move Down",
+            Action::RotateCcw => "Rotate Left",
+            Action::RotateCw => "Rotate Right",
+            Action::Pause => "Pause",
+            Action::Chat => "Chat",
+        }
+    }
+}
+
+/// The key and gamepad button that each action of a player is bound to.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug, Default)]
+pub struct Bindings {
+    pub keys: HashMap<Action, KeyCode>,
+    pub buttons: HashMap<Action, GamepadButtonType>,
+}
+
+impl Bindings {
+    fn label(&self, action: Action) -> String {
+        let key = self.keys.get(&action).map(|key| format!("{:?}", key));
+        let button = self.buttons.get(&action).map(|b| format!("{:?}", b));
+        match (key, button) {
+            (Some(key), Some(button)) => format!("{} / {}", key, button),
+            (Some(binding), None) | (None, Some(binding)) => binding,
+            (None, None) => "-".to_owned(),
+        }
+    }
+}
+
+/// The bindings of both players.
+///
+/// In a local game, the first player steers the left paddle and the second one the right paddle.
+/// Online, both sets of bindings steer your paddle.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct Controls {
+    pub players: [Bindings; 2],
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        let mut buttons = steering(
+            GamepadButtonType::DPadUp,
+            GamepadButtonType::DPadDown,
+            GamepadButtonType::LeftTrigger,
+            GamepadButtonType::RightTrigger,
+        );
+        buttons.insert(Action::Pause, GamepadButtonType::Start);
+
+        let mut first = steering(KeyCode::W, KeyCode::S, KeyCode::Q, KeyCode::E);
+        first.insert(Action::Pause, KeyCode::Escape);
+        first.insert(Action::Chat, KeyCode::Return);
+
+        Controls {
+            players: [
+                Bindings {
+                    keys: first,
+                    buttons: buttons.clone(),
+                },
+                Bindings {
+                    keys: steering(KeyCode::Up, KeyCode::Down, KeyCode::Left, KeyCode::Right),
+                    buttons,
+                },
+            ],
+        }
+    }
+}
+
+/// Binds the actions that steer the paddle.
+fn steering<T>(up: T, down: T, ccw: T, cw: T) -> HashMap<Action, T> {
+    HashMap::from([
+        (Action::MoveUp, up),
+        (Action::MoveDown, down),
+        (Action::RotateCcw, ccw),
+        (Action::RotateCw, cw),
+    ])
+}
+
+impl Controls {
+    /// Loads the saved bindings, or the default ones if there are none.
+    pub fn load() -> Self {
+        config::load(CONTROLS_FILE).unwrap_or_default()
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        config::save(CONTROLS_FILE, self)
+    }
+
+    /// Gets the actions that share a binding with another action, by player.
+    ///
+    /// Keys conflict with the keys of either player, as both use the same keyboard.
+    /// Gamepad buttons only conflict with the same player's, as each player has their own gamepad.
+    pub fn conflicts(&self) -> Vec<(usize, Action)> {
+        let keys: Vec<_> = self
+            .players
+            .iter()
+            .enumerate()
+            .flat_map(|(player, b)| b.keys.iter().map(move |(a, key)| (player, *a, *key)))
+            .collect();
+        let buttons: Vec<_> = self
+            .players
+            .iter()
+            .enumerate()
+            .flat_map(|(player, b)| b.buttons.iter().map(move |(a, btn)| (player, *a, *btn)))
+            .collect();
+
+        let mut conflicts = vec![];
+        for (player, action, key) in &keys {
+            if keys
+                .iter()
+                .any(|(p, a, k)| k == key && (p, a) != (player, action))
+            {
+                conflicts.push((*player, *action));
+            }
+        }
+        for (player, action, button) in &buttons {
+            if buttons
+                .iter()
+                .any(|(p, a, b)| b == button && p == player && a != action)
+            {
+                conflicts.push((*player, *action));
+            }
+        }
+        conflicts
+    }
+}
+
+/// Reads the actions of the players from the keyboard and gamepads.
+///
+/// The first gamepad belongs to the first player, and the second one to the second player.
+#[derive(SystemParam)]
+pub struct ActionInput<'w, 's> {
+    controls: Res<'w, Controls>,
+    keys: Res<'w, Input<KeyCode>>,
+    gamepads: Res<'w, Gamepads>,
+    buttons: Res<'w, Input<GamepadButton>>,
+    #[system_param(ignore)]
+    _marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> ActionInput<'w, 's> {
+    /// Gets the gamepad of the `player`, if they have one.
+    ///
+    /// The gamepads are handed out by id, as `Gamepads` doesn't keep them in order.
+    pub fn pad(&self, player: usize) -> Option<Gamepad> {
+        let mut pads: Vec<_> = self.gamepads.iter().copied().collect();
+        pads.sort_by_key(|pad| pad.0);
+        pads.get(player).copied()
+    }
+
+    /// Whether the `player` is holding the `action`.
+    pub fn pressed(&self, player: usize, action: Action) -> bool {
+        self.check(player, action, |key| self.keys.pressed(key), |b| {
+            self.buttons.pressed(b)
+        })
+    }
+
+    /// Whether the `player` started the `action` this frame.
+    pub fn just_pressed(&self, player: usize, action: Action) -> bool {
+        self.check(player, action, |key| self.keys.just_pressed(key), |b| {
+            self.buttons.just_pressed(b)
+        })
+    }
+
+    /// Gets 1 if only `positive` is held, -1 if only `negative` is held, and 0 otherwise.
+    pub fn axis(&self, player: usize, positive: Action, negative: Action) -> f32 {
+        let value = |action| if self.pressed(player, action) { 1.0 } else { 0.0 };
+        value(positive) - value(negative)
+    }
+
+    fn check(
+        &self,
+        player: usize,
+        action: Action,
+        key: impl Fn(KeyCode) -> bool,
+        button: impl Fn(GamepadButton) -> bool,
+    ) -> bool {
+        let bindings = &self.controls.players[player];
+        let key = bindings.keys.get(&action).is_some_and(|k| key(*k));
+        let button = match (self.pad(player), bindings.buttons.get(&action)) {
+            (Some(pad), Some(b)) => button(GamepadButton(pad, *b)),
+            _ => false,
+        };
+        key || button
+    }
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// All controls screen items have this so that they can be cleaned up easily.
+struct ControlsItem;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+enum ControlsButton {
+    /// Rebinds the action of the player.
+    Binding(usize, Action),
+    Reset,
+    Back,
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// The text that shows the bindings of an action of a player.
+struct BindingText(usize, Action);
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A marker for the text that explains the screen, or warns about conflicts.
+struct HintLabel;
+
+/// The action that the next key or button press is bound to.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+struct Listening(usize, Action);
+
+fn setup_controls(mut commands: Commands, assets: Res<AssetServer>) {
+    let font = assets.load("FiraMono-Medium.ttf");
+    let text_style = TextStyle {
+        font,
+        color: Color::BLACK,
+        font_size: 40.0,
+    };
+    let cell_style = Style {
+        size: Size::new(Val::Px(520.0), Val::Px(60.0)),
+        margin: Rect::all(Val::Px(5.0)),
+        justify_content: JustifyContent::Center,
+        align_items: AlignItems::Center,
+        ..default()
+    };
+    let label_style = Style {
+        size: Size::new(Val::Px(300.0), Val::Auto),
+        margin: Rect::all(Val::Px(5.0)),
+        ..default()
+    };
+    let row_style = Style {
+        flex_direction: FlexDirection::Row,
+        align_items: AlignItems::Center,
+        ..default()
+    };
+
+    // parent
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: Absolute,
+                margin: Rect::all(Val::Auto),
+                padding: Rect::all(Val::Px(10.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                align_items: AlignItems::Center,
+                align_self: AlignSelf::Center,
+                size: Size {
+                    width: Val::Percent(100.0),
+                    height: Val::Auto,
+                },
+                ..default()
+            },
+            color: Color::CRIMSON.into(),
+            ..default()
+        })
+        .insert(ControlsItem)
+        .with_children(|parent| {
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(
+                    "Controls",
+                    TextStyle {
+                        font_size: 60.0,
+                        ..text_style.clone()
+                    },
+                    TextAlignment::default(),
+                ),
+                ..default()
+            });
+
+            // Header
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: row_style.clone(),
+                    color: Color::NONE.into(),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    for (label, style) in [
+                        ("", &label_style),
+                        ("Player 1", &cell_style),
+                        ("Player 2", &cell_style),
+                    ] {
+                        parent
+                            .spawn_bundle(NodeBundle {
+                                style: style.clone(),
+                                color: Color::NONE.into(),
+                                ..default()
+                            })
+                            .with_children(|parent| {
+                                parent.spawn_bundle(TextBundle {
+                                    text: Text::with_section(
+                                        label,
+                                        text_style.clone(),
+                                        TextAlignment::default(),
+                                    ),
+                                    ..default()
+                                });
+                            });
+                    }
+                });
+
+            for action in Action::ALL {
+                parent
+                    .spawn_bundle(NodeBundle {
+                        style: row_style.clone(),
+                        color: Color::NONE.into(),
+                        ..default()
+                    })
+                    .with_children(|parent| {
+                        parent.spawn_bundle(TextBundle {
+                            style: label_style.clone(),
+                            text: Text::with_section(
+                                action.label(),
+                                text_style.clone(),
+                                TextAlignment::default(),
+                            ),
+                            ..default()
+                        });
+
+                        for player in 0..2 {
+                            parent
+                                .spawn_bundle(ButtonBundle {
+                                    color: UiColor(Color::rgb_u8(230, 230, 230)),
+                                    style: cell_style.clone(),
+                                    ..default()
+                                })
+                                .insert(ControlsButton::Binding(player, action))
+                                .with_children(|parent| {
+                                    parent
+                                        .spawn_bundle(TextBundle {
+                                            text: Text::with_section(
+                                                "",
+                                                text_style.clone(),
+                                                TextAlignment::default(),
+                                            ),
+                                            ..default()
+                                        })
+                                        .insert(BindingText(player, action));
+                                });
+                        }
+                    });
+            }
+
+            parent
+                .spawn_bundle(TextBundle {
+                    style: Style {
+                        margin: Rect::all(Val::Px(10.0)),
+                        ..default()
+                    },
+                    text: Text::with_section(
+                        "",
+                        TextStyle {
+                            font_size: 30.0,
+                            color: Color::WHITE,
+                            ..text_style.clone()
+                        },
+                        TextAlignment::default(),
+                    ),
+                    ..default()
+                })
+                .insert(HintLabel);
+
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: row_style,
+                    color: Color::NONE.into(),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    for (label, button) in
+                        [("Reset", ControlsButton::Reset), ("Back", ControlsButton::Back)]
+                    {
+                        parent
+                            .spawn_bundle(ButtonBundle {
+                                color: UiColor(Color::rgb_u8(255, 255, 255)),
+                                style: Style {
+                                    size: Size::new(Val::Px(400.0), Val::Px(80.0)),
+                                    margin: Rect::all(Val::Px(20.0)),
+                                    ..cell_style.clone()
+                                },
+                                ..default()
+                            })
+                            .insert(button)
+                            .with_children(|parent| {
+                                parent.spawn_bundle(TextBundle {
+                                    text: Text::with_section(
+                                        label,
+                                        TextStyle {
+                                            font_size: 60.0,
+                                            ..text_style.clone()
+                                        },
+                                        TextAlignment::default(),
+                                    ),
+                                    ..default()
+                                });
+                            });
+                    }
+                });
+        });
+}
+
+fn handle_ui(
+    q_interaction: Query<(&Interaction, &ControlsButton), Changed<Interaction>>,
+    mut game_state: ResMut<State<GameState>>,
+    mut controls: ResMut<Controls>,
+    listening: Option<Res<Listening>>,
+    mut commands: Commands,
+) {
+    for (interaction, button) in q_interaction.iter() {
+        if *interaction != Interaction::Clicked {
+            continue;
+        }
+        match *button {
+            // Clicking the binding that is being changed again cancels it.
+            ControlsButton::Binding(player, action)
+                if listening.as_deref() == Some(&Listening(player, action)) =>
+            {
+                commands.remove_resource::<Listening>();
+            }
+            ControlsButton::Binding(player, action) => {
+                commands.insert_resource(Listening(player, action));
+            }
+            ControlsButton::Reset => {
+                *controls = Controls::default();
+                if let Err(e) = controls.save() {
+                    warn!("Failed to save the controls: {}", e);
+                }
+                commands.remove_resource::<Listening>();
+            }
+            ControlsButton::Back => {
+                let _ = game_state.set(GameState::Menu);
+            }
+        }
+    }
+}
+
+/// Binds the next key or gamepad button that is pressed. Backspace clears the key.
+fn capture_binding(
+    listening: Option<Res<Listening>>,
+    keys: Res<Input<KeyCode>>,
+    buttons: Res<Input<GamepadButton>>,
+    mut controls: ResMut<Controls>,
+    mut commands: Commands,
+) {
+    let Listening(player, action) = match listening {
+        Some(listening) => *listening,
+        None => return,
+    };
+    let bindings = &mut controls.players[player];
+
+    if keys.just_pressed(KeyCode::Back) {
+        bindings.keys.remove(&action);
+    } else if let Some(key) = keys.get_just_pressed().next() {
+        bindings.keys.insert(action, *key);
+    } else if let Some(button) = buttons.get_just_pressed().next() {
+        bindings.buttons.insert(action, button.1);
+    } else {
+        return;
+    }
+
+    commands.remove_resource::<Listening>();
+    if let Err(e) = controls.save() {
+        warn!("Failed to save the controls: {}", e);
+    }
+}
+
+fn update_labels(
+    controls: Res<Controls>,
+    listening: Option<Res<Listening>>,
+    mut q_binding: Query<(&mut Text, &BindingText)>,
+    mut q_hint: Query<&mut Text, (With<HintLabel>, Without<BindingText>)>,
+) {
+    let conflicts = controls.conflicts();
+
+    for (mut text, binding) in q_binding.iter_mut() {
+        let BindingText(player, action) = *binding;
+        let section = &mut text.sections[0];
+        section.value = if listening.as_deref() == Some(&Listening(player, action)) {
+            "Press a key or button...".to_owned()
+        } else {
+            controls.players[player].label(action)
+        };
+        section.style.color = if conflicts.contains(&(player, action)) {
+            CONFLICT_COLOR
+        } else {
+            Color::BLACK
+        };
+    }
+
+    let hint = if !conflicts.is_empty() {
+        "Some keys or buttons are bound to more than one action"
+    } else if listening.is_some() {
+        "Backspace clears the key, clicking again cancels"
+    } else {
+        "Click an action to change its key or gamepad button"
+    };
+    for mut text in q_hint.iter_mut() {
+        text.sections[0].value = hint.to_owned();
+    }
+}
+
+fn clean_up(mut commands: Commands, q_items: Query<Entity, With<ControlsItem>>) {
+    for e in q_items.iter() {
+        commands.entity(e).despawn_recursive();
+    }
+    commands.remove_resource::<Listening>();
+}
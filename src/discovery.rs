@@ -0,0 +1,539 @@
+//! Finds the servers on the local network.
+//!
+//! Clients broadcast a probe on [`DISCOVERY_PORT`], and every server on the network answers with
+//! its [`ServerInfo`].
+use crate::bot::BotGame;
+use crate::level::{Levels, SelectedLevel};
+use crate::lobby::{LobbyPassword, Players};
+use crate::messages::ProtocolVersion;
+use crate::{GameIp, GameState, MultiplayerType, Name};
+use bevy::prelude::PositionType::Absolute;
+use bevy::prelude::*;
+use carrier_pigeon::{Client, Server};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// The UDP port that servers listen for probes on.
+pub const DISCOVERY_PORT: u16 = 5598;
+/// How often the LAN games screen sends a probe.
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a server is listed after its last answer.
+const SERVER_TIMEOUT: Duration = Duration::from_secs(3);
+/// The max size of a discovery packet.
+const MAX_PACKET: usize = 1024;
+
+pub struct DiscoveryPlugin {
+    /// Whether the game is running without a window or audio.
+    pub headless: bool,
+}
+
+impl Plugin for DiscoveryPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(answer_probes);
+
+        if !self.headless {
+            app.add_system_set(SystemSet::on_enter(GameState::LanGames).with_system(setup_browser))
+                .add_system_set(
+                    SystemSet::on_update(GameState::LanGames)
+                        .with_system(probe)
+                        .with_system(update_list)
+                        .with_system(handle_ui),
+                )
+                .add_system_set(SystemSet::on_exit(GameState::LanGames).with_system(clean_up));
+        }
+    }
+}
+
+/// A discovery packet, sent as RON.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum Packet {
+    /// Asks the servers for their info. The `id` is sent back, to match the answer to the probe.
+    Probe { id: u32 },
+    Info { id: u32, info: ServerInfo },
+}
+
+impl Packet {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        ron::to_string(self).unwrap_or_default().into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        ron::from_str(std::str::from_utf8(bytes).ok()?).ok()
+    }
+}
+
+/// What a server tells the clients that are looking for games.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ServerInfo {
+    pub name: String,
+    /// How many of the two player slots are taken.
+    pub players: usize,
+    /// The name of the selected level.
+    pub level: String,
+    /// The [`ProtocolVersion`] of the server.
+    pub version: u64,
+    /// The port of the game, which can be different from the one that answered.
+    pub port: u16,
+    /// Whether a password is needed to join.
+    pub password: bool,
+}
+
+/// The socket that a server answers probes on.
+///
+/// `None` if the port couldn't be bound, for example because another server on this machine has it.
+struct Responder(Option<UdpSocket>);
+
+/// Answers the probes while this is a server that others can join.
+fn answer_probes(
+    server: Option<Res<Server>>,
+    multiplayer_type: Option<Res<MultiplayerType>>,
+    bot: Option<Res<BotGame>>,
+    responder: Option<Res<Responder>>,
+    ip: Res<GameIp>,
+    name: Res<Name>,
+    players: Option<Res<Players>>,
+    selected: Res<SelectedLevel>,
+    levels: Res<Levels>,
+    version: Res<ProtocolVersion>,
+    password: Res<LobbyPassword>,
+    mut commands: Commands,
+) {
+    let joinable = matches!(
+        multiplayer_type.as_deref(),
+        Some(MultiplayerType::Server | MultiplayerType::Host)
+    ) && bot.is_none();
+    let responder = match (server, responder) {
+        (Some(_), Some(responder)) if joinable => responder,
+        (Some(_), None) if joinable => {
+            commands.insert_resource(Responder(bind_responder()));
+            return;
+        }
+        (_, Some(_)) if !joinable => {
+            commands.remove_resource::<Responder>();
+            return;
+        }
+        _ => return,
+    };
+    let socket = match &responder.0 {
+        Some(socket) => socket,
+        None => return,
+    };
+
+    let mut buf = [0; MAX_PACKET];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(recv) => recv,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => {
+                warn!("Failed to receive a discovery probe: {}", e);
+                break;
+            }
+        };
+        let id = match Packet::from_bytes(&buf[..len]) {
+            Some(Packet::Probe { id }) => id,
+            _ => continue,
+        };
+
+        let level = levels.0.iter().find(|level| level.id == selected.0);
+        let info = ServerInfo {
+            name: name.0.clone(),
+            players: players.as_ref().map_or(0, |players| players.count()),
+            level: level.map_or(&selected.0, |level| &level.name).clone(),
+            version: version.0,
+            port: ip.0.port(),
+            password: password.0.is_some(),
+        };
+        if let Err(e) = socket.send_to(&Packet::Info { id, info }.to_bytes(), from) {
+            warn!("Failed to answer a discovery probe from {}: {}", from, e);
+        }
+    }
+}
+
+fn bind_responder() -> Option<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)).and_then(|socket| {
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    });
+    match socket {
+        Ok(socket) => Some(socket),
+        Err(e) => {
+            warn!("Not answering LAN discovery probes on port {}: {}", DISCOVERY_PORT, e);
+            None
+        }
+    }
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// All LAN games screen items have this so that they can be cleaned up easily.
+struct BrowserItem;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// The node that the found servers are listed in.
+struct ServerList;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+enum BrowserButton {
+    /// Joins the found server with the address.
+    Join(SocketAddr),
+    Back,
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// The text that describes the found server with the address.
+struct ServerLabel(SocketAddr);
+
+/// A server that answered a probe.
+#[derive(Clone, PartialEq, Debug)]
+struct Found {
+    addr: SocketAddr,
+    info: ServerInfo,
+    latency: Duration,
+    last_seen: Instant,
+}
+
+impl Found {
+    fn label(&self, version: &ProtocolVersion) -> String {
+        let mut label = format!(
+            "{} - {}/2 players - {} - {}ms",
+            self.info.name,
+            self.info.players,
+            self.info.level,
+            self.latency.as_millis()
+        );
+        if self.info.password {
+            label.push_str(" - password");
+        }
+        if self.info.version != version.0 {
+            label.push_str(" - other version");
+        }
+        label
+    }
+}
+
+/// The state of the LAN games screen.
+struct Browser {
+    socket: Option<UdpSocket>,
+    timer: Timer,
+    /// The ids of the last probes, with when they were sent.
+    sent: VecDeque<(u32, Instant)>,
+    next_id: u32,
+    found: Vec<Found>,
+    /// Whether the list has to be shown again.
+    changed: bool,
+}
+
+fn setup_browser(mut commands: Commands, assets: Res<AssetServer>) {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).and_then(|socket| {
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    });
+    let socket = match socket {
+        Ok(socket) => Some(socket),
+        Err(e) => {
+            warn!("Failed to open a socket to look for LAN games: {}", e);
+            None
+        }
+    };
+    let mut timer = Timer::new(PROBE_INTERVAL, true);
+    // Probe right away.
+    timer.set_elapsed(PROBE_INTERVAL);
+    commands.insert_resource(Browser {
+        socket,
+        timer,
+        sent: VecDeque::new(),
+        next_id: 0,
+        found: vec![],
+        changed: true,
+    });
+
+    let font = assets.load("FiraMono-Medium.ttf");
+    let text_style = TextStyle {
+        font,
+        color: Color::BLACK,
+        font_size: 60.0,
+    };
+
+    // parent
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: Absolute,
+                margin: Rect::all(Val::Auto),
+                padding: Rect::all(Val::Px(10.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                align_items: AlignItems::Center,
+                align_self: AlignSelf::Center,
+                size: Size {
+                    width: Val::Percent(100.0),
+                    height: Val::Auto,
+                },
+                ..default()
+            },
+            color: Color::CRIMSON.into(),
+            ..default()
+        })
+        .insert(BrowserItem)
+        .with_children(|parent| {
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section("LAN Games", text_style.clone(), TextAlignment::default()),
+                ..default()
+            });
+
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::ColumnReverse,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    color: Color::NONE.into(),
+                    ..default()
+                })
+                .insert(ServerList);
+
+            parent
+                .spawn_bundle(ButtonBundle {
+                    color: UiColor(Color::rgb_u8(255, 255, 255)),
+                    style: Style {
+                        size: Size::new(Val::Px(400.0), Val::Px(100.0)),
+                        margin: Rect::all(Val::Px(20.0)),
+                        justify_content: JustifyContent::Center,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    ..default()
+                })
+                .insert(BrowserButton::Back)
+                .with_children(|parent| {
+                    parent.spawn_bundle(TextBundle {
+                        text: Text::with_section("Back", text_style, TextAlignment::default()),
+                        ..default()
+                    });
+                });
+        });
+}
+
+/// Whether `ip` is an address of this machine.
+fn is_this_machine(ip: IpAddr) -> bool {
+    // Only the machine's own addresses can be bound.
+    ip.is_loopback() || UdpSocket::bind((ip, 0)).is_ok()
+}
+
+/// Sends the probes, and collects the answers.
+fn probe(time: Res<Time>, mut browser: ResMut<Browser>) {
+    let browser = &mut *browser;
+    let socket = match &browser.socket {
+        Some(socket) => socket,
+        None => return,
+    };
+
+    if browser.timer.tick(time.delta()).just_finished() {
+        let id = browser.next_id;
+        browser.next_id = browser.next_id.wrapping_add(1);
+        let packet = Packet::Probe { id }.to_bytes();
+        // Servers on this machine don't always get broadcasts.
+        for ip in [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST] {
+            if let Err(e) = socket.send_to(&packet, (ip, DISCOVERY_PORT)) {
+                warn!("Failed to send a discovery probe to {}: {}", ip, e);
+            }
+        }
+        browser.sent.push_back((id, Instant::now()));
+        if browser.sent.len() > 5 {
+            browser.sent.pop_front();
+        }
+    }
+
+    let mut buf = [0; MAX_PACKET];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(recv) => recv,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => {
+                warn!("Failed to receive a discovery answer: {}", e);
+                break;
+            }
+        };
+        let (id, info) = match Packet::from_bytes(&buf[..len]) {
+            Some(Packet::Info { id, info }) => (id, info),
+            _ => continue,
+        };
+        let sent = match browser.sent.iter().find(|(sent, _)| *sent == id) {
+            Some((_, sent)) => *sent,
+            None => continue,
+        };
+
+        let found = Found {
+            addr: SocketAddr::new(from.ip(), info.port),
+            info,
+            latency: sent.elapsed(),
+            last_seen: Instant::now(),
+        };
+        // A server on this machine answers both the broadcast and the probe to localhost.
+        let existing = browser.found.iter_mut().find(|f| {
+            f.addr == found.addr
+                || (f.addr.port() == found.addr.port()
+                    && is_this_machine(f.addr.ip())
+                    && is_this_machine(found.addr.ip()))
+        });
+        match existing {
+            Some(existing) => {
+                if existing.info != found.info {
+                    browser.changed = true;
+                }
+                // The address on the network can be shared with others, localhost can't.
+                if existing.addr.ip().is_loopback() && !found.addr.ip().is_loopback() {
+                    existing.addr = found.addr;
+                    browser.changed = true;
+                }
+                existing.info = found.info;
+                existing.latency = found.latency;
+                existing.last_seen = found.last_seen;
+            }
+            None => {
+                browser.found.push(found);
+                browser.changed = true;
+            }
+        }
+    }
+
+    let before = browser.found.len();
+    browser
+        .found
+        .retain(|found| found.last_seen.elapsed() < SERVER_TIMEOUT);
+    if browser.found.len() != before {
+        browser.changed = true;
+    }
+}
+
+/// Shows the found servers.
+fn update_list(
+    mut commands: Commands,
+    mut browser: ResMut<Browser>,
+    version: Res<ProtocolVersion>,
+    assets: Res<AssetServer>,
+    q_list: Query<(Entity, Option<&Children>), With<ServerList>>,
+    mut q_label: Query<(&mut Text, &ServerLabel)>,
+) {
+    // The latencies change all the time, so they are updated without rebuilding the list.
+    for (mut text, label) in q_label.iter_mut() {
+        if let Some(found) = browser.found.iter().find(|found| found.addr == label.0) {
+            text.sections[0].value = found.label(&version);
+        }
+    }
+
+    if !browser.changed {
+        return;
+    }
+    browser.changed = false;
+    let (list, children) = match q_list.get_single() {
+        Ok(list) => list,
+        Err(_) => return,
+    };
+    if let Some(children) = children {
+        for child in children.iter() {
+            commands.entity(*child).despawn_recursive();
+        }
+    }
+
+    let font = assets.load("FiraMono-Medium.ttf");
+    let text_style = TextStyle {
+        font,
+        color: Color::BLACK,
+        font_size: 40.0,
+    };
+    commands.entity(list).with_children(|parent| {
+        if browser.found.is_empty() {
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(
+                    "Looking for games...",
+                    TextStyle {
+                        color: Color::WHITE,
+                        ..text_style.clone()
+                    },
+                    TextAlignment::default(),
+                ),
+                ..default()
+            });
+        }
+
+        for found in browser.found.iter() {
+            // Servers with another version can't be joined.
+            let color = if found.info.version == version.0 {
+                Color::rgb_u8(255, 255, 255)
+            } else {
+                Color::rgb_u8(150, 150, 150)
+            };
+
+            parent
+                .spawn_bundle(ButtonBundle {
+                    color: UiColor(color),
+                    style: Style {
+                        size: Size::new(Val::Px(1400.0), Val::Px(70.0)),
+                        margin: Rect::all(Val::Px(5.0)),
+                        justify_content: JustifyContent::Center,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    ..default()
+                })
+                .insert(BrowserButton::Join(found.addr))
+                .with_children(|parent| {
+                    parent
+                        .spawn_bundle(TextBundle {
+                            text: Text::with_section(
+                                found.label(&version),
+                                text_style.clone(),
+                                TextAlignment::default(),
+                            ),
+                            ..default()
+                        })
+                        .insert(ServerLabel(found.addr));
+                });
+        }
+    });
+}
+
+fn handle_ui(
+    q_interaction: Query<(&Interaction, &BrowserButton), Changed<Interaction>>,
+    mut game_state: ResMut<State<GameState>>,
+    browser: Res<Browser>,
+    version: Res<ProtocolVersion>,
+    mut commands: Commands,
+) {
+    for (interaction, button) in q_interaction.iter() {
+        if *interaction != Interaction::Clicked {
+            continue;
+        }
+        match *button {
+            BrowserButton::Join(addr) => {
+                let found = match browser.found.iter().find(|found| found.addr == addr) {
+                    Some(found) if found.info.version == version.0 => found,
+                    _ => continue,
+                };
+                commands.insert_resource(GameIp(found.addr));
+                commands.insert_resource(MultiplayerType::Client);
+                commands.remove_resource::<BotGame>();
+                // Destroy the client/server when returning to the menu.
+                commands.remove_resource::<Client>();
+                commands.remove_resource::<Server>();
+                let _ = game_state.set(GameState::Lobby);
+                return;
+            }
+            BrowserButton::Back => {
+                let _ = game_state.set(GameState::Menu);
+                return;
+            }
+        }
+    }
+}
+
+fn clean_up(mut commands: Commands, q_items: Query<Entity, With<BrowserItem>>) {
+    for e in q_items.iter() {
+        commands.entity(e).despawn_recursive();
+    }
+    commands.remove_resource::<Browser>();
+}
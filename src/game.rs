@@ -1,24 +1,44 @@
-use crate::lobby::Players;
-use crate::messages::{BrickBreak, GameWin, Ping};
-use crate::{GameState, MyTransform, MyVelocity};
-use bevy::ecs::query::QueryEntityError;
+use crate::bot::{BotGame, BOT_CID};
+use crate::chat::ChatInput;
+use crate::config::Settings;
+use crate::controls::{Action, ActionInput};
+use crate::gamepad::pad_axes;
+use crate::level::{CurrentLevel, Level};
+use crate::lobby::{disconnect_cause, disconnect_players, Players, SessionToken};
+use crate::messages::{
+    ActiveEffects, BallDespawn, BallSpawn, BrickUpdate, DisconnectReason, GameWin, MatchPause, Ping,
+};
+use crate::reconnect::{
+    paddle_s_dir, ReconnectOverlay, ReconnectR, Reconnecting, RECONNECT_GRACE,
+};
+use crate::rounds::{MatchResult, RoundOverR, RoundWinE};
+use crate::{GameState, MultiplayerType, MyTransform, MyVelocity};
+use bevy::ecs::system::SystemParam;
+use bevy::math::Vec3Swizzles;
 use bevy::prelude::*;
-use carrier_pigeon::{Client, Server};
+use carrier_pigeon::{Client, OptionPendingClient, Server};
 use heron::*;
 use serde::{Deserialize, Serialize};
+use std::any::Any;
 use std::f32::consts::PI;
+use std::io;
+use std::marker::PhantomData;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use bevy_pigeon::sync::{CNetDir, NetComp, NetEntity, SNetDir};
-use carrier_pigeon::net::{CIdSpec, Status};
+use carrier_pigeon::net::Status;
 use rand::Rng;
 
-pub struct GamePlugin;
+pub struct GamePlugin {
+    /// Whether the game is running without a window or audio.
+    pub headless: bool,
+}
 
 impl Plugin for GamePlugin {
     fn build(&self, app: &mut App) {
         app
             .add_event::<GameWinE>()
-            .add_startup_system(load_sfx)
+            .add_event::<BrickBrokenE>()
+            .init_resource::<NetIds>()
             .add_system_set(
             SystemSet::on_enter(GameState::Game)
                 .with_system(setup_game)
@@ -27,33 +47,81 @@ impl Plugin for GamePlugin {
             ).add_system_set(
             SystemSet::on_update(GameState::Game)
                 .with_system(ping)
-                .with_system(sfx)
                 .with_system(handle_discon)
+                .with_system(handle_server_discon)
                 .with_system(break_bricks)
-                .with_system(move_paddle)
+                .with_system(regen_bricks)
+                .with_system(follow_balls)
                 .with_system(clamp_ball_speed)
                 .with_system(check_game_win)
                 .with_system(handle_game_win)
                 .with_system(leave_game_after_win),
             ).add_system_set(SystemSet::on_exit(GameState::Game).with_system(clean_up));
+
+        if !self.headless {
+            app
+                .add_startup_system(load_sfx)
+                .add_system_set(
+                SystemSet::on_enter(GameState::Game)
+                    .with_system(setup_hud),
+                ).add_system_set(
+                SystemSet::on_update(GameState::Game)
+                    .with_system(sfx)
+                    .with_system(tint_bricks)
+                    .with_system(move_paddle)
+                    .with_system(pause_offline_game),
+                );
+        }
     }
 }
 
+/// How close a brick has to be to an exploding brick to be destroyed by it.
+const EXPLOSION_RADIUS: f32 = 150.0;
+/// How long a regenerating brick takes to heal one hit point.
+const REGEN_TIME: Duration = Duration::from_secs(5);
+
 #[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
-/// A brick that is destroyed when the ball hits it.
+/// A brick that is damaged when the ball hits it, with its id.
 pub struct Brick(pub u32);
 
+#[derive(Component, Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug, Hash, Default)]
+/// What happens when the ball hits a brick.
+pub enum BrickKind {
+    /// Loses a hit point, and breaks once it has none left.
+    #[default]
+    Normal,
+    /// Can't be broken.
+    Steel,
+    /// Destroys the bricks around it when it breaks.
+    Explosive,
+    /// Heals a hit point every few seconds.
+    Regen,
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// The hit points of a brick.
+pub struct BrickHp {
+    pub hp: u32,
+    pub max: u32,
+}
+
+#[derive(Component, Clone, Debug)]
+/// The timer for healing a regenerating brick.
+///
+/// Only ticked on the server.
+struct Regen(Timer);
+
 #[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
-/// A brick that is destroyed when the ball hits it.
-pub struct Paddle(Team);
+/// A paddle that is controlled by the player on the team.
+pub struct Paddle(pub Team);
 
 #[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
-/// The ball.
-pub struct Ball;
+/// A ball, with the id of its `NetEntity`.
+pub struct Ball(pub u64);
 
 #[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
 /// The target that the opposing team is trying to hit.
-pub struct Target(Team);
+pub struct Target(pub Team);
 
 #[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
 /// All game items have this so that they can be cleaned up easily.
@@ -70,9 +138,70 @@ pub struct PingTimer(Timer);
 /// The game win instant.
 pub struct GameWinR(pub Instant);
 
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// The instant that the client lost the connection to the server.
+pub struct DisconnectedR(pub Instant);
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// Marks a game without other people in it as paused, with the overlay that says so.
+pub struct Paused(Entity);
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
 /// The game win event.
-pub struct GameWinE(Team);
+pub struct GameWinE(pub Team);
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+/// The event for when the ball breaks a brick, with the position of the brick.
+///
+/// Only sent on the server.
+pub struct BrickBrokenE(pub Vec2);
+
+/// What runs the match: the server, or a local game, which has no connections at all.
+#[derive(SystemParam)]
+pub struct Authority<'w, 's> {
+    server: Option<Res<'w, Server>>,
+    multiplayer_type: Option<Res<'w, MultiplayerType>>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> Authority<'w, 's> {
+    /// Whether this runs the match, instead of following a server.
+    pub fn runs_match(&self) -> bool {
+        self.server.is_some() || self.multiplayer_type.as_deref() == Some(&MultiplayerType::Local)
+    }
+
+    /// The server, if there is one.
+    pub fn server(&self) -> Option<&Server> {
+        self.server.as_deref()
+    }
+
+    /// Sends `msg` to every client. A local game has no clients to send it to.
+    pub fn broadcast<T: Any + Send + Sync>(&self, msg: &T) -> io::Result<()> {
+        match self.server {
+            Some(ref server) => server.broadcast(msg),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Hands out the ids of the [`NetEntity`]s that are spawned during the game.
+///
+/// Only used by the server.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, Default)]
+pub struct NetIds(u64);
+
+impl NetIds {
+    pub fn next(&mut self) -> u64 {
+        self.0 += 1;
+        self.0
+    }
+}
+
+/// The id of the ball that the game starts with.
+const BALL_ID: u64 = 5768696975200910899;
+pub const PADDLE_WIDTH: f32 = 30.0;
+pub const PADDLE_HEIGHT: f32 = 200.0;
 
 #[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug, Hash)]
 pub enum Team {
@@ -81,7 +210,7 @@ pub enum Team {
 }
 
 impl Team {
-    fn other(&self) -> Self {
+    pub fn other(&self) -> Self {
         match self {
             Team::Left => Team::Right,
             Team::Right => Team::Left,
@@ -125,38 +254,15 @@ fn load_sfx(
     })
 }
 
-fn setup_game(mut commands: Commands, assets: Res<AssetServer>) {
+fn setup_game(
+    mut commands: Commands,
+    assets: Option<Res<AssetServer>>,
+    level: Res<CurrentLevel>,
+) {
     let timer = PingTimer(Timer::new(Duration::from_millis(2000), true));
     commands.insert_resource(timer);
-
-    let font = assets.load("FiraMono-Medium.ttf");
-
-    // Ping counter
-    commands.spawn_bundle(TextBundle {
-        node: Default::default(),
-        style: Style {
-            position: Rect { top: Val::Px(0.0), left: Val::Px(0.0), right: Val::Auto, bottom: Val::Auto},
-            padding: Rect::all(Val::Px(5.0)),
-            ..default()
-        },
-        text: Text::with_section(
-            "Effective Latency: -",
-            TextStyle {
-                font,
-                font_size: 40.0,
-                color: Color::BLACK,
-            },
-            TextAlignment::default(),
-        ),
-        calculated_size: Default::default(),
-        focus_policy: Default::default(),
-        transform: Default::default(),
-        global_transform: Default::default(),
-        visibility: Default::default(),
-        ..default()
-    })
-        .insert(PingCounter)
-        .insert(GameItem);
+    let level = &level.0;
+    let (width, height) = (level.size.x, level.size.y);
 
     // Walls
     commands
@@ -171,10 +277,10 @@ fn setup_game(mut commands: Commands, assets: Res<AssetServer>) {
             parent
                 .spawn()
                 .insert(Name::new("Wall B"))
-                .insert(Transform::from_xyz(0.0, (-1080.0 / 2.0) - 40.0, 0.0))
+                .insert(Transform::from_xyz(0.0, (-height / 2.0) - 40.0, 0.0))
                 .insert(GlobalTransform::default())
                 .insert(CollisionShape::Cuboid {
-                    half_extends: Vec3::new(2000.0 / 2.0, 40.0, 0.0),
+                    half_extends: Vec3::new((width + 80.0) / 2.0, 40.0, 0.0),
                     border_radius: None,
                 });
 
@@ -182,10 +288,10 @@ fn setup_game(mut commands: Commands, assets: Res<AssetServer>) {
             parent
                 .spawn()
                 .insert(Name::new("Wall T"))
-                .insert(Transform::from_xyz(0.0, (1080.0 / 2.0) + 40.0, 0.0))
+                .insert(Transform::from_xyz(0.0, (height / 2.0) + 40.0, 0.0))
                 .insert(GlobalTransform::default())
                 .insert(CollisionShape::Cuboid {
-                    half_extends: Vec3::new(2000.0 / 2.0, 40.0, 0.0),
+                    half_extends: Vec3::new((width + 80.0) / 2.0, 40.0, 0.0),
                     border_radius: None,
                 });
 
@@ -193,10 +299,10 @@ fn setup_game(mut commands: Commands, assets: Res<AssetServer>) {
             parent
                 .spawn()
                 .insert(Name::new("Wall L"))
-                .insert(Transform::from_xyz((-1920.0 / 2.0) - 40.0, 0.0, 0.0))
+                .insert(Transform::from_xyz((-width / 2.0) - 40.0, 0.0, 0.0))
                 .insert(GlobalTransform::default())
                 .insert(CollisionShape::Cuboid {
-                    half_extends: Vec3::new(40.0, 1160.0 / 2.0, 0.0),
+                    half_extends: Vec3::new(40.0, (height + 80.0) / 2.0, 0.0),
                     border_radius: None,
                 });
 
@@ -204,10 +310,10 @@ fn setup_game(mut commands: Commands, assets: Res<AssetServer>) {
             parent
                 .spawn()
                 .insert(Name::new("Wall R"))
-                .insert(Transform::from_xyz((1920.0 / 2.0) + 40.0, 0.0, 0.0))
+                .insert(Transform::from_xyz((width / 2.0) + 40.0, 0.0, 0.0))
                 .insert(GlobalTransform::default())
                 .insert(CollisionShape::Cuboid {
-                    half_extends: Vec3::new(40.0, 1160.0 / 2.0, 0.0),
+                    half_extends: Vec3::new(40.0, (height + 80.0) / 2.0, 0.0),
                     border_radius: None,
                 });
         })
@@ -216,9 +322,86 @@ fn setup_game(mut commands: Commands, assets: Res<AssetServer>) {
             ..Default::default()
         });
 
-    let ball_ico = assets.load("ball.png");
+    spawn_ball(
+        &mut commands,
+        assets.as_deref(),
+        BALL_ID,
+        Transform::default(),
+        Velocity::from_linear(Vec3::new(750.0, 0.0, 0.0)),
+    );
+
+    // Targets
+    let crown_ico = assets.as_ref().map(|a| a.load("crown.png")).unwrap_or_default();
+    for (team, color, name) in [
+        (Team::Left, Color::rgb_u8(255, 25, 25), "Left Target"),
+        (Team::Right, Color::rgb_u8(25, 25, 255), "Right Target"),
+    ] {
+        let target = level.target(team);
+        commands
+            .spawn()
+            .insert_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color,
+                    custom_size: Some(Vec2::new(target.size, target.size)),
+                    ..Default::default()
+                },
+                texture: crown_ico.clone(),
+                transform: Transform::from_translation(target.center.extend(0.0)),
+                ..Default::default()
+            })
+            .insert(CollisionShape::Sphere {
+                radius: target.size / 2.0,
+            })
+            .insert(RigidBody::Sensor)
+            .insert(PhysicMaterial {
+                restitution: 1.0,
+                ..Default::default()
+            })
+            .insert(RotationConstraints::lock())
+            .insert(Collisions::default())
+            .insert(GameItem)
+            .insert(Target(team))
+            .insert(Name::new(name));
+    }
+
+    // Obstacles
+    for obstacle in &level.obstacles {
+        commands
+            .spawn()
+            .insert_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: Color::DARK_GRAY,
+                    custom_size: Some(obstacle.size),
+                    ..Default::default()
+                },
+                transform: Transform::from_translation(obstacle.center.extend(0.0)),
+                ..Default::default()
+            })
+            .insert(CollisionShape::Cuboid {
+                half_extends: (obstacle.size / 2.0).extend(0.0),
+                border_radius: None,
+            })
+            .insert(RigidBody::Static)
+            .insert(PhysicMaterial {
+                restitution: 1.0,
+                ..Default::default()
+            })
+            .insert(GameItem)
+            .insert(Name::new("Obstacle"));
+    }
+}
+
+/// Spawns a ball that is synced with the given `id`.
+pub fn spawn_ball(
+    commands: &mut Commands,
+    assets: Option<&AssetServer>,
+    id: u64,
+    transform: Transform,
+    velocity: Velocity,
+) -> Entity {
+    // Textures aren't loaded when headless.
+    let ball_ico = assets.map(|a| a.load("ball.png")).unwrap_or_default();
 
-    // ball
     commands
         .spawn()
         .insert_bundle(SpriteBundle {
@@ -228,7 +411,7 @@ fn setup_game(mut commands: Commands, assets: Res<AssetServer>) {
                 ..Default::default()
             },
             texture: ball_ico,
-            // transform: Transform::from_xyz(-500.0, 500.0, 0.0),
+            transform,
             ..Default::default()
         })
         .insert(CollisionShape::Sphere { radius: 10.0 })
@@ -238,132 +421,100 @@ fn setup_game(mut commands: Commands, assets: Res<AssetServer>) {
             ..Default::default()
         })
         .insert(RotationConstraints::lock())
-        .insert(Velocity::from_linear(Vec3::new(750.0, 0.0, 0.0)))
+        .insert(velocity)
         .insert(GameItem)
-        .insert(Ball)
+        .insert(Ball(id))
         .insert(Name::new("Ball"))
         .insert(NetComp::<Velocity, MyVelocity>::new(false, CNetDir::From, SNetDir::to_all()))
         .insert(NetComp::<Transform, MyTransform>::new(true, CNetDir::From, SNetDir::to_all()))
-        .insert(NetEntity::new(5768696975200910899));
-
-    // Targets
-    let crown_ico = assets.load("crown.png");
-    let target_size = 125.0;
-    commands
-        .spawn()
-        .insert_bundle(SpriteBundle {
-            sprite: Sprite {
-                color: Color::rgb_u8(255, 25, 25),
-                custom_size: Some(Vec2::new(target_size, target_size)),
-                ..Default::default()
-            },
-            texture: crown_ico.clone(),
-            transform: Transform::from_xyz(-897.5, 0.0, 0.0),
-            ..Default::default()
-        })
-        .insert(CollisionShape::Sphere {
-            radius: target_size / 2.0,
-        })
-        .insert(RigidBody::Sensor)
-        .insert(PhysicMaterial {
-            restitution: 1.0,
-            ..Default::default()
-        })
-        .insert(RotationConstraints::lock())
-        .insert(Collisions::default())
-        .insert(GameItem)
-        .insert(Target(Team::Left))
-        .insert(Name::new("Left Target"));
-
-    commands
-        .spawn()
-        .insert_bundle(SpriteBundle {
-            sprite: Sprite {
-                color: Color::rgb_u8(25, 25, 255),
-                custom_size: Some(Vec2::new(target_size, target_size)),
-                ..Default::default()
-            },
-            texture: crown_ico,
-            transform: Transform::from_xyz(897.5, 0.0, 0.0),
-            ..Default::default()
-        })
-        .insert(CollisionShape::Sphere {
-            radius: target_size / 2.0,
-        })
-        .insert(RigidBody::Sensor)
-        .insert(PhysicMaterial {
-            restitution: 1.0,
-            ..Default::default()
-        })
-        .insert(RotationConstraints::lock())
-        .insert(Collisions::default())
-        .insert(GameItem)
-        .insert(Target(Team::Right))
-        .insert(Name::new("Left Right"));
+        .insert(NetEntity::new(id))
+        .id()
 }
 
-fn setup_bricks(mut commands: Commands) {
-    let mut id = 0;
-    let mut bricks = vec![];
-    // Left
-    let height = 108.0;
-    let width = 60.0;
-    let count = 10;
-    let colors = [
-        Color::RED,
-        Color::ORANGE_RED,
-        Color::ORANGE,
-        Color::YELLOW,
-        Color::YELLOW_GREEN,
-        Color::GREEN,
-    ];
-
-    for r in 0..colors.len() {
-        let color = colors[r];
-        let x = -500.0 - width * r as f32;
-        for i in 1..=count {
-            let h = i as f32 - (count + 1) as f32 / 2.0;
-            bricks.push(spawn_brick(
+/// Spawns and despawns the balls that the server spawned and despawned during the game.
+fn follow_balls(
+    client: Option<Res<Client>>,
+    server: Option<Res<Server>>,
+    assets: Option<Res<AssetServer>>,
+    q_ball: Query<(Entity, &Ball)>,
+    mut commands: Commands,
+) {
+    // The host already has the real balls.
+    if server.is_some() {
+        return;
+    }
+    if let Some(client) = client {
+        for msg in client.recv::<BallSpawn>() {
+            spawn_ball(
                 &mut commands,
-                color,
-                [x, (h * height)].into(),
-                width,
-                height,
-                id,
-            ));
-            id += 1;
+                assets.as_deref(),
+                msg.id,
+                msg.transform.into(),
+                msg.velocity.into(),
+            );
+        }
+        let despawned: Vec<_> = client.recv::<BallDespawn>().map(|msg| msg.id).collect();
+        for (e, ball) in q_ball.iter() {
+            if despawned.contains(&ball.0) {
+                commands.entity(e).despawn();
+            }
         }
     }
+}
 
-    // Right
-    let height = 108.0;
-    let width = 60.0;
-    let count = 10;
-    let colors = [
-        Color::SEA_GREEN,
-        Color::BLUE,
-        Color::MIDNIGHT_BLUE,
-        Color::INDIGO,
-        Color::PURPLE,
-        Color::VIOLET,
-    ];
+fn setup_hud(mut commands: Commands, assets: Res<AssetServer>) {
+    let font = assets.load("FiraMono-Medium.ttf");
 
-    for r in 0..colors.len() {
-        let color = colors[r];
-        let x = 500.0 + width * r as f32;
-        for i in 1..=count {
-            let h = i as f32 - (count + 1) as f32 / 2.0;
-            bricks.push(spawn_brick(
-                &mut commands,
-                color,
-                [x, (h * height)].into(),
-                width,
-                height,
-                id,
-            ));
-            id += 1;
-        }
-    }
+    // Ping counter
+    commands.spawn_bundle(TextBundle {
+        node: Default::default(),
+        style: Style {
+            position: Rect { top: Val::Px(0.0), left: Val::Px(0.0), right: Val::Auto, bottom: Val::Auto},
+            padding: Rect::all(Val::Px(5.0)),
+            ..default()
+        },
+        text: Text::with_section(
+            "Effective Latency: -",
+            TextStyle {
+                font,
+                font_size: 40.0,
+                color: Color::BLACK,
+            },
+            TextAlignment::default(),
+        ),
+        calculated_size: Default::default(),
+        focus_policy: Default::default(),
+        transform: Default::default(),
+        global_transform: Default::default(),
+        visibility: Default::default(),
+    })
+        .insert(PingCounter)
+        .insert(GameItem);
+}
+
+/// The description of a brick, so that the server and clients build the same bricks.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct BrickDef {
+    pub id: u32,
+    pub kind: BrickKind,
+    pub hp: u32,
+    pub color: Color,
+    pub center: Vec2,
+    pub width: f32,
+    pub height: f32,
+}
+
+fn setup_bricks(mut commands: Commands, level: Res<CurrentLevel>) {
+    spawn_bricks(&mut commands, &level.0);
+}
+
+/// Spawns a full set of the bricks of the `level`.
+pub fn spawn_bricks(commands: &mut Commands, level: &Level) {
+    let bricks: Vec<_> = level
+        .brick_defs()
+        .iter()
+        .map(|brick| spawn_brick(commands, brick, brick.hp))
+        .collect();
 
     commands
         .spawn()
@@ -404,8 +555,10 @@ fn ping(
     // Server respond to pings
     if let Some(ref server) = server {
         for msg in server.recv::<Ping>() {
-            let ping_msg = msg.m.clone();
-            server.send_to(msg.cid, &ping_msg).unwrap();
+            // The client may have left since it sent the ping.
+            if let Err(e) = server.send_to(msg.cid, msg.m) {
+                warn!("Failed to answer the ping of {}: {}", msg.cid, e);
+            }
         }
     }
 
@@ -413,32 +566,63 @@ fn ping(
     if timer.0.tick(time.delta()).just_finished() {
         // send ping packet.
         if let Some(ref client) = client {
-            client.send(&Ping {
+            if let Err(e) = client.send(&Ping {
                 time: unix_millis(),
-            }).unwrap();
+            }) {
+                warn!("Failed to send a ping: {}", e);
+            }
         }
     }
 }
 
-fn setup_paddles(players: Res<Players>, mut commands: Commands) {
-    let width = 30.0;
-    let height = 200.0;
-
-    let p1 = players.p1.as_ref().unwrap().0;
-    let p2 = players.p2.as_ref().unwrap().0;
+fn setup_paddles(
+    players: Res<Players>,
+    level: Res<CurrentLevel>,
+    server: Option<Res<Server>>,
+    mut e_game_win: EventWriter<GameWinE>,
+    mut commands: Commands,
+) {
+    let width = PADDLE_WIDTH;
+    let height = PADDLE_HEIGHT;
+
+    // A player can leave while the game starts, which forfeits it. Nobody owns their paddle.
+    let mut s_dir = |team| match players.get(team) {
+        Some((cid, _)) => paddle_s_dir(*cid),
+        None => {
+            if server.is_some() {
+                e_game_win.send(GameWinE(team.other()));
+            }
+            SNetDir::to_all()
+        }
+    };
+    let s_left_dir = s_dir(Team::Left);
+    let s_right_dir = s_dir(Team::Right);
 
-    let c_left_dir;
-    let c_right_dir;
+    let mut c_left_dir;
+    let mut c_right_dir;
 
-    match players.me.unwrap() {
-        Team::Left => {
+    match players.me {
+        Some(Team::Left) => {
             c_left_dir = CNetDir::To;
             c_right_dir = CNetDir::From;
         }
-        Team::Right => {
+        Some(Team::Right) => {
             c_right_dir = CNetDir::To;
             c_left_dir = CNetDir::From;
         }
+        // A dedicated server doesn't control a paddle.
+        None => {
+            c_left_dir = CNetDir::From;
+            c_right_dir = CNetDir::From;
+        }
+    }
+    // The host moves the bot's paddle itself.
+    if server.is_some() {
+        match players.team_of(BOT_CID) {
+            Some(Team::Left) => c_left_dir = CNetDir::To,
+            Some(Team::Right) => c_right_dir = CNetDir::To,
+            None => {}
+        }
     }
 
     // Left
@@ -450,7 +634,7 @@ fn setup_paddles(players: Res<Players>, mut commands: Commands) {
                 color: Color::RED,
                 ..Default::default()
             },
-            transform: Transform::from_xyz(-350.0, 0.0, 0.0),
+            transform: Transform::from_translation(level.0.paddle_start(Team::Left).extend(0.0)),
             ..Default::default()
         })
         .insert(CollisionShape::Cuboid {
@@ -466,7 +650,7 @@ fn setup_paddles(players: Res<Players>, mut commands: Commands) {
         .insert(GameItem)
         .insert(Paddle(Team::Left))
         .insert(NetEntity::new(6413180502345645314))
-        .insert(NetComp::<Transform, MyTransform>::new(true, c_left_dir, SNetDir::ToFrom(CIdSpec::Except(p1), CIdSpec::Only(p1))))
+        .insert(NetComp::<Transform, MyTransform>::new(true, c_left_dir, s_left_dir))
         .insert(Name::new("Paddle L"));
 
     // Right
@@ -478,7 +662,7 @@ fn setup_paddles(players: Res<Players>, mut commands: Commands) {
                 color: Color::BLUE,
                 ..Default::default()
             },
-            transform: Transform::from_xyz(350.0, 0.0, 0.0),
+            transform: Transform::from_translation(level.0.paddle_start(Team::Right).extend(0.0)),
             ..Default::default()
         })
         .insert(CollisionShape::Cuboid {
@@ -494,62 +678,121 @@ fn setup_paddles(players: Res<Players>, mut commands: Commands) {
         .insert(GameItem)
         .insert(Paddle(Team::Right))
         .insert(NetEntity::new(6413180502345645315))
-        .insert(NetComp::<Transform, MyTransform>::new(true, c_right_dir, SNetDir::ToFrom(CIdSpec::Except(p2), CIdSpec::Only(p2))))
+        .insert(NetComp::<Transform, MyTransform>::new(true, c_right_dir, s_right_dir))
         .insert(Name::new("Paddle R"));
 }
 
 fn move_paddle(
     time: Res<Time>,
-    input: Res<Input<KeyCode>>,
+    input: ActionInput,
+    button_axes: Res<Axis<GamepadButton>>,
+    axes: Res<Axis<GamepadAxis>>,
     players: Res<Players>,
+    multiplayer_type: Res<MultiplayerType>,
+    level: Res<CurrentLevel>,
+    paused: Option<Res<Paused>>,
+    chat: Option<Res<ChatInput>>,
     mut q_paddle: Query<(&mut Transform, &Paddle)>,
 ) {
-    // Only run if we are a player.
-    if players.me.is_none() {
+    // The keys type the chat message instead.
+    if paused.is_some() || chat.is_some() {
         return;
     }
-    let me = players.me.unwrap();
 
-    let mut paddle: (Mut<Transform>, &Paddle) = q_paddle
-        .iter_mut()
-        .filter(|(_t, p)| p.0 == me)
-        .next()
-        .unwrap();
-    let mut translation = paddle.0.translation;
-    let mut rotation = paddle.0.rotation;
+    // Which players' bindings steer which paddle.
+    let controls = if *multiplayer_type == MultiplayerType::Local {
+        vec![(Team::Left, vec![0]), (Team::Right, vec![1])]
+    } else if let Some(me) = players.me {
+        vec![(me, vec![0, 1])]
+    } else {
+        // Only run if we are a player.
+        return;
+    };
+
+    for (team, controllers) in controls {
+        let mut movement = 0.0;
+        let mut turn = 0.0;
+        for player in controllers {
+            movement += input.axis(player, Action::MoveUp, Action::MoveDown);
+            turn += input.axis(player, Action::RotateCcw, Action::RotateCw);
+            if let Some(pad) = input.pad(player) {
+                let (pad_movement, pad_turn) = pad_axes(pad, &button_axes, &axes);
+                movement += pad_movement;
+                turn += pad_turn;
+            }
+        }
 
-    if input.pressed(KeyCode::W) || input.pressed(KeyCode::Up) {
-        translation += Vec3::new(0.0, 14.0, 0.0) * time.delta_seconds() * 60.0;
+        for (mut transform, _) in q_paddle.iter_mut().filter(|(_, p)| p.0 == team) {
+            steer_paddle(
+                &mut transform,
+                movement.clamp(-1.0, 1.0),
+                turn.clamp(-1.0, 1.0),
+                time.delta_seconds(),
+                level.0.size.y / 2.0 - 40.0,
+            );
+        }
     }
+}
 
-    if input.pressed(KeyCode::S) || input.pressed(KeyCode::Down) {
-        translation -= Vec3::new(0.0, 14.0, 0.0) * time.delta_seconds() * 60.0;
+/// Pauses and resumes a local or bot game, which nobody else is waiting on.
+fn pause_offline_game(
+    mut commands: Commands,
+    input: ActionInput,
+    multiplayer_type: Res<MultiplayerType>,
+    bot: Option<Res<BotGame>>,
+    paused: Option<Res<Paused>>,
+    game_win: Option<Res<GameWinR>>,
+    chat: Option<Res<ChatInput>>,
+    assets: Res<AssetServer>,
+    mut physics_time: ResMut<PhysicsTime>,
+) {
+    if *multiplayer_type != MultiplayerType::Local && bot.is_none() {
+        return;
     }
-
-    let (x, y, mut z) = rotation.to_euler(EulerRot::XYZ);
-
-    if input.pressed(KeyCode::Q) || input.pressed(KeyCode::Left) {
-        z += PI / 72.0 * time.delta_seconds() * 60.0;
+    // Escape closes the chat instead.
+    if game_win.is_some() || chat.is_some() {
+        return;
     }
-    if input.pressed(KeyCode::E) || input.pressed(KeyCode::Right) {
-        z -= PI / 72.0 * time.delta_seconds() * 60.0;
+    if ![0, 1].into_iter().any(|player| input.just_pressed(player, Action::Pause)) {
+        return;
     }
 
+    match paused {
+        Some(paused) => {
+            commands.entity(paused.0).despawn_recursive();
+            commands.remove_resource::<Paused>();
+            physics_time.resume();
+        }
+        None => {
+            let overlay = spawn_overlay(&mut commands, &assets, "Paused".to_owned());
+            commands.insert_resource(Paused(overlay));
+            physics_time.pause();
+        }
+    }
+}
+
+/// Moves and rotates a paddle, where `movement` and `turn` go from -1 to 1.
+fn steer_paddle(transform: &mut Transform, movement: f32, turn: f32, dt: f32, max_y: f32) {
+    let mut translation = transform.translation;
+    translation += Vec3::new(0.0, 14.0, 0.0) * movement * dt * 60.0;
+
+    let (x, y, mut z) = transform.rotation.to_euler(EulerRot::XYZ);
+    z += PI / 72.0 * turn * dt * 60.0;
+
     // Clamp
     z = z.clamp(-PI / 8.0, PI / 8.0);
-    rotation = Quat::from_euler(EulerRot::XYZ, x, y, z);
-
-    translation.y = translation.y.clamp(-500.0, 500.0);
+    translation.y = translation.y.clamp(-max_y, max_y);
 
     // Apply
-    paddle.0.rotation = rotation;
-    paddle.0.translation = translation;
+    transform.rotation = Quat::from_euler(EulerRot::XYZ, x, y, z);
+    transform.translation = translation;
 }
 
 fn sfx(
     mut collisions: EventReader<CollisionEvent>,
     audio: Res<Audio>,
     sfx: Res<Sfx>,
+    settings: Res<Settings>,
     q_paddle: Query<&Paddle>,
 ) {
     let mut rng = rand::thread_rng();
@@ -562,99 +805,214 @@ fn sfx(
                 let i = rng.gen_range(0, 4);
                 let clip = if b { &sfx.bink[i] } else { &sfx.bonk[i] };
 
-                audio.play((*clip).clone());
+                audio.play_with_settings(
+                    (*clip).clone(),
+                    PlaybackSettings::ONCE.with_volume(settings.effects_volume()),
+                );
             }
         }
     }
 }
 
 fn break_bricks(
-    server: Option<Res<Server>>,
+    authority: Authority,
     client: Option<Res<Client>>,
     q_ball: Query<Entity, With<Ball>>,
-    q_brick: Query<(Entity, &Brick)>,
+    mut q_brick: Query<(Entity, &Brick, &BrickKind, &mut BrickHp, &Transform)>,
     mut collisions: EventReader<CollisionEvent>,
+    mut e_brick_broken: EventWriter<BrickBrokenE>,
     mut commands: Commands,
-    audio: Res<Audio>,
-    sfx: Res<Sfx>
+    audio: Option<Res<Audio>>,
+    sfx: Option<Res<Sfx>>,
+    settings: Res<Settings>,
 ) {
     let mut rng = rand::thread_rng();
+    let mut play_pop = || {
+        if let (Some(audio), Some(sfx)) = (&audio, &sfx) {
+            let i = rng.gen_range(0, 4);
+            let playback = PlaybackSettings::ONCE.with_volume(settings.effects_volume());
+            audio.play_with_settings(sfx.pop[i].clone(), playback);
+        }
+    };
+
+    if authority.runs_match() {
+        // Damage bricks based on collision with any ball.
+        let mut hit = vec![];
+        for event in collisions.iter() {
+            if let CollisionEvent::Stopped(d1, d2) = event {
+                let e1 = d1.rigid_body_entity();
+                let e2 = d2.rigid_body_entity();
+
+                let brick_e = if q_ball.get(e1).is_ok() {
+                    e2
+                } else if q_ball.get(e2).is_ok() {
+                    e1
+                } else {
+                    continue;
+                };
+                // Two balls can hit the same brick at once.
+                if q_brick.get(brick_e).is_ok() && !hit.contains(&brick_e) {
+                    hit.push(brick_e);
+                }
+            }
+        }
 
-    if let Some(server) = server {
-        // Break balls based on collision
-        for ball in q_ball.iter() {
-            for event in collisions.iter() {
-                if let CollisionEvent::Stopped(d1, d2) = event {
-                    let e1 = d1.rigid_body_entity();
-                    let e2 = d2.rigid_body_entity();
-
-                    let brick_e2: Result<(Entity, &Brick), QueryEntityError> = q_brick.get(e2);
-                    let brick_e1: Result<(Entity, &Brick), QueryEntityError> = q_brick.get(e1);
-
-                    // e2 is a brick colliding with a ball
-                    if e1 == ball && brick_e2.is_ok() {
-                        server
-                            .broadcast(&BrickBreak(brick_e2.unwrap().1 .0))
-                            .unwrap();
-                        commands.entity(e2).despawn();
-                        let i = rng.gen_range(0, 4);
-                        audio.play(sfx.pop[i].clone());
-                    }
-                    // e1 is a brick colliding with a ball
-                    if e2 == ball && brick_e1.is_ok() {
-                        server
-                            .broadcast(&BrickBreak(brick_e1.unwrap().1 .0))
-                            .unwrap();
-                        commands.entity(e1).despawn();
-                        let i = rng.gen_range(0, 4);
-                        audio.play(sfx.pop[i].clone());
+        let mut to_break = vec![];
+        for e in hit {
+            let (e, brick, kind, mut hp, _) = q_brick.get_mut(e).unwrap();
+            if *kind == BrickKind::Steel {
+                continue;
+            }
+            hp.hp = hp.hp.saturating_sub(1);
+            if hp.hp == 0 {
+                to_break.push(e);
+            } else {
+                let msg = BrickUpdate {
+                    id: brick.0,
+                    hp: hp.hp,
+                };
+                if let Err(e) = authority.broadcast(&msg) {
+                    warn!("Failed to broadcast the brick update: {}", e);
+                }
+            }
+        }
+
+        // Explosions can set off other explosive bricks.
+        let mut broken = vec![];
+        while let Some(e) = to_break.pop() {
+            if broken.contains(&e) {
+                continue;
+            }
+            broken.push(e);
+            let (_, brick, kind, _, transform) = q_brick.get(e).unwrap();
+            let center = transform.translation.xy();
+            let msg = BrickUpdate {
+                id: brick.0,
+                hp: 0,
+            };
+            if let Err(e) = authority.broadcast(&msg) {
+                warn!("Failed to broadcast the brick update: {}", e);
+            }
+            commands.entity(e).despawn();
+            e_brick_broken.send(BrickBrokenE(center));
+            play_pop();
+
+            if *kind == BrickKind::Explosive {
+                for (other, _, other_kind, _, t) in q_brick.iter() {
+                    if *other_kind != BrickKind::Steel
+                        && !broken.contains(&other)
+                        && t.translation.xy().distance(center) <= EXPLOSION_RADIUS
+                    {
+                        to_break.push(other);
                     }
                 }
             }
         }
     } else if let Some(client) = client {
-        let ids: Vec<_> = client.recv::<BrickBreak>().map(|m| m.0).collect();
-        for (e, brick) in q_brick.iter() {
-            if ids.contains(&brick.0) {
-                commands.entity(e).despawn();
+        for msg in client.recv::<BrickUpdate>() {
+            for (e, brick, _, mut hp, _) in q_brick.iter_mut() {
+                if brick.0 != msg.id {
+                    continue;
+                }
+                if msg.hp == 0 {
+                    commands.entity(e).despawn();
+                    play_pop();
+                } else {
+                    hp.hp = msg.hp;
+                }
             }
         }
     }
 }
 
-fn clamp_ball_speed(mut q_ball: Query<&mut Velocity, With<Ball>>) {
+/// Heals the regenerating bricks.
+fn regen_bricks(
+    authority: Authority,
+    time: Res<Time>,
+    mut q_brick: Query<(&Brick, &mut BrickHp, &mut Regen)>,
+) {
+    if !authority.runs_match() {
+        return;
+    }
+
+    for (brick, mut hp, mut regen) in q_brick.iter_mut() {
+        if hp.hp >= hp.max {
+            regen.0.reset();
+            continue;
+        }
+        if regen.0.tick(time.delta()).just_finished() {
+            hp.hp += 1;
+            let msg = BrickUpdate {
+                id: brick.0,
+                hp: hp.hp,
+            };
+            if let Err(e) = authority.broadcast(&msg) {
+                warn!("Failed to broadcast the brick update: {}", e);
+            }
+        }
+    }
+}
+
+/// Fades the bricks as they are damaged.
+fn tint_bricks(mut q_brick: Query<(&BrickHp, &mut Sprite), Changed<BrickHp>>) {
+    for (hp, mut sprite) in q_brick.iter_mut() {
+        let health = hp.hp as f32 / hp.max.max(1) as f32;
+        sprite.color.set_a(0.3 + 0.7 * health);
+    }
+}
+
+fn clamp_ball_speed(
+    round_over: Option<Res<RoundOverR>>,
+    effects: Res<ActiveEffects>,
+    mut q_ball: Query<&mut Velocity, With<Ball>>,
+) {
+    // The ball is held still between rounds.
+    if round_over.is_some() {
+        return;
+    }
+    let factor = effects.speed_factor();
     for mut ball in q_ball.iter_mut() {
-        if ball.linear.x.abs() < 200.0 {
+        // A ball that isn't moving is held by a sticky paddle.
+        if ball.linear == Vec3::ZERO {
+            continue;
+        }
+        if ball.linear.x.abs() < 200.0 * factor {
             if ball.linear.x < 0.0 {
-                ball.linear.x = -200.0
+                ball.linear.x = -200.0 * factor
             } else {
-                ball.linear.x = 200.0
+                ball.linear.x = 200.0 * factor
             }
         }
-        if ball.linear.length() < 400.0 {
-            ball.linear = ball.linear.normalize() * 400.0;
+        if ball.linear.length() < 400.0 * factor {
+            ball.linear = ball.linear.normalize() * 400.0 * factor;
         }
-        if ball.linear.length() > 1200.0 {
-            ball.linear = ball.linear.normalize() * 1200.0;
+        if ball.linear.length() > 1200.0 * factor {
+            ball.linear = ball.linear.normalize() * 1200.0 * factor;
         }
     }
 }
 
 fn check_game_win(
-    server: Option<Res<Server>>,
+    authority: Authority,
     client: Option<Res<Client>>,
+    round_over: Option<Res<RoundOverR>>,
+    game_win: Option<Res<GameWinR>>,
     q_targets: Query<(&Target, &Collisions)>,
     q_ball: Query<Entity, With<Ball>>,
+    mut e_round_win: EventWriter<RoundWinE>,
     mut e_game_win: EventWriter<GameWinE>,
 ) {
-    if let Some(_server) = server {
+    if authority.runs_match() {
+        if round_over.is_some() || game_win.is_some() {
+            return;
+        }
         for (target, collisions) in q_targets.iter() {
             let collisions: &Collisions = collisions;
             for e in collisions.entities() {
                 // If the thing we collided with was a ball
                 if q_ball.get(e).is_ok() {
                     let win_side = target.0.other();
-                    e_game_win.send(GameWinE(win_side));
+                    e_round_win.send(RoundWinE(win_side));
                 }
             }
         }
@@ -668,77 +1026,155 @@ fn check_game_win(
 fn handle_game_win(
     players: Res<Players>,
     server: Option<Res<Server>>,
-    assets: Res<AssetServer>,
+    assets: Option<Res<AssetServer>>,
+    game_win: Option<Res<GameWinR>>,
     q_ball: Query<Entity, With<Ball>>,
     mut e_game_win: EventReader<GameWinE>,
     mut commands: Commands,
 ) {
-    for gw in e_game_win.iter() {
-        commands.insert_resource(GameWinR(Instant::now()));
+    // Only the first win counts. The winner can leave after it, which makes the other side win
+    // too.
+    let wins: Vec<Team> = e_game_win.iter().map(|gw| gw.0).collect();
+    let team = match wins.first() {
+        Some(team) if game_win.is_none() => *team,
+        _ => return,
+    };
+    commands.insert_resource(GameWinR(Instant::now()));
 
-        if let Some(ref server) = server {
-            server.broadcast(&GameWin(gw.0)).unwrap();
+    if let Some(ref server) = server {
+        // The loser may have just disconnected, so this can fail.
+        if let Err(e) = server.broadcast(&GameWin(team)) {
+            warn!("Failed to broadcast the game win: {}", e);
         }
+    }
 
-        let font = assets.load("FiraMono-Medium.ttf");
+    // The winner may have left in the same frame as the loser.
+    let winner = players
+        .get(team)
+        .map_or_else(|| format!("{:?}", team), |(_, name)| name.clone());
+    info!("{} wins!", winner);
+    commands.insert_resource(MatchResult {
+        winner: winner.clone(),
+    });
+    // Despawn balls.
+    for b in q_ball.iter() {
+        commands.entity(b).despawn();
+    }
 
-        let winner = match gw.0 {
-            Team::Left => players.p1.as_ref().unwrap().1.clone(),
-            Team::Right => players.p2.as_ref().unwrap().1.clone(),
-        };
-        // Despawn balls.
-        for b in q_ball.iter() {
-            commands.entity(b).despawn();
-        }
-        commands
-            .spawn_bundle(TextBundle {
-                node: Default::default(),
-                style: Style {
-                    position_type: PositionType::Absolute,
-                    margin: Rect::all(Val::Auto),
-                    padding: Rect::all(Val::Px(10.0)),
-                    flex_direction: FlexDirection::ColumnReverse,
-                    align_items: AlignItems::Center,
-                    align_self: AlignSelf::Center,
-                    size: Size {
-                        width: Val::Percent(100.0),
-                        height: Val::Auto,
-                    },
-                    ..default()
+    // There is no UI when headless.
+    if let Some(ref assets) = assets {
+        spawn_overlay(&mut commands, assets, format!("{} wins!", winner));
+    }
+}
+
+/// Spawns a message in the middle of the screen.
+pub fn spawn_overlay(commands: &mut Commands, assets: &AssetServer, msg: String) -> Entity {
+    let font = assets.load("FiraMono-Medium.ttf");
+    commands
+        .spawn_bundle(TextBundle {
+            node: Default::default(),
+            style: Style {
+                position_type: PositionType::Absolute,
+                margin: Rect::all(Val::Auto),
+                padding: Rect::all(Val::Px(10.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                align_items: AlignItems::Center,
+                align_self: AlignSelf::Center,
+                size: Size {
+                    width: Val::Percent(100.0),
+                    height: Val::Auto,
                 },
-                text: Text::with_section(
-                    format!("{} wins!", winner),
-                    TextStyle {
-                        font,
-                        font_size: 60.0,
-                        color: Color::BLACK,
-                    },
-                    TextAlignment::default(),
-                ),
                 ..default()
-            })
-            .insert(GameItem);
+            },
+            text: Text::with_section(
+                msg,
+                TextStyle {
+                    font,
+                    font_size: 60.0,
+                    color: Color::BLACK,
+                },
+                TextAlignment::default(),
+            ),
+            ..default()
+        })
+        .insert(GameItem)
+        .id()
+}
+
+/// Shows why the client lost the connection to the server, or starts reconnecting.
+fn handle_server_discon(
+    client: Option<Res<Client>>,
+    server: Option<Res<Server>>,
+    game_win: Option<Res<GameWinR>>,
+    discon: Option<Res<DisconnectedR>>,
+    reconnect: Option<Res<ReconnectR>>,
+    token: Option<Res<SessionToken>>,
+    assets: Option<Res<AssetServer>>,
+    mut commands: Commands,
+) {
+    // The host can't lose its own server, and the game is already over after a win.
+    if server.is_some() || game_win.is_some() || discon.is_some() || reconnect.is_some() {
+        return;
+    }
+    if let Some(client) = client {
+        if let Some(cause) = disconnect_cause(&client) {
+            // A dropped connection might come back, so try to rejoin.
+            if matches!(client.status(), Status::Dropped(_)) && token.is_some() {
+                info!("Lost the connection to the server ({}). Reconnecting.", cause);
+                commands.remove_resource::<Client>();
+                commands.insert_resource(ReconnectR::start());
+                if let Some(ref assets) = assets {
+                    let overlay = spawn_overlay(
+                        &mut commands,
+                        assets,
+                        "Connection lost. Reconnecting...".to_owned(),
+                    );
+                    commands.entity(overlay).insert(ReconnectOverlay);
+                }
+                return;
+            }
+
+            info!("Disconnected from the server: {}", cause);
+            commands.insert_resource(DisconnectedR(Instant::now()));
+            if let Some(ref assets) = assets {
+                spawn_overlay(&mut commands, assets, format!("Disconnected: {}", cause));
+            }
+        }
     }
 }
 
-fn leave_game_after_win(game_win: Option<Res<GameWinR>>, mut game_state: ResMut<State<GameState>>) {
-    if let Some(gw) = game_win {
-        if gw.0.elapsed() > Duration::from_millis(3000) {
-            let _ = game_state.set(GameState::Menu);
+/// Shows the result after a win, or goes back to the menu after a disconnect.
+fn leave_game_after_win(
+    game_win: Option<Res<GameWinR>>,
+    discon: Option<Res<DisconnectedR>>,
+    mut game_state: ResMut<State<GameState>>,
+) {
+    let end = game_win
+        .map(|gw| (gw.0, GameState::GameOver))
+        .or_else(|| discon.map(|d| (d.0, GameState::Menu)));
+    if let Some((end, next)) = end {
+        if end.elapsed() > Duration::from_millis(3000) {
+            let _ = game_state.set(next);
         }
     }
 }
 
-fn spawn_brick(
-    commands: &mut Commands,
-    color: Color,
-    center: Vec2,
-    width: f32,
-    height: f32,
-    id: u32,
-) -> Entity {
-    commands
-        .spawn()
+/// Spawns a brick with the given hit points.
+pub fn spawn_brick(commands: &mut Commands, brick: &BrickDef, hp: u32) -> Entity {
+    let BrickDef {
+        id,
+        kind,
+        hp: max,
+        color,
+        center,
+        width,
+        height,
+    } = *brick;
+    let mut entity = commands.spawn();
+    if kind == BrickKind::Regen {
+        entity.insert(Regen(Timer::new(REGEN_TIME, true)));
+    }
+    entity
         .insert_bundle(SpriteBundle {
             sprite: Sprite {
                 custom_size: Some(Vec2::new(width, height)),
@@ -759,6 +1195,8 @@ fn spawn_brick(
         .insert(RigidBody::Static)
         .insert(GameItem)
         .insert(Brick(id))
+        .insert(kind)
+        .insert(BrickHp { hp, max })
         .insert(Name::new("Brick"))
         .id()
 }
@@ -766,12 +1204,16 @@ fn spawn_brick(
 fn handle_discon(
     server: Option<ResMut<Server>>,
     mut e_game_win: EventWriter<GameWinE>,
-    players: Res<Players>,
+    mut players: ResMut<Players>,
+    game_win: Option<Res<GameWinR>>,
+    mut reconnecting: ResMut<Reconnecting>,
+    mut physics_time: ResMut<PhysicsTime>,
 ) {
     if let Some(mut server) = server {
         let mut discons = vec![];
 
         server.handle_disconnects(&mut |cid, status| {
+            let mut dropped = false;
             match status {
                 Status::Closed => {
                     info!("Connection {} Closed", cid);
@@ -781,38 +1223,121 @@ fn handle_discon(
                 },
                 Status::Dropped(e) => {
                     info!("Client {} Dropped with e: {}", cid, e);
+                    dropped = true;
                 },
                 Status::Connected => {}, // Not possible.
             }
 
-            discons.push(cid);
+            discons.push((cid, dropped));
         });
 
 
-        for cid in discons {
-            if let Some(ref p1) = players.p1 {
-                if p1.0 == cid {
-                    info!("Player 1 disconnected. Player 2 wins.");
-                    // Left player disconnected. Right wins.
-                    e_game_win.send(GameWinE(Team::Right));
+        for (cid, dropped) in discons {
+            let team = match players.team_of(cid) {
+                Some(team) => team,
+                None => {
+                    // Spectators can leave without affecting the game.
+                    players.remove_cid(cid);
+                    continue;
                 }
-            }
-            if let Some(ref p2) = players.p2 {
-                if p2.0 == cid {
-                    info!("Player 2 disconnected. Player 1 wins.");
-                    // Right player disconnected. Left wins.
-                    e_game_win.send(GameWinE(Team::Left));
+            };
+            let name = players.get(team).unwrap().1.clone();
+
+            // Give a dropped player a chance to reconnect, unless the game is already over.
+            if dropped && game_win.is_none() {
+                info!(
+                    "{} dropped. Waiting {}s for them to reconnect.",
+                    name,
+                    RECONNECT_GRACE.as_secs()
+                );
+                reconnecting.0.insert(team, Instant::now());
+                physics_time.pause();
+                let pause = MatchPause {
+                    waiting_for: Some(name),
+                };
+                if let Err(e) = server.broadcast(&pause) {
+                    warn!("Failed to broadcast the pause: {}", e);
                 }
+            } else {
+                info!("{} disconnected. {:?} wins.", name, team.other());
+                players.remove_cid(cid);
+                e_game_win.send(GameWinE(team.other()));
             }
         }
     }
 }
 
-fn clean_up(mut commands: Commands, q_game_items: Query<Entity, With<GameItem>>) {
+fn clean_up(
+    mut commands: Commands,
+    q_game_items: Query<Entity, With<GameItem>>,
+    server: Option<ResMut<Server>>,
+    players: Res<Players>,
+    game_win: Option<Res<GameWinR>>,
+    mut physics_time: ResMut<PhysicsTime>,
+) {
     for e in q_game_items.iter() {
         commands.entity(e).despawn_recursive();
     }
-    commands.remove_resource::<Client>();
-    commands.remove_resource::<Server>();
+    physics_time.resume();
+    // After a win, everyone stays connected to vote on a rematch.
+    if game_win.is_none() {
+        // Let the remaining clients know that the server is closing.
+        if let Some(mut server) = server {
+            disconnect_players(&mut server, &players, DisconnectReason::ServerClosed);
+        }
+        commands.remove_resource::<Client>();
+        commands.remove_resource::<Server>();
+    }
     commands.remove_resource::<GameWinR>();
+    commands.remove_resource::<Paused>();
+    commands.remove_resource::<DisconnectedR>();
+    commands.remove_resource::<ReconnectR>();
+    commands.remove_resource::<OptionPendingClient>();
+    commands.insert_resource(Reconnecting::default());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::event::Events;
+    use carrier_pigeon::CId;
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_event::<GameWinE>()
+            .insert_resource(Players {
+                p1: Some((1, "Alice".to_owned())),
+                p2: Some((2, "Bob".to_owned())),
+                ..default()
+            })
+            .add_system(handle_game_win);
+        app
+    }
+
+    /// Disconnects the player with `cid`, like `handle_discon` does.
+    fn disconnect(app: &mut App, cid: CId, team: Team) {
+        app.world.resource_mut::<Players>().remove_cid(cid);
+        app.world
+            .resource_mut::<Events<GameWinE>>()
+            .send(GameWinE(team.other()));
+    }
+
+    #[test]
+    fn winner_leaves_after_the_loser() {
+        let mut app = app();
+        disconnect(&mut app, 1, Team::Left);
+        app.update();
+        disconnect(&mut app, 2, Team::Right);
+        app.update();
+        assert_eq!(app.world.resource::<MatchResult>().winner, "Bob");
+    }
+
+    #[test]
+    fn both_leave_in_one_frame() {
+        let mut app = app();
+        disconnect(&mut app, 1, Team::Left);
+        disconnect(&mut app, 2, Team::Right);
+        app.update();
+        assert_eq!(app.world.resource::<MatchResult>().winner, "Right");
+    }
 }
@@ -0,0 +1,233 @@
+//! The screen that shows the result of a match, and where the players vote on a rematch.
+use crate::game::Team;
+use crate::lobby::{disconnect_cause, disconnect_players, Players};
+use crate::messages::{Disconnect, DisconnectReason, RematchStatus, RematchVote, Score};
+use crate::rematch::RematchVotes;
+use crate::rounds::MatchResult;
+use crate::{GameState, MultiplayerType};
+use bevy::prelude::PositionType::Absolute;
+use bevy::prelude::*;
+use carrier_pigeon::{Client, Server};
+
+pub struct GameOverPlugin;
+
+impl Plugin for GameOverPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(
+            SystemSet::on_enter(GameState::GameOver).with_system(setup_game_over),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameState::GameOver)
+                .with_system(handle_ui)
+                .with_system(update_rematch_label),
+        )
+        .add_system_set(SystemSet::on_exit(GameState::GameOver).with_system(clean_up));
+    }
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// All game over items have this so that they can be cleaned up easily.
+struct GameOverItem;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A marker for the text that shows the state of the rematch vote.
+struct RematchLabel;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+enum GameOverButton {
+    Rematch,
+    Leave,
+}
+
+fn setup_game_over(
+    mut commands: Commands,
+    assets: Res<AssetServer>,
+    result: Option<Res<MatchResult>>,
+    score: Res<Score>,
+    players: Res<Players>,
+    multiplayer_type: Res<MultiplayerType>,
+) {
+    let font = assets.load("FiraMono-Medium.ttf");
+    let text_style = TextStyle {
+        font,
+        color: Color::BLACK,
+        font_size: 60.0,
+    };
+    let button_style = Style {
+        size: Size::new(Val::Px(1000.0), Val::Px(100.0)),
+        margin: Rect::all(Val::Px(20.0)),
+        justify_content: JustifyContent::Center,
+        align_items: AlignItems::Center,
+        ..default()
+    };
+
+    let title = match result {
+        Some(result) => format!("{} wins!", result.winner),
+        None => "Game Over".to_owned(),
+    };
+
+    // parent
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: Absolute,
+                margin: Rect::all(Val::Auto),
+                padding: Rect::all(Val::Px(10.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                align_items: AlignItems::Center,
+                align_self: AlignSelf::Center,
+                size: Size {
+                    width: Val::Percent(100.0),
+                    height: Val::Auto,
+                },
+                ..default()
+            },
+            color: Color::CRIMSON.into(),
+            ..default()
+        })
+        .insert(GameOverItem)
+        .with_children(|parent| {
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(title, text_style.clone(), TextAlignment::default()),
+                ..default()
+            });
+
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section(
+                    format!("{} - {}", score.left, score.right),
+                    TextStyle {
+                        font_size: 80.0,
+                        ..text_style.clone()
+                    },
+                    TextAlignment::default(),
+                ),
+                ..default()
+            });
+
+            parent
+                .spawn_bundle(TextBundle {
+                    text: Text::with_section(
+                        "",
+                        TextStyle {
+                            font_size: 40.0,
+                            ..text_style.clone()
+                        },
+                        TextAlignment::default(),
+                    ),
+                    ..default()
+                })
+                .insert(RematchLabel);
+
+            let mut buttons = vec![("Leave", GameOverButton::Leave)];
+            // Spectators don't get a vote.
+            if players.me.is_some() || *multiplayer_type == MultiplayerType::Local {
+                buttons.insert(0, ("Rematch", GameOverButton::Rematch));
+            }
+            for (label, button) in buttons {
+                parent
+                    .spawn_bundle(ButtonBundle {
+                        color: UiColor(Color::rgb_u8(255, 255, 255)),
+                        style: button_style.clone(),
+                        ..default()
+                    })
+                    .insert(button)
+                    .with_children(|parent| {
+                        parent.spawn_bundle(TextBundle {
+                            text: Text::with_section(
+                                label,
+                                text_style.clone(),
+                                TextAlignment::default(),
+                            ),
+                            ..default()
+                        });
+                    });
+            }
+        });
+}
+
+fn handle_ui(
+    q_interaction: Query<(&Interaction, &GameOverButton), Changed<Interaction>>,
+    mut game_state: ResMut<State<GameState>>,
+    players: Res<Players>,
+    multiplayer_type: Res<MultiplayerType>,
+    mut votes: ResMut<RematchVotes>,
+    mut server: Option<ResMut<Server>>,
+    mut client: Option<ResMut<Client>>,
+    mut commands: Commands,
+) {
+    for (interaction, button) in q_interaction.iter() {
+        if *interaction == Interaction::Clicked {
+            match button {
+                // Both local players are at the same screen, so one click is enough.
+                GameOverButton::Rematch if *multiplayer_type == MultiplayerType::Local => {
+                    votes.0.insert(Team::Left, true);
+                    votes.0.insert(Team::Right, true);
+                }
+                GameOverButton::Rematch => {
+                    if let Some(client) = &client {
+                        if let Err(e) = client.send(&RematchVote(true)) {
+                            warn!("Failed to send the rematch vote: {}", e);
+                        }
+                    }
+                }
+                GameOverButton::Leave => {
+                    if let Some(server) = &mut server {
+                        disconnect_players(server, &players, DisconnectReason::ServerClosed);
+                    }
+                    if let Some(client) = &mut client {
+                        let _ = client.send(&RematchVote(false));
+                        let _ = client.disconnect(&Disconnect::new(DisconnectReason::Left));
+                    }
+                    commands.remove_resource::<Client>();
+                    commands.remove_resource::<Server>();
+                    let _ = game_state.set(GameState::Menu);
+                }
+            }
+        }
+    }
+}
+
+fn update_rematch_label(
+    mut q_label: Query<&mut Text, With<RematchLabel>>,
+    players: Res<Players>,
+    status: Option<Res<RematchStatus>>,
+    client: Option<Res<Client>>,
+    multiplayer_type: Res<MultiplayerType>,
+) {
+    let name = |team: Team| {
+        players
+            .get(team)
+            .map(|(_, name)| name.clone())
+            .unwrap_or_else(|| "Your opponent".to_owned())
+    };
+    let status = status.as_deref().cloned().unwrap_or_default();
+
+    let text = match (client.as_deref().and_then(disconnect_cause), players.me) {
+        (Some(cause), _) => format!("Disconnected: {}", cause),
+        (None, None) if *multiplayer_type == MultiplayerType::Local => "Play again?".to_owned(),
+        (None, None) => "Waiting for the players...".to_owned(),
+        (None, Some(me)) => {
+            let other = me.other();
+            if status.left.contains(&other) {
+                format!("{} left", name(other))
+            } else if status.accepted.contains(&me) {
+                format!("Waiting for {}...", name(other))
+            } else if status.accepted.contains(&other) {
+                format!("{} wants a rematch!", name(other))
+            } else {
+                "Play again?".to_owned()
+            }
+        }
+    };
+
+    for mut label in q_label.iter_mut() {
+        label.sections[0].value = text.clone();
+    }
+}
+
+fn clean_up(mut commands: Commands, q_items: Query<Entity, With<GameOverItem>>) {
+    for e in q_items.iter() {
+        commands.entity(e).despawn_recursive();
+    }
+    commands.remove_resource::<MatchResult>();
+}
@@ -0,0 +1,159 @@
+//! Gamepad input, for steering a paddle and for using the menus without a mouse.
+use bevy::prelude::*;
+use bevy::ui::UiSystem;
+
+/// How far a stick has to be pushed before it counts.
+const DEADZONE: f32 = 0.15;
+/// How far a stick has to be pushed to move the focus in the menus.
+const NAV_THRESHOLD: f32 = 0.5;
+/// The color of the button that has the gamepad's focus.
+const FOCUS_COLOR: Color = Color::rgb(1.0, 0.85, 0.3);
+
+pub struct GamepadPlugin;
+
+impl Plugin for GamepadPlugin {
+    fn build(&self, app: &mut App) {
+        // Pressing a button sets its `Interaction` like a click does, so this runs right after the
+        // mouse is handled, before the screens' ui handlers read it.
+        app.init_resource::<PadFocus>().add_system_to_stage(
+            CoreStage::PreUpdate,
+            navigate_ui.after(UiSystem::Focus),
+        );
+    }
+}
+
+/// Reads the movement and rotation of a paddle from the gamepad's sticks and triggers, both from -1
+/// to 1.
+///
+/// The left stick moves the paddle, and the triggers and the right stick rotate it. The buttons are
+/// read from the bindings in [`Controls`](crate::controls::Controls) instead.
+pub fn pad_axes(
+    pad: Gamepad,
+    button_axes: &Axis<GamepadButton>,
+    axes: &Axis<GamepadAxis>,
+) -> (f32, f32) {
+    let axis = |axis| deadzone(axes.get(GamepadAxis(pad, axis)).unwrap_or_default());
+    let trigger = |button| {
+        deadzone(
+            button_axes
+                .get(GamepadButton(pad, button))
+                .unwrap_or_default(),
+        )
+    };
+
+    let movement = axis(GamepadAxisType::LeftStickY);
+    let turn = trigger(GamepadButtonType::LeftTrigger2)
+        - trigger(GamepadButtonType::RightTrigger2)
+        - axis(GamepadAxisType::RightStickX);
+
+    (movement.clamp(-1.0, 1.0), turn.clamp(-1.0, 1.0))
+}
+
+fn deadzone(value: f32) -> f32 {
+    if value.abs() < DEADZONE {
+        0.0
+    } else {
+        value
+    }
+}
+
+/// The button that the gamepad has focused in the menus.
+#[derive(Default)]
+struct PadFocus {
+    button: Option<Entity>,
+    /// The color of the focused button before it was highlighted.
+    color: Color,
+    /// The button that was pressed last frame, which has to be released again.
+    pressed: Option<Entity>,
+    /// Whether a stick was pushed last frame, so that holding it only moves once.
+    stick_held: bool,
+}
+
+/// Moves the focus between the buttons with the D-pad or left stick, and presses them with South.
+fn navigate_ui(
+    gamepads: Res<Gamepads>,
+    buttons: Res<Input<GamepadButton>>,
+    axes: Res<Axis<GamepadAxis>>,
+    mut focus: ResMut<PadFocus>,
+    mut q_button: Query<(Entity, &GlobalTransform, &mut UiColor, &mut Interaction), With<Button>>,
+) {
+    if let Some(e) = focus.pressed.take() {
+        if let Ok((_, _, _, mut interaction)) = q_button.get_mut(e) {
+            *interaction = Interaction::None;
+        }
+    }
+
+    let mut step = 0i32;
+    let mut press = false;
+    let mut stick = 0.0;
+    for pad in gamepads.iter().copied() {
+        let just_pressed = |button| buttons.just_pressed(GamepadButton(pad, button));
+        if just_pressed(GamepadButtonType::DPadUp) || just_pressed(GamepadButtonType::DPadLeft) {
+            step -= 1;
+        }
+        if just_pressed(GamepadButtonType::DPadDown) || just_pressed(GamepadButtonType::DPadRight)
+        {
+            step += 1;
+        }
+        press |= just_pressed(GamepadButtonType::South);
+        let y = axes
+            .get(GamepadAxis(pad, GamepadAxisType::LeftStickY))
+            .unwrap_or_default();
+        if y.abs() > stick {
+            stick = y;
+        }
+    }
+    if stick.abs() > NAV_THRESHOLD {
+        if !focus.stick_held {
+            step -= stick.signum() as i32;
+        }
+        focus.stick_held = true;
+    } else {
+        focus.stick_held = false;
+    }
+
+    // The buttons from the top left to the bottom right. The ui's y axis points up.
+    let mut order: Vec<_> = q_button
+        .iter()
+        .map(|(e, t, _, _)| (e, t.translation))
+        .collect();
+    order.sort_by(|(_, a), (_, b)| {
+        b.y.partial_cmp(&a.y)
+            .unwrap()
+            .then(a.x.partial_cmp(&b.x).unwrap())
+    });
+    let order: Vec<Entity> = order.into_iter().map(|(e, _)| e).collect();
+
+    // The focused button is gone when the screen changes.
+    let current = focus.button.and_then(|e| order.iter().position(|o| *o == e));
+    if current.is_none() {
+        focus.button = None;
+    }
+    if step == 0 && !press {
+        return;
+    }
+    if order.is_empty() {
+        return;
+    }
+
+    let next = match current {
+        Some(i) => (i as i32 + step).rem_euclid(order.len() as i32) as usize,
+        None => 0,
+    };
+    if current != Some(next) {
+        if let Some((_, _, mut color, _)) = current.and_then(|i| q_button.get_mut(order[i]).ok()) {
+            color.0 = focus.color;
+        }
+        if let Ok((_, _, mut color, _)) = q_button.get_mut(order[next]) {
+            focus.color = color.0;
+            color.0 = FOCUS_COLOR;
+        }
+        focus.button = Some(order[next]);
+    } else if press {
+        // Pressing works like a click, which the screens listen for.
+        if let Ok((e, _, _, mut interaction)) = q_button.get_mut(order[next]) {
+            *interaction = Interaction::Clicked;
+            focus.pressed = Some(e);
+        }
+    }
+}
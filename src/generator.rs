@@ -0,0 +1,400 @@
+//! Generates brick layouts from a seed, so that every match can look different.
+//!
+//! The server only sends the [`Layout`], and everyone generates the same bricks from it.
+use crate::game::{BrickKind, Team};
+use crate::level::{BrickGroup, Level};
+use bevy::math::Vec2;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// The width of a column of bricks.
+const CELL_WIDTH: f32 = 60.0;
+/// The number of rows of bricks.
+const ROWS: u32 = 10;
+/// How far the bricks stay from the paddle's start.
+const PADDLE_GAP: f32 = 150.0;
+/// How far the bricks stay from the target.
+const TARGET_GAP: f32 = 40.0;
+
+/// The colors of the columns on the left and right, from the middle outwards.
+const LEFT_COLORS: [(u8, u8, u8); 6] = [
+    (255, 0, 0),
+    (255, 69, 0),
+    (255, 166, 0),
+    (255, 255, 0),
+    (153, 204, 51),
+    (0, 255, 0),
+];
+const RIGHT_COLORS: [(u8, u8, u8); 6] = [
+    (46, 140, 87),
+    (0, 0, 255),
+    (25, 25, 112),
+    (74, 0, 130),
+    (128, 0, 128),
+    (238, 130, 238),
+];
+const STEEL_COLOR: (u8, u8, u8) = (150, 160, 170);
+const EXPLOSIVE_COLOR: (u8, u8, u8) = (40, 40, 40);
+const REGEN_COLOR: (u8, u8, u8) = (0, 200, 120);
+
+/// How the two halves of a generated layout relate to each other.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub enum Symmetry {
+    /// The right half is the left half flipped left to right.
+    Mirror,
+    /// The right half is the left half turned around the center.
+    Rotational,
+    /// The halves are different, but have the same bricks.
+    Fair,
+}
+
+impl FromStr for Symmetry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mirror" => Ok(Symmetry::Mirror),
+            "rotational" => Ok(Symmetry::Rotational),
+            "fair" => Ok(Symmetry::Fair),
+            _ => Err(format!("unknown symmetry \"{}\"", s)),
+        }
+    }
+}
+
+/// How the bricks of a half are arranged.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub enum Pattern {
+    /// Every spot is filled at random.
+    Scatter,
+    /// Whole rows are filled, with gaps between them.
+    Rows,
+    /// The bricks are grouped in clumps.
+    Clusters,
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scatter" => Ok(Pattern::Scatter),
+            "rows" => Ok(Pattern::Rows),
+            "clusters" => Ok(Pattern::Clusters),
+            _ => Err(format!("unknown pattern \"{}\"", s)),
+        }
+    }
+}
+
+/// Everything that is needed to generate the same layout again.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct Layout {
+    pub seed: u64,
+    pub symmetry: Symmetry,
+    pub pattern: Pattern,
+    /// How many of the spots have a brick, in percent.
+    pub density: u8,
+}
+
+/// Whether the server generates the bricks, and how.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct LayoutSettings {
+    /// Whether the bricks of the level are replaced with generated ones.
+    pub enabled: bool,
+    pub symmetry: Symmetry,
+    pub pattern: Pattern,
+    pub density: u8,
+    /// The seed of every layout, instead of a new one for each match.
+    pub seed: Option<u64>,
+}
+
+impl Default for LayoutSettings {
+    fn default() -> Self {
+        LayoutSettings {
+            enabled: false,
+            symmetry: Symmetry::Mirror,
+            pattern: Pattern::Scatter,
+            density: 60,
+            seed: None,
+        }
+    }
+}
+
+impl LayoutSettings {
+    /// Picks a layout with a new seed, unless one was set, or `None` if the level's own bricks are
+    /// used.
+    pub fn pick(&self) -> Option<Layout> {
+        if !self.enabled {
+            return None;
+        }
+        Some(Layout {
+            seed: self.seed.unwrap_or_else(rand::random),
+            symmetry: self.symmetry,
+            pattern: self.pattern,
+            density: self.density.min(100),
+        })
+    }
+}
+
+/// A spot on one half of the arena, counted from the middle outwards and from the bottom up.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+struct Cell {
+    column: u32,
+    row: u32,
+}
+
+/// What is put in a spot.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+struct Spec {
+    kind: BrickKind,
+    hp: u32,
+}
+
+/// The spots that bricks can be put in, on either half.
+struct Grid {
+    columns: u32,
+    /// The distance from the middle to the first column.
+    inner: f32,
+    row_height: f32,
+    bottom: f32,
+}
+
+impl Grid {
+    fn new(level: &Level) -> Self {
+        let inner = level.paddle_start(Team::Left).x.abs() + PADDLE_GAP;
+        let target = level.target(Team::Left);
+        let outer = target.center.x.abs() - target.size / 2.0 - TARGET_GAP;
+        let columns = ((outer - inner) / CELL_WIDTH).floor().max(0.0) as u32 + 1;
+        Grid {
+            columns,
+            inner,
+            row_height: level.size.y / ROWS as f32,
+            bottom: -level.size.y / 2.0,
+        }
+    }
+
+    fn cells(&self) -> Vec<Cell> {
+        let mut cells = vec![];
+        for column in 0..self.columns {
+            for row in 0..ROWS {
+                cells.push(Cell { column, row });
+            }
+        }
+        cells
+    }
+
+    fn center(&self, team: Team, cell: Cell) -> Vec2 {
+        let side = match team {
+            Team::Left => -1.0,
+            Team::Right => 1.0,
+        };
+        Vec2::new(
+            side * (self.inner + cell.column as f32 * CELL_WIDTH),
+            self.bottom + (cell.row as f32 + 0.5) * self.row_height,
+        )
+    }
+}
+
+/// Generates the bricks of the `level` from the `layout`.
+///
+/// The same level and layout always give the same bricks, in the same order.
+pub fn generate(level: &Level, layout: &Layout) -> Vec<BrickGroup> {
+    // `StdRng` can change between versions of rand, but the clients have to get the same bricks.
+    let mut rng = ChaCha8Rng::seed_from_u64(layout.seed);
+    let grid = Grid::new(level);
+    let target = grid.cells().len() * layout.density.min(100) as usize / 100;
+
+    let left = fill(&mut rng, &grid, layout.pattern, target);
+    let specs: Vec<_> = left.iter().map(|_| pick_spec(&mut rng)).collect();
+
+    let right: Vec<_> = match layout.symmetry {
+        Symmetry::Mirror => left.clone(),
+        Symmetry::Rotational => left
+            .iter()
+            .map(|cell| Cell {
+                column: cell.column,
+                row: ROWS - 1 - cell.row,
+            })
+            .collect(),
+        Symmetry::Fair => fill(&mut rng, &grid, layout.pattern, left.len()),
+    };
+    // Fair halves get the same bricks, in different spots.
+    let mut right_specs = specs.clone();
+    if layout.symmetry == Symmetry::Fair {
+        right_specs.shuffle(&mut rng);
+    }
+
+    let mut bricks = vec![];
+    for (team, cells, specs, colors) in [
+        (Team::Left, &left, &specs, &LEFT_COLORS),
+        (Team::Right, &right, &right_specs, &RIGHT_COLORS),
+    ] {
+        for (cell, spec) in cells.iter().zip(specs.iter()) {
+            let color = match spec.kind {
+                BrickKind::Normal => colors[cell.column as usize % colors.len()],
+                BrickKind::Steel => STEEL_COLOR,
+                BrickKind::Explosive => EXPLOSIVE_COLOR,
+                BrickKind::Regen => REGEN_COLOR,
+            };
+            bricks.push(BrickGroup::Brick {
+                center: grid.center(team, *cell),
+                width: CELL_WIDTH,
+                height: grid.row_height,
+                color,
+                kind: spec.kind,
+                hp: spec.hp,
+            });
+        }
+    }
+    bricks
+}
+
+/// Picks exactly `count` spots of one half, arranged in the `pattern`.
+fn fill(rng: &mut ChaCha8Rng, grid: &Grid, pattern: Pattern, count: usize) -> Vec<Cell> {
+    let all = grid.cells();
+    let count = count.min(all.len());
+    let mut picked: Vec<Cell> = vec![];
+
+    match pattern {
+        Pattern::Scatter => {}
+        Pattern::Rows => {
+            let mut rows: Vec<u32> = (0..ROWS).collect();
+            rows.shuffle(rng);
+            for row in rows {
+                if picked.len() + grid.columns as usize > count {
+                    break;
+                }
+                picked.extend((0..grid.columns).map(|column| Cell { column, row }));
+            }
+        }
+        Pattern::Clusters => {
+            while picked.len() < count {
+                // Grow a clump from a random free spot.
+                let free: Vec<_> = all.iter().filter(|c| !picked.contains(c)).collect();
+                let mut cell = **free.choose(rng).unwrap();
+                for _ in 0..rng.gen_range(3, 8) {
+                    if picked.len() >= count {
+                        break;
+                    }
+                    if !picked.contains(&cell) {
+                        picked.push(cell);
+                    }
+                    cell = step(rng, grid, cell);
+                }
+            }
+        }
+    }
+
+    // Top up with random spots, which is all that scatter does.
+    let mut free: Vec<_> = all.into_iter().filter(|c| !picked.contains(c)).collect();
+    free.shuffle(rng);
+    picked.extend(free.into_iter().take(count - picked.len()));
+    picked.sort_by_key(|cell| (cell.column, cell.row));
+    picked
+}
+
+/// Moves to a random neighbouring spot, staying inside the grid.
+fn step(rng: &mut ChaCha8Rng, grid: &Grid, cell: Cell) -> Cell {
+    let mut next = cell;
+    match rng.gen_range(0, 4) {
+        0 if cell.column + 1 < grid.columns => next.column += 1,
+        1 if cell.column > 0 => next.column -= 1,
+        2 if cell.row + 1 < ROWS => next.row += 1,
+        3 if cell.row > 0 => next.row -= 1,
+        _ => {}
+    }
+    next
+}
+
+fn pick_spec(rng: &mut ChaCha8Rng) -> Spec {
+    let kind = match rng.gen_range(0, 100) {
+        0..=4 => BrickKind::Steel,
+        5..=9 => BrickKind::Explosive,
+        10..=19 => BrickKind::Regen,
+        _ => BrickKind::Normal,
+    };
+    let hp = match (kind, rng.gen_range(0, 10)) {
+        (BrickKind::Steel | BrickKind::Explosive, _) => 1,
+        (_, 0..=5) => 1,
+        (_, 6..=8) => 2,
+        _ => 3,
+    };
+    Spec { kind, hp }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::level::Level;
+
+    fn classic() -> Level {
+        ron::from_str(include_str!("../assets/levels/classic.ron")).unwrap()
+    }
+
+    fn layout(symmetry: Symmetry) -> Layout {
+        Layout {
+            seed: 42,
+            symmetry,
+            pattern: Pattern::Scatter,
+            density: 60,
+        }
+    }
+
+    /// The center, kind and hp of every brick on one half.
+    fn half(bricks: &[BrickGroup], team: Team) -> Vec<(Vec2, BrickKind, u32)> {
+        bricks
+            .iter()
+            .filter_map(|brick| match *brick {
+                BrickGroup::Brick {
+                    center, kind, hp, ..
+                } => Some((center, kind, hp)),
+                _ => None,
+            })
+            .filter(|(center, _, _)| (center.x < 0.0) == (team == Team::Left))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_same_bricks() {
+        let bricks = generate(&classic(), &layout(Symmetry::Mirror));
+        let left = half(&bricks, Team::Left);
+        assert_eq!(left.len(), 30);
+        // Every client has to get these exact bricks from the seed.
+        assert_eq!(
+            left[..5],
+            [
+                (Vec2::new(-500.0, -486.0), BrickKind::Normal, 2),
+                (Vec2::new(-500.0, -378.0), BrickKind::Normal, 1),
+                (Vec2::new(-500.0, -270.0), BrickKind::Explosive, 1),
+                (Vec2::new(-500.0, -54.0), BrickKind::Normal, 1),
+                (Vec2::new(-500.0, 54.0), BrickKind::Normal, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn mirror_symmetry() {
+        let bricks = generate(&classic(), &layout(Symmetry::Mirror));
+        let mirrored: Vec<_> = half(&bricks, Team::Left)
+            .into_iter()
+            .map(|(center, kind, hp)| (Vec2::new(-center.x, center.y), kind, hp))
+            .collect();
+        assert_eq!(mirrored, half(&bricks, Team::Right));
+    }
+
+    #[test]
+    fn rotational_symmetry() {
+        let bricks = generate(&classic(), &layout(Symmetry::Rotational));
+        let mut rotated: Vec<_> = half(&bricks, Team::Left)
+            .into_iter()
+            .map(|(center, kind, hp)| (-center, kind, hp))
+            .collect();
+        let mut right = half(&bricks, Team::Right);
+        for bricks in [&mut rotated, &mut right] {
+            bricks.sort_by(|(a, _, _), (b, _, _)| (a.x, a.y).partial_cmp(&(b.x, b.y)).unwrap());
+        }
+        assert_eq!(rotated, right);
+    }
+}
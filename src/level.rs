@@ -0,0 +1,391 @@
+//! The levels, which describe the arena that a match is played in.
+//!
+//! Levels are RON files in `assets/levels`.
+use crate::game::{BrickDef, BrickKind, Team};
+use crate::generator::{generate, Layout};
+use crate::messages::StartGame;
+use crate::GameState;
+use bevy::app::AppExit;
+use bevy::asset::{AssetLoader, FileAssetIo, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::render::camera::Camera2d;
+use bevy::utils::BoxedFuture;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The level that is played when none is picked.
+pub const DEFAULT_LEVEL: &str = "classic";
+
+pub struct LevelPlugin {
+    /// Whether the game is running without a window or audio.
+    pub headless: bool,
+}
+
+impl Plugin for LevelPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Levels>()
+            .init_resource::<CurrentLevel>();
+
+        // There is no asset server when headless, so the files are read directly.
+        if self.headless {
+            app.add_startup_system(read_levels);
+        } else {
+            app.add_asset::<Level>()
+                .init_asset_loader::<LevelLoader>()
+                .add_startup_system(load_levels)
+                .add_system(collect_levels)
+                .add_system_set(SystemSet::on_enter(GameState::Game).with_system(fit_camera));
+        }
+    }
+}
+
+/// The description of an arena.
+#[derive(Deserialize, TypeUuid, Clone, PartialEq, Debug)]
+#[uuid = "1053bc88-ebe2-4720-8cc0-6a73a8e8f8ae"]
+pub struct Level {
+    /// The id that the server sends to the clients, so that they load the same level.
+    pub id: String,
+    pub name: String,
+    /// The width and height of the arena.
+    pub size: Vec2,
+    pub targets: Vec<TargetDef>,
+    pub paddles: Vec<PaddleDef>,
+    #[serde(default)]
+    pub bricks: Vec<BrickGroup>,
+    /// Walls that the ball bounces off of.
+    #[serde(default)]
+    pub obstacles: Vec<ObstacleDef>,
+    /// The layout that the bricks were generated from, if they were.
+    #[serde(skip)]
+    pub layout: Option<Layout>,
+}
+
+#[derive(Deserialize, Copy, Clone, PartialEq, Debug)]
+pub struct TargetDef {
+    pub team: Team,
+    pub center: Vec2,
+    /// The diameter of the target.
+    pub size: f32,
+}
+
+#[derive(Deserialize, Copy, Clone, PartialEq, Debug)]
+pub struct PaddleDef {
+    pub team: Team,
+    /// Where the paddle starts.
+    pub start: Vec2,
+}
+
+#[derive(Deserialize, Copy, Clone, PartialEq, Debug)]
+pub struct ObstacleDef {
+    pub center: Vec2,
+    pub size: Vec2,
+}
+
+/// One or more bricks.
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub enum BrickGroup {
+    /// A single brick.
+    Brick {
+        center: Vec2,
+        width: f32,
+        height: f32,
+        /// The red, green and blue of the brick.
+        color: (u8, u8, u8),
+        #[serde(default)]
+        kind: BrickKind,
+        /// How many hits it takes to break the brick.
+        #[serde(default = "one")]
+        hp: u32,
+    },
+    /// A grid of bricks, with one color per column.
+    Grid {
+        /// The center of the first brick.
+        origin: Vec2,
+        /// The offset between the columns (x) and rows (y).
+        step: Vec2,
+        rows: u32,
+        columns: Vec<(u8, u8, u8)>,
+        width: f32,
+        height: f32,
+        #[serde(default)]
+        kind: BrickKind,
+        /// How many hits it takes to break each brick.
+        #[serde(default = "one")]
+        hp: u32,
+    },
+}
+
+fn one() -> u32 {
+    1
+}
+
+impl Level {
+    /// Gets the bricks of the level.
+    ///
+    /// The ids are given out in order, so they are the same for everyone with the same level.
+    pub fn brick_defs(&self) -> Vec<BrickDef> {
+        let mut bricks = vec![];
+        for group in &self.bricks {
+            match group {
+                BrickGroup::Brick {
+                    center,
+                    width,
+                    height,
+                    color: (r, g, b),
+                    kind,
+                    hp,
+                } => bricks.push(BrickDef {
+                    id: bricks.len() as u32,
+                    kind: *kind,
+                    hp: *hp,
+                    color: Color::rgb_u8(*r, *g, *b),
+                    center: *center,
+                    width: *width,
+                    height: *height,
+                }),
+                BrickGroup::Grid {
+                    origin,
+                    step,
+                    rows,
+                    columns,
+                    width,
+                    height,
+                    kind,
+                    hp,
+                } => {
+                    for (c, (r, g, b)) in columns.iter().enumerate() {
+                        for row in 0..*rows {
+                            bricks.push(BrickDef {
+                                id: bricks.len() as u32,
+                                kind: *kind,
+                                hp: *hp,
+                                color: Color::rgb_u8(*r, *g, *b),
+                                center: *origin + *step * Vec2::new(c as f32, row as f32),
+                                width: *width,
+                                height: *height,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        bricks
+    }
+
+    /// Replaces the bricks with ones generated from the `layout`, if there is one.
+    pub fn with_layout(mut self, layout: Option<Layout>) -> Self {
+        if let Some(layout) = layout {
+            self.bricks = generate(&self, &layout);
+            self.layout = Some(layout);
+        }
+        self
+    }
+
+    /// Gets the message that starts a game on this level.
+    pub fn start_msg(&self) -> StartGame {
+        StartGame {
+            level: self.id.clone(),
+            layout: self.layout,
+        }
+    }
+
+    /// Gets the target of `team`.
+    pub fn target(&self, team: Team) -> TargetDef {
+        self.targets
+            .iter()
+            .find(|t| t.team == team)
+            .copied()
+            .unwrap_or_else(|| default_target(team, self.size))
+    }
+
+    /// Gets where the paddle of `team` starts.
+    pub fn paddle_start(&self, team: Team) -> Vec2 {
+        self.paddles
+            .iter()
+            .find(|p| p.team == team)
+            .map(|p| p.start)
+            .unwrap_or_else(|| Vec2::new(side(team) * 350.0, 0.0))
+    }
+}
+
+/// The arena without any bricks, for when no level could be loaded.
+impl Default for Level {
+    fn default() -> Self {
+        let size = Vec2::new(1920.0, 1080.0);
+        Level {
+            id: "empty".to_owned(),
+            name: "Empty".to_owned(),
+            size,
+            targets: vec![
+                default_target(Team::Left, size),
+                default_target(Team::Right, size),
+            ],
+            paddles: vec![],
+            bricks: vec![],
+            obstacles: vec![],
+            layout: None,
+        }
+    }
+}
+
+/// Gets the sign of the x coordinates on the side of `team`.
+fn side(team: Team) -> f32 {
+    match team {
+        Team::Left => -1.0,
+        Team::Right => 1.0,
+    }
+}
+
+fn default_target(team: Team, size: Vec2) -> TargetDef {
+    TargetDef {
+        team,
+        center: Vec2::new(side(team) * (size.x / 2.0 - 62.5), 0.0),
+        size: 125.0,
+    }
+}
+
+/// Loads [`Level`]s from RON files.
+#[derive(Default)]
+pub struct LevelLoader;
+
+impl AssetLoader for LevelLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let level: Level = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(level));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ron"]
+    }
+}
+
+/// The levels that can be played, sorted by id.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Levels(pub Vec<Level>);
+
+impl Levels {
+    /// Reads all the levels in `dir`, skipping the ones that can't be parsed.
+    pub fn read_dir(dir: &Path) -> io::Result<Self> {
+        let mut levels = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "ron") {
+                continue;
+            }
+            match ron::from_str(&fs::read_to_string(&path)?) {
+                Ok(level) => levels.push(level),
+                Err(e) => warn!("Failed to parse the level {}: {}", path.display(), e),
+            }
+        }
+        let mut levels = Levels(levels);
+        levels.sort();
+        Ok(levels)
+    }
+
+    fn sort(&mut self) {
+        self.0.sort_by(|a, b| a.id.cmp(&b.id));
+    }
+
+    /// Gets the level with the given `id`, or `None` if there is no such level.
+    pub fn get(&self, id: &str) -> Option<Level> {
+        let level = self.0.iter().find(|level| level.id == id).cloned();
+        if level.is_none() {
+            warn!("There is no level \"{}\"", id);
+        }
+        level
+    }
+
+    /// Gets the level that the server started the game on, or `None` if this client doesn't have
+    /// it.
+    pub fn start(&self, msg: &StartGame) -> Option<Level> {
+        Some(self.get(&msg.level)?.with_layout(msg.layout))
+    }
+
+    /// Gets the id of the level after the one with the given `id`, wrapping around.
+    pub fn next_id(&self, id: &str) -> Option<String> {
+        let i = self.0.iter().position(|level| level.id == id);
+        let next = i.map_or(0, |i| (i + 1) % self.0.len());
+        self.0.get(next).map(|level| level.id.clone())
+    }
+}
+
+/// The level that the server picked for the next match.
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub struct SelectedLevel(pub String);
+
+impl Default for SelectedLevel {
+    fn default() -> Self {
+        SelectedLevel(DEFAULT_LEVEL.to_owned())
+    }
+}
+
+/// The level of the current match.
+///
+/// This is set before entering [`GameState::Game`].
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct CurrentLevel(pub Level);
+
+/// Keeps the level files loaded.
+struct LevelHandles(#[allow(dead_code)] Vec<HandleUntyped>);
+
+fn load_levels(mut commands: Commands, assets: Res<AssetServer>) {
+    match assets.load_folder("levels") {
+        Ok(handles) => commands.insert_resource(LevelHandles(handles)),
+        Err(e) => warn!("Failed to load the levels: {}", e),
+    }
+}
+
+fn collect_levels(
+    mut events: EventReader<AssetEvent<Level>>,
+    assets: Res<Assets<Level>>,
+    mut levels: ResMut<Levels>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    levels.0 = assets.iter().map(|(_, level)| level.clone()).collect();
+    levels.sort();
+}
+
+fn read_levels(
+    mut levels: ResMut<Levels>,
+    selected: Res<SelectedLevel>,
+    mut exit: EventWriter<AppExit>,
+) {
+    // The same directory that the asset server loads from, so that it doesn't depend on the
+    // working directory.
+    let dir = FileAssetIo::get_root_path().join("assets/levels");
+    match Levels::read_dir(&dir) {
+        Ok(read) => *levels = read,
+        Err(e) => warn!("Failed to read the levels from {}: {}", dir.display(), e),
+    }
+    // Nobody can pick another level on the headless server.
+    if levels.get(&selected.0).is_none() {
+        error!("Can't host the level \"{}\"", selected.0);
+        exit.send(AppExit);
+    }
+}
+
+/// Makes the camera show the whole arena.
+fn fit_camera(
+    level: Res<CurrentLevel>,
+    mut q_camera: Query<&mut OrthographicProjection, With<Camera2d>>,
+) {
+    let half = level.0.size / 2.0;
+    for mut projection in q_camera.iter_mut() {
+        projection.left = -half.x;
+        projection.right = half.x;
+        projection.bottom = -half.y;
+        projection.top = half.y;
+    }
+}
@@ -0,0 +1,2 @@
+//! The parts of bong that are shared by the game and the `bong-registry` binary.
+pub mod registry;
@@ -1,13 +1,36 @@
+use crate::bot::{BotGame, BOT_CID};
+use crate::browser::Heartbeat;
 use crate::game::Team;
-use crate::messages::{ConnectionBroadcast, DisconnectBroadcast, RejectReason, StartGame};
+use crate::generator::LayoutSettings;
+use crate::level::{CurrentLevel, Level, Levels, SelectedLevel};
+use crate::messages::{
+    valid_name, ConnectionBroadcast, Disconnect, DisconnectBroadcast, DisconnectReason,
+    LobbyStatus, ProtocolVersion, RejectReason, SetReady, SideRequest, StartGame, SwapSides,
+};
 use crate::{Connection, GameIp, GameState, MultiplayerType, Name, Response};
 use bevy::prelude::PositionType::Absolute;
+use bevy::app::AppExit;
 use bevy::prelude::*;
-use carrier_pigeon::net::{CIdSpec, Config};
+use bevy::window::ReceivedCharacter;
+use carrier_pigeon::net::{Config, Status};
 use carrier_pigeon::{CId, Client, MsgTableParts, OptionPendingClient, Server};
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
 use std::f32::consts::PI;
+use std::io::ErrorKind;
+use std::time::Duration;
 
-pub struct LobbyPlugin;
+/// The characters of invite codes, without the ones that are easy to mix up, like 0 and O.
+const INVITE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+/// The length of an invite code.
+const INVITE_LEN: usize = 6;
+/// The max length of a typed password.
+const MAX_PASSWORD_LEN: usize = 32;
+
+pub struct LobbyPlugin {
+    /// Whether the game is running without a window or audio.
+    pub headless: bool,
+}
 
 #[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
 /// All lobby items have this so that they can be cleaned up easily.
@@ -24,29 +47,196 @@ enum Player {
     Two,
 }
 
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A marker for the text that lists the spectators.
+struct SpectatorLabel;
+
 #[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
 enum LobbyButton {
     Back,
     Start,
+    /// Cycles through the levels. Only the host can use it.
+    Level,
+    /// Sets a new invite code, or removes the password. Only the host has it.
+    Password,
+    /// Joins again with the typed password.
+    Join,
+    /// Toggles whether this player is ready.
+    Ready,
+    /// Asks to play on the other side.
+    Swap,
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A marker for the text that shows the selected level.
+struct LevelLabel;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A marker for the text that shows the lobby password to the host.
+struct PasswordLabel;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A marker for the row that asks the client for the password. Hidden unless the server asks.
+struct PasswordRow;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A marker for the text of the typed password.
+struct PasswordText;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A marker for the row with the ready and swap buttons. Hidden for spectators.
+struct PlayerButtons;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A marker for the text of the ready button.
+struct ReadyLabel;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A marker for the text of the swap button.
+struct SwapLabel;
+
+/// The reason that the client couldn't connect.
+struct ConnectFailure(String);
+
+/// The password that the client is typing, after the server asked for one.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+struct PasswordPrompt(String);
+
+/// The password or invite code that clients need to join, or `None` if anyone can.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct LobbyPassword(pub Option<String>);
+
+/// The password that this client joins with.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct JoinPassword(pub Option<String>);
+
+/// Generates a short invite code, to be used as the lobby password.
+pub fn invite_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..INVITE_LEN)
+        .map(|_| INVITE_CHARS[rng.gen_range(0, INVITE_CHARS.len())] as char)
+        .collect()
+}
+
+/// The names of players that are not allowed to join.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct Bans(pub HashSet<String>);
+
+impl Bans {
+    pub fn is_banned(&self, name: &str) -> bool {
+        self.0.contains(&name.to_lowercase())
+    }
+}
+
+/// The session tokens of the players, used to let them rejoin a game in progress.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct Sessions(pub HashMap<u64, Team>);
+
+/// The session token that the server gave this client.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct SessionToken(pub u64);
+
+/// The connection ids of the players in a local game.
+pub const LOCAL_CIDS: [CId; 2] = [CId::MAX - 2, CId::MAX - 1];
+
+/// Whether `cid` is a player on this machine, like the bot, instead of a connection.
+pub fn is_local(cid: CId) -> bool {
+    cid == BOT_CID || LOCAL_CIDS.contains(&cid)
+}
+
+/// The max number of spectators that the server accepts.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct SpectatorCap(pub usize);
+
+impl Default for SpectatorCap {
+    fn default() -> Self {
+        SpectatorCap(8)
+    }
+}
+
+/// How long after both players are ready the game starts, or `None` to wait for the host.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, Default)]
+pub struct AutoStart(pub Option<Duration>);
+
+/// Which players are ready, and who asked to swap sides.
+///
+/// Only used by the server.
+#[derive(Clone, Debug, Default)]
+struct ReadyCheck {
+    /// The connections whose player is ready.
+    ready: HashSet<CId>,
+    /// The connection whose player asked to swap sides.
+    swap: Option<CId>,
+    /// Counts down to the start, while both players are ready.
+    countdown: Option<Timer>,
+}
+
+impl ReadyCheck {
+    /// Whether the player with `cid` is ready. Players on this machine always are.
+    fn is_ready(&self, cid: CId) -> bool {
+        is_local(cid) || self.ready.contains(&cid)
+    }
+
+    /// Whether both player slots are taken by players that are ready.
+    fn all_ready(&self, players: &Players) -> bool {
+        [Team::Left, Team::Right].iter().all(|team| {
+            players
+                .get(*team)
+                .is_some_and(|(cid, _)| self.is_ready(*cid))
+        })
+    }
+
+    /// Gets the state of the lobby, to show to the players.
+    fn status(&self, players: &Players) -> LobbyStatus {
+        let ready = [Team::Left, Team::Right]
+            .into_iter()
+            .filter(|team| {
+                players
+                    .get(*team)
+                    .is_some_and(|(cid, _)| self.is_ready(*cid))
+            })
+            .collect();
+        let countdown = self.countdown.as_ref().map(|timer| {
+            (timer.duration() - timer.elapsed()).as_secs_f32().ceil() as u32
+        });
+        LobbyStatus {
+            ready,
+            swap: self.swap.and_then(|cid| players.team_of(cid)),
+            countdown,
+        }
+    }
 }
 
 #[derive(Clone, Eq, PartialEq, Debug, Default)]
 pub struct Players {
     pub p1: Option<(CId, String)>,
     pub p2: Option<(CId, String)>,
+    /// The connections that are only watching.
+    pub spectators: Vec<(CId, String)>,
     pub me: Option<Team>,
 }
 
 impl Players {
-    fn first(&self) -> Option<(CId, String)> {
-        if self.p1.is_some() {
-            self.p1.clone()
-        } else {
-            self.p2.clone()
-        }
+    /// Gets the connection broadcasts for everyone that is connected.
+    pub fn members(&self) -> Vec<ConnectionBroadcast> {
+        let p1 = self.p1.iter().map(|(cid, name)| (cid, name, Some(Team::Left)));
+        let p2 = self.p2.iter().map(|(cid, name)| (cid, name, Some(Team::Right)));
+        let spectators = self.spectators.iter().map(|(cid, name)| (cid, name, None));
+        p1.chain(p2)
+            .chain(spectators)
+            .map(|(cid, name, team)| ConnectionBroadcast::new(name.clone(), *cid, team))
+            .collect()
+    }
+
+    /// Gets the cids and names of everyone that is connected, including spectators.
+    pub fn everyone(&self) -> impl Iterator<Item = &(CId, String)> {
+        self.p1
+            .iter()
+            .chain(self.p2.iter())
+            .chain(self.spectators.iter())
     }
 
-    fn count(&self) -> usize {
+    pub fn count(&self) -> usize {
         match (&self.p1, &self.p2) {
             (None, None) => 0,
             (Some(_), None) => 1,
@@ -55,7 +245,40 @@ impl Players {
         }
     }
 
-    fn remove_cid(&mut self, cid: CId) -> bool {
+    /// Gets the team of the player with the given `cid`.
+    pub fn team_of(&self, cid: CId) -> Option<Team> {
+        match (&self.p1, &self.p2) {
+            (Some((c, _)), _) if *c == cid => Some(Team::Left),
+            (_, Some((c, _))) if *c == cid => Some(Team::Right),
+            _ => None,
+        }
+    }
+
+    /// Gets the player on the given team.
+    pub fn get(&self, team: Team) -> Option<&(CId, String)> {
+        match team {
+            Team::Left => self.p1.as_ref(),
+            Team::Right => self.p2.as_ref(),
+        }
+    }
+
+    /// Changes the cid of the player on `team`, for when they rejoin.
+    pub fn set_cid(&mut self, team: Team, cid: CId) {
+        let player = match team {
+            Team::Left => &mut self.p1,
+            Team::Right => &mut self.p2,
+        };
+        if let Some((c, _)) = player {
+            *c = cid;
+        }
+    }
+
+    pub fn remove_cid(&mut self, cid: CId) -> bool {
+        if let Some(i) = self.spectators.iter().position(|(c, _)| *c == cid) {
+            info!("Removing spectator {}", self.spectators[i].1);
+            self.spectators.remove(i);
+            return true;
+        }
         if let Some((c, s)) = &self.p1 {
             if *c == cid {
                 println!("Removing {}", s);
@@ -88,26 +311,76 @@ impl Players {
             (Some(_), Some(_)) => false,
         }
     }
+
+    /// Adds a spectator, if there are less than `cap` of them.
+    pub fn add_spectator(&mut self, cid: CId, name: String, cap: usize) -> bool {
+        if self.spectators.len() >= cap {
+            return false;
+        }
+        info!("Adding spectator {} with cid {}", name, cid);
+        self.spectators.push((cid, name));
+        true
+    }
+
+    /// Swaps the teams of the two players.
+    pub fn swap_sides(&mut self) {
+        std::mem::swap(&mut self.p1, &mut self.p2);
+        self.me = self.me.map(|team| team.other());
+    }
+
+    /// Adds someone with a known team, or a spectator if `team` is `None`.
+    pub fn insert(&mut self, cid: CId, name: String, team: Option<Team>) {
+        match team {
+            Some(Team::Left) => self.p1 = Some((cid, name)),
+            Some(Team::Right) => self.p2 = Some((cid, name)),
+            None => self.spectators.push((cid, name)),
+        }
+    }
 }
 
 impl Plugin for LobbyPlugin {
     fn build(&self, app: &mut App) {
         app.add_system_set(
-            SystemSet::on_enter(GameState::Lobby)
-                .with_system(setup_networking)
-                .with_system(setup_lobby_ui),
+            SystemSet::on_enter(GameState::Lobby).with_system(setup_networking),
         )
         .add_system_set(
             SystemSet::on_update(GameState::Lobby)
-                .with_system(handle_ui)
                 .with_system(game_start)
                 .with_system(connect_client)
                 .with_system(handle_connections)
                 .with_system(handle_disconnections)
-                .with_system(update_status)
-                .with_system(update_player_labels),
+                .with_system(handle_ready_check)
+                .with_system(follow_ready_check)
+                .with_system(auto_start),
+        )
+        .add_system_set(SystemSet::on_exit(GameState::Lobby).with_system(clean_up))
+        // Players can still leave while voting on a rematch.
+        .add_system_set(
+            SystemSet::on_update(GameState::GameOver).with_system(handle_disconnections),
         )
-        .add_system_set(SystemSet::on_exit(GameState::Lobby).with_system(clean_up));
+        .add_system_to_stage(CoreStage::Last, disconnect_on_exit);
+
+        if self.headless {
+            app.add_system_set(
+                SystemSet::on_update(GameState::Menu).with_system(return_to_lobby),
+            );
+        } else {
+            app.add_system_set(
+                SystemSet::on_enter(GameState::Lobby).with_system(setup_lobby_ui),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameState::Lobby)
+                    .with_system(handle_ui)
+                    .with_system(edit_password)
+                    .with_system(submit_password)
+                    .with_system(update_status)
+                    .with_system(update_player_labels)
+                    .with_system(update_level_label)
+                    .with_system(update_password_label)
+                    .with_system(update_password_prompt)
+                    .with_system(update_player_buttons),
+            );
+        }
     }
 }
 
@@ -117,57 +390,136 @@ fn setup_networking(
     mut commands: Commands,
     multiplayer_type: Res<MultiplayerType>,
     parts: Res<MsgTableParts>,
+    version: Res<ProtocolVersion>,
+    bot: Option<Res<BotGame>>,
+    lobby_password: Res<LobbyPassword>,
+    join_password: Res<JoinPassword>,
 ) {
+    let mut players = Players::default();
+    // The bot takes the second slot, so the host gets the first one.
+    if let Some(bot) = bot {
+        players.p2 = Some((BOT_CID, bot.name()));
+    }
+    commands.insert_resource(Sessions::default());
+    commands.insert_resource(ReadyCheck::default());
+    commands.insert_resource(LobbyStatus::default());
+    commands.remove_resource::<ConnectFailure>();
+    commands.remove_resource::<SessionToken>();
+    commands.remove_resource::<PasswordPrompt>();
+
     match *multiplayer_type {
         MultiplayerType::Server => {
             println!("server");
             commands.insert_resource(Server::new(ip.0, parts.clone(), Config::default()).unwrap());
+            // There is no lobby screen to show it on.
+            if let Some(password) = &lobby_password.0 {
+                info!("Players need the password {} to join", password);
+            }
         }
         MultiplayerType::Host => {
             println!("host");
             commands.insert_resource(Server::new(ip.0, parts.clone(), Config::default()).unwrap());
+            let connection =
+                Connection::new(name.0.clone(), *version).with_password(lobby_password.0.clone());
             commands.insert_resource(
-                Client::new(ip.0, parts.clone(), Config::default(), Connection::new(name.0.clone())).option(),
+                Client::new(ip.0, parts.clone(), Config::default(), connection).option(),
             );
         }
+        // Both players are on this machine, so there is nothing to connect to. The game systems
+        // run the match without a server.
+        MultiplayerType::Local => {
+            info!("local");
+            players.p1 = Some((LOCAL_CIDS[0], "Player 1".to_owned()));
+            players.p2 = Some((LOCAL_CIDS[1], "Player 2".to_owned()));
+        }
         MultiplayerType::Client => {
             println!("client");
+            let connection =
+                Connection::new(name.0.clone(), *version).with_password(join_password.0.clone());
             commands.insert_resource(
-                Client::new(ip.0, parts.clone(), Config::default(), Connection::new(name.0.clone())).option(),
+                Client::new(ip.0, parts.clone(), Config::default(), connection).option(),
             );
         }
     }
+    commands.insert_resource(players);
 }
 
 fn connect_client(
     pending: Option<ResMut<OptionPendingClient>>,
+    server: Option<Res<Server>>,
+    levels: Res<Levels>,
+    mut current: ResMut<CurrentLevel>,
     mut players: ResMut<Players>,
+    mut game_state: ResMut<State<GameState>>,
     mut commands: Commands,
 ) {
     if let Some(mut pending) = pending {
         if pending.done().unwrap() {
-            if let Ok((client, resp)) = pending.take::<Response>().unwrap() {
-                println!("Client Connected!");
-                if let Response::Accepted(_this_cid, optional_player) = resp {
-                    if let Some((p_cid, p)) = optional_player {
-                        players.add(p_cid, p);
-                        players.me = Some(Team::Right);
-                    } else {
-                        players.me = Some(Team::Left);
+            match pending.take::<Response>().unwrap() {
+                Ok((
+                    mut client,
+                    Response::Accepted {
+                        team,
+                        others,
+                        session,
+                        game,
+                        ..
+                    },
+                )) => {
+                    // Spectators can join after the game started.
+                    let level = match game.map(|game| (levels.start(&game), game.level)) {
+                        Some((None, id)) => {
+                            let _ = client.disconnect(&Disconnect::new(DisconnectReason::Left));
+                            commands.insert_resource(ConnectFailure(missing_level(&id)));
+                            commands.remove_resource::<OptionPendingClient>();
+                            return;
+                        }
+                        Some((level, _)) => level,
+                        None => None,
+                    };
+                    println!("Client Connected!");
+                    commands.insert_resource(SessionToken(session));
+                    // The host shares the `Players` with its server.
+                    if server.is_none() {
+                        for other in others {
+                            players.insert(other.cid, other.name, other.team);
+                        }
+                    }
+                    players.me = team;
+                    commands.insert_resource(client);
+                    if let Some(level) = level {
+                        current.0 = level;
+                        let _ = game_state.set(GameState::Game);
                     }
                 }
-                commands.insert_resource(client);
+                Ok((_client, Response::Rejected(reason))) => {
+                    println!("Client Rejected: {}", reason);
+                    if matches!(
+                        reason,
+                        RejectReason::PasswordRequired | RejectReason::WrongPassword
+                    ) {
+                        commands.insert_resource(PasswordPrompt::default());
+                    }
+                    commands.insert_resource(ConnectFailure(format!("Rejected: {}", reason)));
+                }
+                Err(e) => {
+                    println!("Client failed to connect: {}", e);
+                    commands.insert_resource(ConnectFailure(format!("Failed to connect: {}", e)));
+                }
             }
             commands.remove_resource::<OptionPendingClient>()
         }
     }
 }
 
-fn setup_lobby_ui(mut commands: Commands, assets: Res<AssetServer>) {
+fn setup_lobby_ui(
+    mut commands: Commands,
+    assets: Res<AssetServer>,
+    multiplayer_type: Res<MultiplayerType>,
+    bot: Option<Res<BotGame>>,
+) {
     println!("Setting up lobby");
 
-    commands.insert_resource(Players::default());
-
     let font = assets.load("FiraMono-Medium.ttf");
     let arrow = assets.load("arrow.png");
     let text_style = TextStyle {
@@ -297,6 +649,234 @@ fn setup_lobby_ui(mut commands: Commands, assets: Res<AssetServer>) {
                         .insert(Player::Two);
                 });
 
+            // Ready and swap, for the players that are connected
+            if matches!(*multiplayer_type, MultiplayerType::Host | MultiplayerType::Client) {
+                parent
+                    .spawn_bundle(NodeBundle {
+                        style: Style {
+                            margin: Rect::all(Val::Px(10.0)),
+                            flex_direction: FlexDirection::Row,
+                            align_items: AlignItems::Center,
+                            ..default()
+                        },
+                        color: Color::NONE.into(),
+                        ..default()
+                    })
+                    .insert(PlayerButtons)
+                    .with_children(|parent| {
+                        parent
+                            .spawn_bundle(ButtonBundle {
+                                color: UiColor(Color::WHITE),
+                                style: Style {
+                                    margin: Rect::all(Val::Px(10.0)),
+                                    padding: Rect::all(Val::Px(10.0)),
+                                    justify_content: JustifyContent::Center,
+                                    align_items: AlignItems::Center,
+                                    ..Default::default()
+                                },
+                                ..Default::default()
+                            })
+                            .insert(LobbyButton::Ready)
+                            .with_children(|parent| {
+                                parent
+                                    .spawn_bundle(TextBundle {
+                                        text: Text::with_section(
+                                            "",
+                                            TextStyle {
+                                                font_size: 40.0,
+                                                ..text_style.clone()
+                                            },
+                                            TextAlignment::default(),
+                                        ),
+                                        ..Default::default()
+                                    })
+                                    .insert(ReadyLabel);
+                            });
+
+                        parent
+                            .spawn_bundle(ButtonBundle {
+                                color: UiColor(Color::WHITE),
+                                style: Style {
+                                    margin: Rect::all(Val::Px(10.0)),
+                                    padding: Rect::all(Val::Px(10.0)),
+                                    justify_content: JustifyContent::Center,
+                                    align_items: AlignItems::Center,
+                                    ..Default::default()
+                                },
+                                ..Default::default()
+                            })
+                            .insert(LobbyButton::Swap)
+                            .with_children(|parent| {
+                                parent
+                                    .spawn_bundle(TextBundle {
+                                        text: Text::with_section(
+                                            "",
+                                            TextStyle {
+                                                font_size: 40.0,
+                                                ..text_style.clone()
+                                            },
+                                            TextAlignment::default(),
+                                        ),
+                                        ..Default::default()
+                                    })
+                                    .insert(SwapLabel);
+                            });
+                    });
+            }
+
+            // Spectators
+            parent
+                .spawn_bundle(TextBundle {
+                    style: Style {
+                        margin: Rect::all(Val::Px(10.0)),
+                        ..Default::default()
+                    },
+                    text: Text::with_section(
+                        "",
+                        TextStyle {
+                            font_size: 40.0,
+                            ..text_style.clone()
+                        },
+                        TextAlignment::default(),
+                    ),
+                    ..Default::default()
+                })
+                .insert(SpectatorLabel);
+
+            // Level
+            parent
+                .spawn_bundle(ButtonBundle {
+                    color: UiColor(Color::WHITE),
+                    style: Style {
+                        margin: Rect::all(Val::Px(10.0)),
+                        padding: Rect::all(Val::Px(10.0)),
+                        justify_content: JustifyContent::Center,
+                        align_items: AlignItems::Center,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .insert(LobbyButton::Level)
+                .with_children(|parent| {
+                    parent
+                        .spawn_bundle(TextBundle {
+                            text: Text::with_section(
+                                "",
+                                TextStyle {
+                                    font_size: 40.0,
+                                    ..text_style.clone()
+                                },
+                                TextAlignment::default(),
+                            ),
+                            ..Default::default()
+                        })
+                        .insert(LevelLabel);
+                });
+
+            // Password, for hosts that others can join
+            if *multiplayer_type == MultiplayerType::Host && bot.is_none() {
+                parent
+                    .spawn_bundle(ButtonBundle {
+                        color: UiColor(Color::WHITE),
+                        style: Style {
+                            margin: Rect::all(Val::Px(10.0)),
+                            padding: Rect::all(Val::Px(10.0)),
+                            justify_content: JustifyContent::Center,
+                            align_items: AlignItems::Center,
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    })
+                    .insert(LobbyButton::Password)
+                    .with_children(|parent| {
+                        parent
+                            .spawn_bundle(TextBundle {
+                                text: Text::with_section(
+                                    "",
+                                    TextStyle {
+                                        font_size: 40.0,
+                                        ..text_style.clone()
+                                    },
+                                    TextAlignment::default(),
+                                ),
+                                ..Default::default()
+                            })
+                            .insert(PasswordLabel);
+                    });
+            }
+
+            // Password prompt
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        margin: Rect::all(Val::Px(10.0)),
+                        flex_direction: FlexDirection::Row,
+                        align_items: AlignItems::Center,
+                        display: Display::None,
+                        ..default()
+                    },
+                    color: Color::NONE.into(),
+                    ..default()
+                })
+                .insert(PasswordRow)
+                .with_children(|parent| {
+                    parent
+                        .spawn_bundle(NodeBundle {
+                            style: Style {
+                                size: Size::new(Val::Px(560.0), Val::Auto),
+                                padding: Rect::all(Val::Px(10.0)),
+                                ..default()
+                            },
+                            color: Color::WHITE.into(),
+                            ..default()
+                        })
+                        .with_children(|parent| {
+                            parent
+                                .spawn_bundle(TextBundle {
+                                    text: Text::with_section(
+                                        "",
+                                        TextStyle {
+                                            font_size: 40.0,
+                                            ..text_style.clone()
+                                        },
+                                        TextAlignment::default(),
+                                    ),
+                                    ..Default::default()
+                                })
+                                .insert(PasswordText);
+                        });
+
+                    parent
+                        .spawn_bundle(ButtonBundle {
+                            color: UiColor(Color::WHITE),
+                            style: Style {
+                                margin: Rect {
+                                    left: Val::Px(10.0),
+                                    ..default()
+                                },
+                                padding: Rect::all(Val::Px(10.0)),
+                                justify_content: JustifyContent::Center,
+                                align_items: AlignItems::Center,
+                                ..Default::default()
+                            },
+                            ..Default::default()
+                        })
+                        .insert(LobbyButton::Join)
+                        .with_children(|parent| {
+                            parent.spawn_bundle(TextBundle {
+                                text: Text::with_section(
+                                    "Join",
+                                    TextStyle {
+                                        font_size: 40.0,
+                                        ..text_style.clone()
+                                    },
+                                    TextAlignment::default(),
+                                ),
+                                ..Default::default()
+                            });
+                        });
+                });
+
             // Start Arrow
             parent
                 .spawn_bundle(ButtonBundle {
@@ -319,10 +899,53 @@ fn setup_lobby_ui(mut commands: Commands, assets: Res<AssetServer>) {
         });
 }
 
-fn game_start(client: Option<Res<Client>>, mut game_state: ResMut<State<GameState>>) {
-    if let Some(client) = client {
-        if client.recv::<StartGame>().count() >= 1 {
-            let _ = game_state.set(GameState::Game);
+fn game_start(
+    client: Option<ResMut<Client>>,
+    levels: Res<Levels>,
+    mut current: ResMut<CurrentLevel>,
+    mut game_state: ResMut<State<GameState>>,
+    mut commands: Commands,
+) {
+    if let Some(mut client) = client {
+        let start = client.recv::<StartGame>().last().map(|msg| msg.m.clone());
+        if let Some(start) = start {
+            match levels.start(&start) {
+                Some(level) => {
+                    current.0 = level;
+                    let _ = game_state.set(GameState::Game);
+                }
+                None => {
+                    leave_for_missing_level(&mut client, &start.level, &mut commands);
+                    commands.insert_resource(ConnectFailure(missing_level(&start.level)));
+                }
+            }
+        }
+    }
+}
+
+/// Gets the reason shown for leaving a server whose level this client doesn't have.
+fn missing_level(id: &str) -> String {
+    format!("The server plays the level \"{}\", which isn't installed", id)
+}
+
+/// Leaves the server, as it started a level that this client doesn't have.
+pub fn leave_for_missing_level(client: &mut Client, id: &str, commands: &mut Commands) {
+    warn!("Leaving the server: it started the level \"{}\", which isn't installed", id);
+    let _ = client.disconnect(&Disconnect::new(DisconnectReason::Left));
+    commands.remove_resource::<Client>();
+}
+
+/// Starts the next match on the `level`, and tells everyone to start it.
+///
+/// A local game has no `server`, and nobody else to tell.
+pub fn start_game(server: Option<&Server>, level: Level, current: &mut CurrentLevel) {
+    if let Some(layout) = &level.layout {
+        info!("Generating the bricks from seed {}", layout.seed);
+    }
+    current.0 = level;
+    if let Some(server) = server {
+        if let Err(e) = server.broadcast(&current.0.start_msg()) {
+            warn!("Failed to broadcast the game start: {}", e);
         }
     }
 }
@@ -332,40 +955,58 @@ fn update_status(
     multiplayer_type: Res<MultiplayerType>,
     client: Option<Res<Client>>,
     server: Option<Res<Server>>,
+    failure: Option<Res<ConnectFailure>>,
+    players: Res<Players>,
+    lobby: Res<LobbyStatus>,
 ) {
-    let status = format!(
+    let mut status = format!(
         "Status: {}",
         match *multiplayer_type {
+            MultiplayerType::Local => "Local game".to_owned(),
             MultiplayerType::Client => {
-                match client {
-                    Some(client) if client.open() => "Client connected",
-                    _ => "Client not connected",
+                match (client, &failure) {
+                    (Some(client), _) if client.open() && players.me.is_none() => {
+                        "Client connected (spectating)".to_owned()
+                    }
+                    (Some(client), _) if client.open() => "Client connected".to_owned(),
+                    (Some(client), _) => disconnect_cause(&client)
+                        .unwrap_or_else(|| "Client not connected".to_owned()),
+                    (_, Some(failure)) => failure.0.clone(),
+                    _ => "Client not connected".to_owned(),
                 }
             }
             _ => {
                 if server.is_some() {
-                    "Server Listening"
+                    "Server Listening".to_owned()
                 } else {
-                    "No Server"
+                    "No Server".to_owned()
                 }
             }
         }
     );
+    if let Some(secs) = lobby.countdown {
+        status.push_str(&format!(" - Starting in {}", secs));
+    }
 
     for mut c_status in q_status.iter_mut() {
         c_status.sections[0].value = status.clone();
     }
 }
 
-fn update_player_labels(mut q_player_label: Query<(&mut Text, &Player)>, players: Res<Players>) {
-    let p1_txt = match &players.p1 {
-        None => "Player 1".to_owned(),
-        Some(p) => p.1.clone(),
-    };
-    let p2_txt = match &players.p2 {
-        None => "Player 2".to_owned(),
-        Some(p) => p.1.clone(),
+fn update_player_labels(
+    mut q_player_label: Query<(&mut Text, &Player)>,
+    mut q_spectator_label: Query<&mut Text, (With<SpectatorLabel>, Without<Player>)>,
+    players: Res<Players>,
+    lobby: Res<LobbyStatus>,
+) {
+    let label = |team: Team, empty: &str| match players.get(team) {
+        None => empty.to_owned(),
+        Some((_, name)) if lobby.swap == Some(team) => format!("{} (swap?)", name),
+        Some((_, name)) if lobby.ready.contains(&team) => format!("{} (ready)", name),
+        Some((_, name)) => name.clone(),
     };
+    let p1_txt = label(Team::Left, "Player 1");
+    let p2_txt = label(Team::Right, "Player 2");
 
     for (mut text, player) in q_player_label.iter_mut() {
         match player {
@@ -373,58 +1014,476 @@ fn update_player_labels(mut q_player_label: Query<(&mut Text, &Player)>, players
             Player::Two => text.sections[0].value = p2_txt.clone(),
         }
     }
+
+    let spectators_txt = if players.spectators.is_empty() {
+        String::new()
+    } else {
+        let names: Vec<_> = players.spectators.iter().map(|(_, name)| name.as_str()).collect();
+        format!("Spectators: {}", names.join(", "))
+    };
+    for mut text in q_spectator_label.iter_mut() {
+        text.sections[0].value = spectators_txt.clone();
+    }
+}
+
+/// Shows the selected level to the host.
+///
+/// The clients only find out the level once the game starts.
+fn update_level_label(
+    mut q_label: Query<&mut Text, With<LevelLabel>>,
+    server: Option<Res<Server>>,
+    selected: Res<SelectedLevel>,
+    levels: Res<Levels>,
+) {
+    let text = match server {
+        Some(_) => {
+            let level = levels.0.iter().find(|level| level.id == selected.0);
+            format!("Level: {}", level.map_or(&selected.0, |level| &level.name))
+        }
+        None => "Level: picked by the host".to_owned(),
+    };
+    for mut label in q_label.iter_mut() {
+        label.sections[0].value = text.clone();
+    }
+}
+
+/// Shows the lobby password to the host, so that they can share it.
+fn update_password_label(
+    mut q_label: Query<&mut Text, With<PasswordLabel>>,
+    password: Res<LobbyPassword>,
+) {
+    let text = match &password.0 {
+        Some(password) => format!("Password: {}", password),
+        None => "No password".to_owned(),
+    };
+    for mut label in q_label.iter_mut() {
+        label.sections[0].value = text.clone();
+    }
+}
+
+/// Shows the password row while the server asks for a password, with the typed one hidden.
+fn update_password_prompt(
+    mut q_row: Query<&mut Style, With<PasswordRow>>,
+    mut q_text: Query<&mut Text, With<PasswordText>>,
+    prompt: Option<Res<PasswordPrompt>>,
+) {
+    let display = if prompt.is_some() {
+        Display::Flex
+    } else {
+        Display::None
+    };
+    for mut style in q_row.iter_mut() {
+        style.display = display;
+    }
+    let text = match &prompt {
+        Some(prompt) => format!("Password: {}", "*".repeat(prompt.0.chars().count())),
+        None => String::new(),
+    };
+    for mut field in q_text.iter_mut() {
+        field.sections[0].value = text.clone();
+    }
+}
+
+/// Applies typed characters and backspace to the password.
+fn edit_password(
+    mut chars: EventReader<ReceivedCharacter>,
+    keys: Res<Input<KeyCode>>,
+    prompt: Option<ResMut<PasswordPrompt>>,
+) {
+    let mut prompt = match prompt {
+        Some(prompt) => prompt,
+        None => {
+            chars.iter().for_each(drop);
+            return;
+        }
+    };
+    for c in chars.iter() {
+        // Control characters (backspace, enter) are handled through `keys`.
+        if !c.char.is_control() && prompt.0.chars().count() < MAX_PASSWORD_LEN {
+            prompt.0.push(c.char);
+        }
+    }
+    if keys.just_pressed(KeyCode::Back) {
+        prompt.0.pop();
+    }
+}
+
+/// Connects again with the typed password when the client presses join or enter.
+fn submit_password(
+    q_interaction: Query<(&Interaction, &LobbyButton), Changed<Interaction>>,
+    keys: Res<Input<KeyCode>>,
+    prompt: Option<Res<PasswordPrompt>>,
+    pending: Option<Res<OptionPendingClient>>,
+    ip: Res<GameIp>,
+    name: Res<Name>,
+    parts: Res<MsgTableParts>,
+    version: Res<ProtocolVersion>,
+    mut join_password: ResMut<JoinPassword>,
+    mut commands: Commands,
+) {
+    let prompt = match prompt {
+        Some(prompt) if pending.is_none() => prompt,
+        _ => return,
+    };
+    let clicked = q_interaction.iter().any(|(interaction, button)| {
+        *interaction == Interaction::Clicked && *button == LobbyButton::Join
+    });
+    if !clicked && !keys.just_pressed(KeyCode::Return) {
+        return;
+    }
+
+    join_password.0 = Some(prompt.0.clone());
+    commands.remove_resource::<PasswordPrompt>();
+    commands.remove_resource::<ConnectFailure>();
+    let connection =
+        Connection::new(name.0.clone(), *version).with_password(join_password.0.clone());
+    commands.insert_resource(
+        Client::new(ip.0, parts.clone(), Config::default(), connection).option(),
+    );
 }
 
 fn handle_ui(
     q_interaction: Query<(&Interaction, &LobbyButton), Changed<Interaction>>,
     mut game_state: ResMut<State<GameState>>,
     players: Res<Players>,
+    levels: Res<Levels>,
+    layout: Res<LayoutSettings>,
+    mut selected: ResMut<SelectedLevel>,
+    mut current: ResMut<CurrentLevel>,
+    mut password: ResMut<LobbyPassword>,
+    check: Res<ReadyCheck>,
+    lobby: Res<LobbyStatus>,
+    multiplayer_type: Res<MultiplayerType>,
     mut server: Option<ResMut<Server>>,
+    mut client: Option<ResMut<Client>>,
+    mut commands: Commands,
 ) {
+    // A local game has no server, but picks the level and starts like one.
+    let hosting = server.is_some() || *multiplayer_type == MultiplayerType::Local;
     for (interaction, button) in q_interaction.iter() {
         if *interaction == Interaction::Clicked {
             match button {
                 LobbyButton::Back => {
+                    if let Some(server) = &mut server {
+                        disconnect_players(server, &players, DisconnectReason::ServerClosed);
+                    }
+                    if let Some(client) = &mut client {
+                        let _ = client.disconnect(&Disconnect::new(DisconnectReason::Left));
+                    }
+                    commands.remove_resource::<Client>();
+                    commands.remove_resource::<Server>();
                     let _ = game_state.set(GameState::Menu);
                 }
                 LobbyButton::Start => {
-                    if let Some(server) = &mut server {
-                        if players.count() == 2 {
+                    if hosting && check.all_ready(&players) {
+                        if let Some(level) = levels.get(&selected.0) {
+                            let level = level.with_layout(layout.pick());
+                            start_game(server.as_deref(), level, &mut current);
                             let _ = game_state.set(GameState::Game);
-                            server.send_spec(CIdSpec::All, &StartGame).unwrap();
                         }
                     }
                 }
+                LobbyButton::Level => {
+                    if hosting {
+                        if let Some(next) = levels.next_id(&selected.0) {
+                            selected.0 = next;
+                        }
+                    }
+                }
+                LobbyButton::Password => {
+                    if server.is_some() {
+                        password.0 = match password.0 {
+                            Some(_) => None,
+                            None => Some(invite_code()),
+                        };
+                    }
+                }
+                // Handled by `submit_password`.
+                LobbyButton::Join => {}
+                LobbyButton::Ready => {
+                    if let (Some(client), Some(me)) = (&client, players.me) {
+                        let ready = !lobby.ready.contains(&me);
+                        if let Err(e) = client.send(&SetReady(ready)) {
+                            warn!("Failed to send the ready flag: {}", e);
+                        }
+                    }
+                }
+                LobbyButton::Swap => {
+                    if let (Some(client), Some(me)) = (&client, players.me) {
+                        if let Err(e) = client.send(&SideRequest(me.other())) {
+                            warn!("Failed to send the side request: {}", e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Shows the ready and swap buttons to the players, with what they do.
+fn update_player_buttons(
+    mut q_row: Query<&mut Style, With<PlayerButtons>>,
+    mut q_ready: Query<&mut Text, (With<ReadyLabel>, Without<SwapLabel>)>,
+    mut q_swap: Query<&mut Text, (With<SwapLabel>, Without<ReadyLabel>)>,
+    players: Res<Players>,
+    lobby: Res<LobbyStatus>,
+) {
+    let me = match players.me {
+        Some(me) => me,
+        None => {
+            for mut style in q_row.iter_mut() {
+                style.display = Display::None;
+            }
+            return;
+        }
+    };
+    for mut style in q_row.iter_mut() {
+        style.display = Display::Flex;
+    }
+
+    let ready_txt = if lobby.ready.contains(&me) {
+        "Not ready"
+    } else {
+        "Ready"
+    };
+    let swap_txt = match lobby.swap {
+        Some(team) if team == me => "Swap requested",
+        Some(_) => "Accept swap",
+        None => "Swap sides",
+    };
+    for mut text in q_ready.iter_mut() {
+        text.sections[0].value = ready_txt.to_owned();
+    }
+    for mut text in q_swap.iter_mut() {
+        text.sections[0].value = swap_txt.to_owned();
+    }
+}
+
+/// Handles the ready flags and side requests of the players, and tells everyone the lobby status.
+fn handle_ready_check(
+    server: Option<Res<Server>>,
+    auto: Res<AutoStart>,
+    mut players: ResMut<Players>,
+    mut sessions: ResMut<Sessions>,
+    mut check: ResMut<ReadyCheck>,
+    mut status: ResMut<LobbyStatus>,
+) {
+    let server = match server {
+        Some(server) => server,
+        None => return,
+    };
+
+    // Joining and leaving doesn't always change the status, but the new player needs it.
+    let mut changed = players.is_changed();
+    check.ready.retain(|cid| players.team_of(*cid).is_some());
+    if check.swap.is_some_and(|cid| players.team_of(cid).is_none()) {
+        check.swap = None;
+    }
+
+    for msg in server.recv::<SetReady>() {
+        // Spectators can't hold up the game.
+        if players.team_of(msg.cid).is_some() {
+            if msg.m.0 {
+                check.ready.insert(msg.cid);
+            } else {
+                check.ready.remove(&msg.cid);
             }
         }
     }
+
+    for msg in server.recv::<SideRequest>() {
+        let team = match players.team_of(msg.cid) {
+            Some(team) if team != msg.m.0 => team,
+            _ => continue,
+        };
+        // The bot doesn't mind, and an empty side can just be taken.
+        let agreed = match players.get(team.other()) {
+            None => true,
+            Some((other, _)) => is_local(*other) || check.swap == Some(*other),
+        };
+        if agreed {
+            info!("Swapping sides");
+            swap_teams(Some(&server), &mut players, &mut sessions);
+            check.swap = None;
+            // The players have to agree on the new sides.
+            check.ready.clear();
+            changed = true;
+        } else {
+            check.swap = Some(msg.cid);
+        }
+    }
+
+    if check.all_ready(&players) {
+        if check.countdown.is_none() {
+            check.countdown = auto.0.map(|duration| Timer::new(duration, false));
+        }
+    } else {
+        check.countdown = None;
+    }
+
+    let new_status = check.status(&players);
+    if changed || new_status != *status {
+        if let Err(e) = server.broadcast(&new_status) {
+            warn!("Failed to broadcast the lobby status: {}", e);
+        }
+        *status = new_status;
+    }
+}
+
+/// Follows the lobby status and side swaps on the clients.
+fn follow_ready_check(
+    client: Option<Res<Client>>,
+    server: Option<Res<Server>>,
+    mut players: ResMut<Players>,
+    mut status: ResMut<LobbyStatus>,
+) {
+    // The host shares the `Players` and the status with its server.
+    let client = match client {
+        Some(client) if server.is_none() => client,
+        _ => return,
+    };
+
+    if client.recv::<SwapSides>().count() >= 1 {
+        players.swap_sides();
+    }
+    if let Some(msg) = client.recv::<LobbyStatus>().last() {
+        *status = msg.m.clone();
+    }
+}
+
+/// Swaps the sides of the players, and tells everyone.
+pub fn swap_teams(server: Option<&Server>, players: &mut Players, sessions: &mut Sessions) {
+    players.swap_sides();
+    for team in sessions.0.values_mut() {
+        *team = team.other();
+    }
+    if let Some(server) = server {
+        if let Err(e) = server.broadcast(&SwapSides) {
+            warn!("Failed to broadcast the side swap: {}", e);
+        }
+    }
+}
+
+/// Starts the game once the countdown is over.
+///
+/// Without a countdown the host has to press start, except on the headless server.
+fn auto_start(
+    time: Res<Time>,
+    selected: Res<SelectedLevel>,
+    levels: Res<Levels>,
+    layout: Res<LayoutSettings>,
+    mut check: ResMut<ReadyCheck>,
+    mut current: ResMut<CurrentLevel>,
+    server: Option<Res<Server>>,
+    mut game_state: ResMut<State<GameState>>,
+) {
+    let (server, countdown) = match (server, &mut check.countdown) {
+        (Some(server), Some(countdown)) => (server, countdown),
+        _ => return,
+    };
+    if countdown.tick(time.delta()).finished() {
+        match levels.get(&selected.0) {
+            Some(level) => {
+                info!("Both players are ready. Starting the game.");
+                start_game(Some(&server), level.with_layout(layout.pick()), &mut current);
+                let _ = game_state.set(GameState::Game);
+            }
+            // Count down again, which gives the host time to pick another level.
+            None => countdown.reset(),
+        }
+    }
+}
+
+/// Goes back to the lobby after a game, so that the headless server can host another one.
+fn return_to_lobby(mut game_state: ResMut<State<GameState>>) {
+    let _ = game_state.set(GameState::Lobby);
+}
+
+/// Checks whether a client may join at all, regardless of the state of the lobby.
+///
+/// `password` is the one that the client needs, if any.
+pub fn check_connection(
+    c: &Connection,
+    version: ProtocolVersion,
+    bans: &Bans,
+    password: Option<&str>,
+) -> Result<(), RejectReason> {
+    if c.version != version.0 {
+        return Err(RejectReason::VersionMismatch {
+            server: version.0,
+            client: c.version,
+        });
+    }
+    if !valid_name(&c.name) {
+        return Err(RejectReason::BadName);
+    }
+    if bans.is_banned(&c.name) {
+        return Err(RejectReason::Banned);
+    }
+    match (password, c.password.as_deref()) {
+        (Some(_), None) => return Err(RejectReason::PasswordRequired),
+        (Some(password), Some(sent)) if password != sent => {
+            return Err(RejectReason::WrongPassword)
+        }
+        _ => {}
+    }
+    Ok(())
 }
 
 fn handle_connections(
     server: Option<ResMut<Server>>,
     client: Option<Res<Client>>,
     mut players: ResMut<Players>,
+    mut sessions: ResMut<Sessions>,
+    version: Res<ProtocolVersion>,
+    bans: Res<Bans>,
+    password: Res<LobbyPassword>,
+    spectator_cap: Res<SpectatorCap>,
 ) {
     if let Some(mut server) = server {
         let mut broadcasts = vec![];
         server.handle_new_cons(|cid, c: Connection| {
-            let existing_player = players.first();
-            if players.add(cid, c.name.clone()) {
+            if let Err(reason) = check_connection(&c, *version, &bans, password.0.as_deref()) {
+                println!("Rejecting new Player: {}", reason);
+                return (false, Response::Rejected(reason));
+            }
+            let others = players.members();
+            let team = if players.add(cid, c.name.clone()) {
                 println!("Adding new Player");
-                broadcasts.push(ConnectionBroadcast::new(c.name, cid));
-                (true, Response::Accepted(cid, existing_player))
+                players.team_of(cid)
+            } else if players.add_spectator(cid, c.name.clone(), spectator_cap.0) {
+                info!("Adding new Spectator");
+                None
             } else {
                 println!("Rejecting new Player");
-                (false, Response::Rejected(RejectReason::MaxPlayersReached))
+                return (false, Response::Rejected(RejectReason::MaxPlayersReached));
+            };
+
+            let session = rand::random();
+            if let Some(team) = team {
+                sessions.0.insert(session, team);
             }
+            broadcasts.push(ConnectionBroadcast::new(c.name, cid, team));
+            (
+                true,
+                Response::Accepted {
+                    cid,
+                    team,
+                    others,
+                    session,
+                    game: None,
+                },
+            )
         });
         for bm in broadcasts {
             println!("Broadcasting");
-            server.broadcast(&bm).unwrap();
+            if let Err(e) = server.broadcast(&bm) {
+                warn!("Failed to broadcast: {}", e);
+            }
         }
     } else if let Some(client) = client {
         for msg in client.recv::<ConnectionBroadcast>() {
-            players.add(msg.cid, msg.name.clone());
+            players.insert(msg.cid, msg.name.clone(), msg.team);
         }
     }
 }
@@ -433,16 +1492,22 @@ fn handle_disconnections(
     server: Option<ResMut<Server>>,
     client: Option<Res<Client>>,
     mut players: ResMut<Players>,
+    mut sessions: ResMut<Sessions>,
 ) {
     if let Some(mut server) = server {
         let mut broadcasts = vec![];
         server.handle_disconnects(&mut |cid, _status| {
             broadcasts.push(DisconnectBroadcast { cid });
+            if let Some(team) = players.team_of(cid) {
+                sessions.0.retain(|_, t| *t != team);
+            }
             players.remove_cid(cid);
         });
         for bm in broadcasts {
             println!("Broadcasting");
-            server.broadcast(&bm).unwrap();
+            if let Err(e) = server.broadcast(&bm) {
+                warn!("Failed to broadcast: {}", e);
+            }
         }
     } else if let Some(client) = client {
         for msg in client.recv::<DisconnectBroadcast>() {
@@ -452,6 +1517,54 @@ fn handle_disconnections(
     }
 }
 
+/// Describes why the `client` is no longer connected, or `None` if it still is.
+pub fn disconnect_cause(client: &Client) -> Option<String> {
+    match client.status() {
+        Status::Connected => None,
+        Status::Disconnected(discon) => Some(match discon.downcast_ref::<Disconnect>() {
+            Some(discon) => discon.reason.to_string(),
+            None => "Disconnected".to_owned(),
+        }),
+        Status::Dropped(e) if e.kind() == ErrorKind::TimedOut => {
+            Some("Connection timed out".to_owned())
+        }
+        Status::Dropped(e) => Some(format!("Connection lost: {}", e)),
+        Status::Closed => Some("Server closed".to_owned()),
+    }
+}
+
+/// Gracefully disconnects all players, so that they know why the connection closed.
+pub fn disconnect_players(server: &mut Server, players: &Players, reason: DisconnectReason) {
+    let discon = Disconnect::new(reason);
+    for (cid, name) in players.everyone().filter(|(cid, _)| !is_local(*cid)) {
+        if let Err(e) = server.disconnect(&discon, *cid) {
+            warn!("Failed to disconnect {} ({}): {}", name, cid, e);
+        }
+    }
+}
+
+/// Gracefully closes the connections when the app exits.
+fn disconnect_on_exit(
+    mut exit: EventReader<AppExit>,
+    players: Option<Res<Players>>,
+    server: Option<ResMut<Server>>,
+    client: Option<ResMut<Client>>,
+    heartbeat: Option<Res<Heartbeat>>,
+) {
+    if exit.iter().next().is_none() {
+        return;
+    }
+    if let Some(heartbeat) = heartbeat {
+        heartbeat.deregister();
+    }
+    if let (Some(mut server), Some(players)) = (server, players) {
+        disconnect_players(&mut server, &players, DisconnectReason::ServerClosed);
+    }
+    if let Some(mut client) = client {
+        let _ = client.disconnect(&Disconnect::new(DisconnectReason::Left));
+    }
+}
+
 fn clean_up(mut commands: Commands, q_menu: Query<Entity, With<LobbyItem>>) {
     for e in q_menu.iter() {
         commands.entity(e).despawn_recursive();
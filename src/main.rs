@@ -1,18 +1,64 @@
+// Bevy systems take their resources and queries as arguments.
+#![allow(clippy::too_many_arguments, clippy::type_complexity)]
+
+mod bot;
+mod browser;
+mod chat;
+mod cli;
+mod config;
+mod controls;
+mod discovery;
 mod game;
+mod game_over;
+mod gamepad;
+mod generator;
+mod level;
 mod lobby;
 mod menu;
 mod messages;
+mod powerups;
+mod reconnect;
+mod rematch;
+mod rounds;
+mod schema;
+mod settings;
+mod snapshot;
 
-use crate::messages::{Connection, Disconnect, MyTransform, MyVelocity, Response};
+use crate::config::Settings;
+use crate::messages::{name_problem, Connection, Disconnect, MyTransform, MyVelocity, Response};
 use bevy::prelude::*;
+use bevy::app::ScheduleRunnerSettings;
+use bevy::hierarchy::HierarchyPlugin;
+use bevy::log::{LogPlugin, LogSettings};
 use bevy::render::camera::ScalingMode;
+use bevy::transform::TransformPlugin;
 use std::net::SocketAddr;
+use std::time::Duration;
 use bevy_pigeon::{AppExt, ClientPlugin, ServerPlugin};
 // use bevy_editor_pls::EditorPlugin;
+use crate::bot::{BotGame, BotPlugin};
+use crate::browser::{BrowserPlugin, Registry};
+use crate::chat::ChatPlugin;
+use crate::cli::{Cli, Command};
+use crate::controls::{Controls, ControlsPlugin};
+use crate::discovery::DiscoveryPlugin;
 use crate::game::GamePlugin;
-use crate::lobby::LobbyPlugin;
+use crate::game_over::GameOverPlugin;
+use crate::gamepad::GamepadPlugin;
+use crate::generator::LayoutSettings;
+use crate::level::{LevelPlugin, SelectedLevel};
+use crate::lobby::{
+    invite_code, AutoStart, Bans, JoinPassword, LobbyPassword, LobbyPlugin, SpectatorCap,
+};
 use crate::menu::MenuPlugin;
+use crate::powerups::PowerupPlugin;
+use crate::reconnect::ReconnectPlugin;
+use crate::rematch::RematchPlugin;
+use crate::rounds::{MatchRules, RoundsPlugin};
+use crate::settings::{present_mode, SettingsPlugin};
+use crate::snapshot::SnapshotPlugin;
 use carrier_pigeon::Transport;
+use clap::{CommandFactory, ErrorKind};
 use heron::prelude::*;
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
@@ -20,15 +66,18 @@ pub enum MultiplayerType {
     Server,
     Host,
     Client,
+    /// Two players on one machine, without any connections.
+    Local,
 }
 
 impl MultiplayerType {
-    /// Whether this is a server type (`Server` or `Host`).
+    /// Whether this is a server type (`Server`, `Host` or `Local`).
     pub fn is_server(&self) -> bool {
         match self {
             MultiplayerType::Server => true,
             MultiplayerType::Host => true,
             MultiplayerType::Client => false,
+            MultiplayerType::Local => true,
         }
     }
 
@@ -38,6 +87,7 @@ impl MultiplayerType {
             MultiplayerType::Server => false,
             MultiplayerType::Host => true,
             MultiplayerType::Client => true,
+            MultiplayerType::Local => false,
         }
     }
 }
@@ -52,6 +102,14 @@ pub enum GameState {
     Game,
     /// Game is over.
     GameOver,
+    /// Rebinding the controls.
+    Controls,
+    /// Changing the settings.
+    Settings,
+    /// Looking for games on the local network.
+    LanGames,
+    /// Looking for games in the registry.
+    Browser,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
@@ -61,40 +119,200 @@ pub struct GameIp(SocketAddr);
 pub struct Name(String);
 
 fn main() {
-    // Parse the second arg as an ip address.
-    let ip = std::env::args().nth(1).unwrap_or("127.0.0.1:5599".into());
-    let ip: SocketAddr = ip.parse().unwrap_or("127.0.0.1:5599".parse().unwrap());
+    let cli = Cli::parse_args();
+    if let Some(dir) = cli.config.clone() {
+        config::set_config_dir(dir);
+    }
+
+    // Prefill the menu and the window from the settings. The args override them.
+    let settings = Settings::load();
+
+    let bans = Bans(cli.bans.iter().map(|name| name.to_lowercase()).collect());
+    let spectator_cap = cli.max_spectators.map(SpectatorCap).unwrap_or_default();
+    // There is nobody to press start on the headless server.
+    let auto_start = cli
+        .countdown
+        .map(Duration::from_secs)
+        .or_else(|| cli.headless.then_some(Duration::ZERO));
+    let mut rules = MatchRules::default();
+    if let Some(first_to) = cli.first_to {
+        rules.first_to = first_to;
+    }
+    rules.rebuild_bricks = !cli.keep_bricks;
+    rules.swap_sides = cli.swap_sides;
+    let level = cli.level.clone().map(SelectedLevel).unwrap_or_default();
+    let mut layout = LayoutSettings {
+        enabled: cli.random_layout || cli.seed.is_some(),
+        seed: cli.seed,
+        ..default()
+    };
+    if let Some(symmetry) = cli.symmetry {
+        layout.symmetry = symmetry;
+    }
+    if let Some(pattern) = cli.pattern {
+        layout.pattern = pattern;
+    }
+    if let Some(density) = cli.density {
+        layout.density = density;
+    }
 
-    let name = std::env::args().nth(2).unwrap_or("Player".into());
+    // A subcommand skips the menu.
+    let mut ip = settings.socket_addr();
+    let mut name = settings.name.clone();
+    let mut bot = None;
+    let mut lobby_password = None;
+    let mut join_password = None;
+    let multiplayer_type = match cli.command.clone() {
+        None if cli.headless => Some(MultiplayerType::Server),
+        None => None,
+        Some(Command::Server {
+            bind,
+            password,
+            invite,
+        }) => {
+            ip = bind.unwrap_or(ip);
+            lobby_password = if invite { Some(invite_code()) } else { password };
+            Some(MultiplayerType::Server)
+        }
+        Some(Command::Host {
+            bind,
+            name: n,
+            password,
+            invite,
+        }) => {
+            ip = bind.unwrap_or(ip);
+            name = n.unwrap_or(name);
+            lobby_password = if invite { Some(invite_code()) } else { password };
+            Some(MultiplayerType::Host)
+        }
+        Some(Command::Client {
+            connect,
+            name: n,
+            password,
+        }) => {
+            ip = connect.unwrap_or(ip);
+            name = n.unwrap_or(name);
+            join_password = password;
+            Some(MultiplayerType::Client)
+        }
+        Some(Command::Bot { difficulty, name: n }) => {
+            // Only this machine can join.
+            ip = SocketAddr::from(([127, 0, 0, 1], ip.port()));
+            name = n.unwrap_or(name);
+            bot = Some(BotGame(difficulty));
+            Some(MultiplayerType::Host)
+        }
+    };
+    if let Some(problem) = name_problem(name.trim()) {
+        Cli::command().error(ErrorKind::InvalidValue, problem).exit();
+    }
+    let name = name.trim().to_owned();
 
-    let mut table = messages::get_table();
+    let (mut table, mut version) = messages::get_table();
 
     let mut app = App::new();
     app.sync_comp::<Transform, MyTransform>(&mut table, Transport::UDP)
         .sync_comp::<Velocity, MyVelocity>(&mut table, Transport::UDP);
+    version.add::<MyTransform>(Transport::UDP);
+    version.add::<MyVelocity>(Transport::UDP);
+    version.add::<Connection>(Transport::TCP);
+    version.add::<Response>(Transport::TCP);
+    version.add::<Disconnect>(Transport::TCP);
 
     let parts = table.build::<Connection, Response, Disconnect>().unwrap();
 
-    app
+    app.insert_resource(LogSettings {
+            level: cli.log_level,
+            ..default()
+        })
         .insert_resource(GameIp(ip))
         .insert_resource(Name(name))
+        .insert_resource(Registry(cli.registry.unwrap_or(settings.registry)))
         .insert_resource(parts)
-        .insert_resource(WindowDescriptor {
+        .insert_resource(version)
+        .insert_resource(bans)
+        .insert_resource(LobbyPassword(lobby_password))
+        .insert_resource(JoinPassword(join_password))
+        .insert_resource(spectator_cap)
+        .insert_resource(AutoStart(auto_start))
+        .insert_resource(rules)
+        .insert_resource(level)
+        .insert_resource(layout)
+        .insert_resource(settings.clone());
+
+    if cli.headless {
+        app.insert_resource(MultiplayerType::Server)
+            .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
+                1.0 / 60.0,
+            )))
+            // Skip the menu and start listening right away.
+            .add_state(GameState::Lobby)
+            .add_plugins(MinimalPlugins)
+            .add_plugin(LogPlugin)
+            .add_plugin(TransformPlugin)
+            .add_plugin(HierarchyPlugin)
+            .add_plugin(PhysicsPlugin::default())
+            .add_plugin(ServerPlugin)
+            .add_plugin(LevelPlugin { headless: true })
+            .add_plugin(GamePlugin { headless: true })
+            .add_plugin(ReconnectPlugin)
+            .add_plugin(SnapshotPlugin)
+            .add_plugin(RoundsPlugin)
+            .add_plugin(PowerupPlugin { headless: true })
+            .add_plugin(RematchPlugin { headless: true })
+            .add_plugin(LobbyPlugin { headless: true })
+            .add_plugin(ChatPlugin { headless: true })
+            .add_plugin(DiscoveryPlugin { headless: true })
+            .add_plugin(BrowserPlugin { headless: true });
+    } else {
+        let (width, height) = settings.resolution;
+        app.insert_resource(WindowDescriptor {
             title: "Bong".into(),
-            mode: bevy::window::WindowMode::Windowed,
+            width: width as f32,
+            height: height as f32,
+            mode: settings.window_mode.into(),
+            present_mode: present_mode(settings.vsync),
             ..Default::default()
         })
-        .add_state(GameState::Menu)
+        .insert_resource(Controls::load())
         .add_plugins(DefaultPlugins)
         .add_plugin(PhysicsPlugin::default())
-
         .add_plugin(ClientPlugin)
         .add_plugin(ServerPlugin)
-        .add_plugin(GamePlugin)
+        .add_plugin(LevelPlugin { headless: false })
+        .add_plugin(GamePlugin { headless: false })
+        .add_plugin(ReconnectPlugin)
+        .add_plugin(SnapshotPlugin)
+        .add_plugin(RoundsPlugin)
+        .add_plugin(PowerupPlugin { headless: false })
+        .add_plugin(RematchPlugin { headless: false })
+        .add_plugin(BotPlugin)
+        .add_plugin(GamepadPlugin)
+        .add_plugin(ControlsPlugin)
+        .add_plugin(SettingsPlugin)
+        .add_plugin(GameOverPlugin)
         .add_plugin(MenuPlugin)
-        .add_plugin(LobbyPlugin)
-        .add_startup_system(setup)
-        .run();
+        .add_plugin(LobbyPlugin { headless: false })
+        .add_plugin(ChatPlugin { headless: false })
+        .add_plugin(DiscoveryPlugin { headless: false })
+        .add_plugin(BrowserPlugin { headless: false })
+        .add_startup_system(setup);
+
+        match multiplayer_type {
+            Some(multiplayer_type) => {
+                app.insert_resource(multiplayer_type)
+                    .add_state(GameState::Lobby);
+                if let Some(bot) = bot {
+                    app.insert_resource(bot);
+                }
+            }
+            None => {
+                app.add_state(GameState::Menu);
+            }
+        }
+    }
+
+    app.run();
 }
 
 fn setup(mut commands: Commands) {
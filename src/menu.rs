@@ -1,7 +1,13 @@
-use crate::{GameState, MultiplayerType};
+use crate::bot::{BotGame, Difficulty};
+use crate::config::Settings;
+use crate::messages::{name_problem, MAX_NAME_LEN};
+use crate::{GameIp, GameState, MultiplayerType, Name};
 use bevy::prelude::PositionType::Absolute;
 use bevy::prelude::*;
+use bevy::window::ReceivedCharacter;
 use carrier_pigeon::{Client, Server};
+use std::net::SocketAddr;
+use std::time::Duration;
 
 pub struct MenuPlugin;
 
@@ -14,17 +20,106 @@ enum MenuButton {
     Server,
     Host,
     Client,
+    /// Lists the games on the local network.
+    LanGames,
+    /// Lists the games in the registry.
+    Browser,
+    /// Hosts a game against a bot.
+    Bot,
+    /// Plays a game with two players on this machine.
+    Local,
+    /// Cycles through the bot's difficulties.
+    Difficulty,
+    /// Opens the screen for rebinding the controls.
+    Controls,
+    /// Opens the settings screen.
+    Settings,
 }
 
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A marker for the text that shows the bot's difficulty.
+struct DifficultyLabel;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// An editable text field.
+enum TextField {
+    Address,
+    Name,
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// The text that displays the contents of a [`TextField`].
+struct FieldText(TextField);
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A marker for the text that shows validation errors.
+struct ErrorLabel;
+
+/// The contents and focus of the menu's text fields.
+struct MenuInput {
+    address: String,
+    name: String,
+    /// The values that the fields were prefilled with, which may come from the command line.
+    /// Only the fields that differ from these are saved.
+    prefilled: (SocketAddr, String),
+    focus: Option<TextField>,
+    error: Option<String>,
+}
+
+impl MenuInput {
+    fn field_mut(&mut self, field: TextField) -> &mut String {
+        match field {
+            TextField::Address => &mut self.address,
+            TextField::Name => &mut self.name,
+        }
+    }
+}
+
+/// The blinking caret of the focused text field.
+struct Caret {
+    timer: Timer,
+    visible: bool,
+}
+
+impl Caret {
+    /// Shows the caret and restarts the blinking.
+    fn reset(&mut self) {
+        self.timer.reset();
+        self.visible = true;
+    }
+}
+
+/// The max length of an address.
+const MAX_ADDRESS_LEN: usize = 48;
+
 impl Plugin for MenuPlugin {
     fn build(&self, app: &mut App) {
         app.add_system_set(SystemSet::on_enter(GameState::Menu).with_system(setup_menu))
-            .add_system_set(SystemSet::on_update(GameState::Menu).with_system(handle_ui))
+            .add_system_set(
+                SystemSet::on_update(GameState::Menu)
+                    .with_system(handle_ui)
+                    .with_system(focus_fields)
+                    .with_system(edit_fields)
+                    .with_system(update_fields)
+                    .with_system(update_difficulty_label),
+            )
             .add_system_set(SystemSet::on_exit(GameState::Menu).with_system(clean_up));
     }
 }
 
-fn setup_menu(mut commands: Commands, assets: Res<AssetServer>) {
+fn setup_menu(mut commands: Commands, assets: Res<AssetServer>, ip: Res<GameIp>, name: Res<Name>) {
+    commands.insert_resource(MenuInput {
+        address: ip.0.to_string(),
+        name: name.0.clone(),
+        prefilled: (ip.0, name.0.clone()),
+        focus: None,
+        error: None,
+    });
+    commands.insert_resource(Caret {
+        timer: Timer::new(Duration::from_millis(500), true),
+        visible: true,
+    });
+
     let font = assets.load("FiraMono-Medium.ttf");
     let text_style = TextStyle {
         font,
@@ -38,6 +133,18 @@ fn setup_menu(mut commands: Commands, assets: Res<AssetServer>) {
         align_items: AlignItems::Center,
         ..default()
     };
+    let field_style = Style {
+        size: Size::new(Val::Px(700.0), Val::Px(80.0)),
+        margin: Rect::all(Val::Px(10.0)),
+        padding: Rect::all(Val::Px(10.0)),
+        align_items: AlignItems::Center,
+        ..default()
+    };
+    let label_style = Style {
+        size: Size::new(Val::Px(280.0), Val::Auto),
+        margin: Rect::all(Val::Px(10.0)),
+        ..default()
+    };
 
     println!("setting up menu");
     // parent
@@ -60,6 +167,73 @@ fn setup_menu(mut commands: Commands, assets: Res<AssetServer>) {
         })
         .insert(MenuItem)
         .with_children(|parent| {
+            for (label, field) in [("Address:", TextField::Address), ("Name:", TextField::Name)] {
+                parent
+                    .spawn_bundle(NodeBundle {
+                        style: Style {
+                            flex_direction: FlexDirection::Row,
+                            align_items: AlignItems::Center,
+                            ..default()
+                        },
+                        color: Color::NONE.into(),
+                        ..default()
+                    })
+                    .with_children(|parent| {
+                        parent.spawn_bundle(TextBundle {
+                            style: label_style.clone(),
+                            text: Text::with_section(
+                                label,
+                                text_style.clone(),
+                                TextAlignment::default(),
+                            ),
+                            ..default()
+                        });
+
+                        parent
+                            .spawn_bundle(ButtonBundle {
+                                color: UiColor(Color::rgb_u8(230, 230, 230)),
+                                style: field_style.clone(),
+                                ..default()
+                            })
+                            .insert(field)
+                            .with_children(|parent| {
+                                parent
+                                    .spawn_bundle(TextBundle {
+                                        text: Text::with_section(
+                                            "",
+                                            TextStyle {
+                                                font_size: 50.0,
+                                                ..text_style.clone()
+                                            },
+                                            TextAlignment::default(),
+                                        ),
+                                        ..default()
+                                    })
+                                    .insert(FieldText(field));
+                            });
+                    });
+            }
+
+            // Validation errors
+            parent
+                .spawn_bundle(TextBundle {
+                    style: Style {
+                        margin: Rect::all(Val::Px(10.0)),
+                        ..default()
+                    },
+                    text: Text::with_section(
+                        "",
+                        TextStyle {
+                            font_size: 40.0,
+                            color: Color::WHITE,
+                            ..text_style.clone()
+                        },
+                        TextAlignment::default(),
+                    ),
+                    ..default()
+                })
+                .insert(ErrorLabel);
+
             parent
                 .spawn_bundle(ButtonBundle {
                     color: UiColor(Color::rgb_u8(255, 255, 255)),
@@ -101,7 +275,7 @@ fn setup_menu(mut commands: Commands, assets: Res<AssetServer>) {
             parent
                 .spawn_bundle(ButtonBundle {
                     color: UiColor(Color::rgb_u8(255, 255, 255)),
-                    style: button_style,
+                    style: button_style.clone(),
                     // transform: Transform::from_xyz(100.0, 0.0, 0.0),
                     ..Default::default()
                 })
@@ -110,26 +284,258 @@ fn setup_menu(mut commands: Commands, assets: Res<AssetServer>) {
                     parent.spawn_bundle(TextBundle {
                         text: Text::with_section(
                             "Start Client",
-                            text_style,
+                            text_style.clone(),
+                            TextAlignment::default(),
+                        ),
+                        ..Default::default()
+                    });
+                });
+
+            // Finding games to join
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Row,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    color: Color::NONE.into(),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    for (label, button) in [
+                        ("LAN Games", MenuButton::LanGames),
+                        ("Online Games", MenuButton::Browser),
+                    ] {
+                        parent
+                            .spawn_bundle(ButtonBundle {
+                                color: UiColor(Color::rgb_u8(255, 255, 255)),
+                                style: Style {
+                                    size: Size::new(Val::Px(480.0), Val::Px(100.0)),
+                                    ..button_style.clone()
+                                },
+                                ..Default::default()
+                            })
+                            .insert(button)
+                            .with_children(|parent| {
+                                parent.spawn_bundle(TextBundle {
+                                    text: Text::with_section(
+                                        label,
+                                        text_style.clone(),
+                                        TextAlignment::default(),
+                                    ),
+                                    ..Default::default()
+                                });
+                            });
+                    }
+                });
+
+            parent
+                .spawn_bundle(ButtonBundle {
+                    color: UiColor(Color::rgb_u8(255, 255, 255)),
+                    style: button_style.clone(),
+                    ..Default::default()
+                })
+                .insert(MenuButton::Local)
+                .with_children(|parent| {
+                    parent.spawn_bundle(TextBundle {
+                        text: Text::with_section(
+                            "Local Versus",
+                            text_style.clone(),
                             TextAlignment::default(),
                         ),
                         ..Default::default()
                     });
                 });
+
+            // Bot game, with the difficulty next to it
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Row,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    color: Color::NONE.into(),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    parent
+                        .spawn_bundle(ButtonBundle {
+                            color: UiColor(Color::rgb_u8(255, 255, 255)),
+                            style: Style {
+                                size: Size::new(Val::Px(640.0), Val::Px(100.0)),
+                                ..button_style.clone()
+                            },
+                            ..Default::default()
+                        })
+                        .insert(MenuButton::Bot)
+                        .with_children(|parent| {
+                            parent.spawn_bundle(TextBundle {
+                                text: Text::with_section(
+                                    "Play vs Bot",
+                                    text_style.clone(),
+                                    TextAlignment::default(),
+                                ),
+                                ..Default::default()
+                            });
+                        });
+
+                    parent
+                        .spawn_bundle(ButtonBundle {
+                            color: UiColor(Color::rgb_u8(230, 230, 230)),
+                            style: Style {
+                                size: Size::new(Val::Px(320.0), Val::Px(100.0)),
+                                ..button_style.clone()
+                            },
+                            ..Default::default()
+                        })
+                        .insert(MenuButton::Difficulty)
+                        .with_children(|parent| {
+                            parent
+                                .spawn_bundle(TextBundle {
+                                    text: Text::with_section(
+                                        "",
+                                        text_style.clone(),
+                                        TextAlignment::default(),
+                                    ),
+                                    ..Default::default()
+                                })
+                                .insert(DifficultyLabel);
+                        });
+                });
+
+            // Controls and settings
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Row,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    color: Color::NONE.into(),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    for (label, button) in [
+                        ("Controls", MenuButton::Controls),
+                        ("Settings", MenuButton::Settings),
+                    ] {
+                        parent
+                            .spawn_bundle(ButtonBundle {
+                                color: UiColor(Color::rgb_u8(230, 230, 230)),
+                                style: Style {
+                                    size: Size::new(Val::Px(480.0), Val::Px(100.0)),
+                                    ..button_style.clone()
+                                },
+                                ..Default::default()
+                            })
+                            .insert(button)
+                            .with_children(|parent| {
+                                parent.spawn_bundle(TextBundle {
+                                    text: Text::with_section(
+                                        label,
+                                        text_style.clone(),
+                                        TextAlignment::default(),
+                                    ),
+                                    ..Default::default()
+                                });
+                            });
+                    }
+                });
         });
 }
 
 fn handle_ui(
     q_interaction: Query<(&Interaction, &MenuButton), Changed<Interaction>>,
     mut game_state: ResMut<State<GameState>>,
+    mut input: ResMut<MenuInput>,
+    mut difficulty: ResMut<Difficulty>,
+    mut settings: ResMut<Settings>,
     mut commands: Commands,
 ) {
     for (interaction, menu_button) in q_interaction.iter() {
         if *interaction == Interaction::Clicked {
+            if *menu_button == MenuButton::Difficulty {
+                *difficulty = difficulty.next();
+                continue;
+            }
+            if *menu_button == MenuButton::Controls {
+                game_state.set(GameState::Controls).unwrap();
+                return;
+            }
+            if *menu_button == MenuButton::Settings {
+                game_state.set(GameState::Settings).unwrap();
+                return;
+            }
+
+            let name = input.name.trim().to_owned();
+            if let Some(problem) = name_problem(&name) {
+                input.error = Some(problem);
+                input.focus = Some(TextField::Name);
+                return;
+            }
+            // The address comes from the game that is picked.
+            let list = match menu_button {
+                MenuButton::LanGames => Some(GameState::LanGames),
+                MenuButton::Browser => Some(GameState::Browser),
+                _ => None,
+            };
+            if let Some(list) = list {
+                if name != input.prefilled.1 {
+                    settings.name = name.clone();
+                    if let Err(e) = settings.save() {
+                        warn!("Failed to save the settings: {}", e);
+                    }
+                }
+                commands.insert_resource(Name(name));
+                game_state.set(list).unwrap();
+                return;
+            }
+            let ip: SocketAddr = match input.address.trim().parse() {
+                Ok(ip) => ip,
+                Err(_) => {
+                    input.error = Some(format!("Invalid address \"{}\"", input.address.trim()));
+                    input.focus = Some(TextField::Address);
+                    return;
+                }
+            };
+
+            let edited_name = name != input.prefilled.1;
+            let edited_ip = ip != input.prefilled.0;
+            if edited_name {
+                settings.name = name.clone();
+            }
+            if edited_ip {
+                settings.address = ip.ip();
+                settings.port = ip.port();
+            }
+            if edited_name || edited_ip {
+                if let Err(e) = settings.save() {
+                    warn!("Failed to save the settings: {}", e);
+                }
+            }
+            commands.insert_resource(GameIp(ip));
+            commands.insert_resource(Name(name));
+
+            commands.remove_resource::<BotGame>();
             match menu_button {
                 MenuButton::Server => commands.insert_resource(MultiplayerType::Server),
                 MenuButton::Host => commands.insert_resource(MultiplayerType::Host),
                 MenuButton::Client => commands.insert_resource(MultiplayerType::Client),
+                MenuButton::Bot => {
+                    // Only this machine can join.
+                    let local = SocketAddr::from(([127, 0, 0, 1], ip.port()));
+                    commands.insert_resource(GameIp(local));
+                    commands.insert_resource(MultiplayerType::Host);
+                    commands.insert_resource(BotGame(*difficulty));
+                }
+                MenuButton::Local => commands.insert_resource(MultiplayerType::Local),
+                MenuButton::LanGames
+                | MenuButton::Browser
+                | MenuButton::Difficulty
+                | MenuButton::Controls
+                | MenuButton::Settings => unreachable!(),
             }
             // Destroy the client/server when returning to the menu.
             commands.remove_resource::<Client>();
@@ -139,8 +545,140 @@ fn handle_ui(
     }
 }
 
+/// Focuses the text field that was clicked.
+fn focus_fields(
+    q_interaction: Query<(&Interaction, &TextField), Changed<Interaction>>,
+    mut input: ResMut<MenuInput>,
+    mut caret: ResMut<Caret>,
+) {
+    for (interaction, field) in q_interaction.iter() {
+        if *interaction == Interaction::Clicked {
+            input.focus = Some(*field);
+            caret.reset();
+        }
+    }
+}
+
+/// Applies typed characters, backspace, tab and paste to the focused text field.
+fn edit_fields(
+    mut chars: EventReader<ReceivedCharacter>,
+    keys: Res<Input<KeyCode>>,
+    mut input: ResMut<MenuInput>,
+    mut caret: ResMut<Caret>,
+) {
+    let focus = match input.focus {
+        Some(focus) => focus,
+        None => {
+            chars.iter().for_each(drop);
+            return;
+        }
+    };
+    let max_len = match focus {
+        TextField::Address => MAX_ADDRESS_LEN,
+        TextField::Name => MAX_NAME_LEN,
+    };
+
+    let mut changed = false;
+    let ctrl = keys.any_pressed([
+        KeyCode::LControl,
+        KeyCode::RControl,
+        KeyCode::LWin,
+        KeyCode::RWin,
+    ]);
+
+    if ctrl && keys.just_pressed(KeyCode::V) {
+        match arboard::Clipboard::new().and_then(|mut c| c.get_text()) {
+            Ok(pasted) => {
+                let field = input.field_mut(focus);
+                for c in pasted.chars().filter(|c| !c.is_control()) {
+                    if field.chars().count() < max_len {
+                        field.push(c);
+                    }
+                }
+                changed = true;
+            }
+            Err(e) => warn!("Failed to read the clipboard: {}", e),
+        }
+    }
+
+    for c in chars.iter() {
+        // Control characters (backspace, tab, enter) are handled through `keys`.
+        if c.char.is_control() || ctrl {
+            continue;
+        }
+        let field = input.field_mut(focus);
+        if field.chars().count() < max_len {
+            field.push(c.char);
+            changed = true;
+        }
+    }
+
+    if keys.just_pressed(KeyCode::Back) {
+        input.field_mut(focus).pop();
+        changed = true;
+    }
+
+    if keys.just_pressed(KeyCode::Tab) {
+        input.focus = Some(match focus {
+            TextField::Address => TextField::Name,
+            TextField::Name => TextField::Address,
+        });
+        changed = true;
+    }
+
+    if keys.just_pressed(KeyCode::Escape) || keys.just_pressed(KeyCode::Return) {
+        input.focus = None;
+    }
+
+    if changed {
+        input.error = None;
+        caret.reset();
+    }
+}
+
+/// Updates the field texts, caret and error label.
+fn update_fields(
+    time: Res<Time>,
+    input: Res<MenuInput>,
+    mut caret: ResMut<Caret>,
+    mut q_field_text: Query<(&mut Text, &FieldText)>,
+    mut q_error: Query<&mut Text, (With<ErrorLabel>, Without<FieldText>)>,
+) {
+    if caret.timer.tick(time.delta()).just_finished() {
+        caret.visible = !caret.visible;
+    }
+
+    for (mut text, field_text) in q_field_text.iter_mut() {
+        let value = match field_text.0 {
+            TextField::Address => &input.address,
+            TextField::Name => &input.name,
+        };
+        let caret_txt = if input.focus == Some(field_text.0) && caret.visible {
+            "|"
+        } else {
+            ""
+        };
+        text.sections[0].value = format!("{}{}", value, caret_txt);
+    }
+
+    for mut text in q_error.iter_mut() {
+        text.sections[0].value = input.error.clone().unwrap_or_default();
+    }
+}
+
+fn update_difficulty_label(
+    difficulty: Res<Difficulty>,
+    mut q_label: Query<&mut Text, With<DifficultyLabel>>,
+) {
+    for mut text in q_label.iter_mut() {
+        text.sections[0].value = difficulty.label().to_owned();
+    }
+}
+
 fn clean_up(mut commands: Commands, q_menu: Query<Entity, With<MenuItem>>) {
     for e in q_menu.iter() {
         commands.entity(e).despawn_recursive();
     }
+    commands.remove_resource::<MenuInput>();
+    commands.remove_resource::<Caret>();
 }
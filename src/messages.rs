@@ -3,20 +3,79 @@ use bevy::math::Vec3Swizzles;
 use bevy::prelude::Transform;
 use carrier_pigeon::{CId, MsgTable, Transport};
 use heron::Velocity;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
 
 use crate::game::Team;
+use crate::generator::Layout;
+use crate::powerups::PowerupKind;
+use crate::schema;
 use crate::{default, Quat, Vec2};
 
+/// The max length of a player name.
+pub const MAX_NAME_LEN: usize = 16;
+/// The max length of a chat message.
+pub const MAX_CHAT_LEN: usize = 120;
+
+/// Whether `name` is allowed as a player name.
+pub fn valid_name(name: &str) -> bool {
+    name_problem(name).is_none()
+}
+
+/// Describes why `name` isn't allowed as a player name, or `None` if it is.
+pub fn name_problem(name: &str) -> Option<String> {
+    if name.trim().is_empty() {
+        Some("The name can't be empty".to_owned())
+    } else if name.trim() != name {
+        Some("The name can't start or end with spaces".to_owned())
+    } else if name.chars().count() > MAX_NAME_LEN {
+        Some(format!(
+            "The name can't be longer than {} characters",
+            MAX_NAME_LEN
+        ))
+    } else if name.chars().any(char::is_control) {
+        Some("The name can't contain control characters".to_owned())
+    } else {
+        None
+    }
+}
+
 /// The connection message.
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
 pub struct Connection {
+    /// The [`ProtocolVersion`] of the client.
+    ///
+    /// This is the first field so that it can still be read when the other fields changed.
+    pub version: u64,
     pub name: String,
+    /// The session token from a previous connection, when rejoining a game in progress.
+    pub session: Option<u64>,
+    /// The lobby password or invite code, if the server needs one.
+    pub password: Option<String>,
 }
 
 impl Connection {
-    pub fn new(name: impl Into<String>) -> Self {
-        Connection { name: name.into() }
+    pub fn new(name: impl Into<String>, version: ProtocolVersion) -> Self {
+        Connection {
+            version: version.0,
+            name: name.into(),
+            session: None,
+            password: None,
+        }
+    }
+
+    /// Adds the lobby password to the connection message.
+    pub fn with_password(self, password: Option<String>) -> Self {
+        Connection { password, ..self }
+    }
+
+    /// Creates a connection message for rejoining a game in progress.
+    pub fn rejoin(name: impl Into<String>, version: ProtocolVersion, session: u64) -> Self {
+        Connection {
+            session: Some(session),
+            ..Connection::new(name, version)
+        }
     }
 }
 
@@ -25,13 +84,16 @@ impl Connection {
 pub struct ConnectionBroadcast {
     pub name: String,
     pub cid: CId,
+    /// The team that they play on, or `None` for spectators.
+    pub team: Option<Team>,
 }
 
 impl ConnectionBroadcast {
-    pub fn new(name: impl Into<String>, cid: CId) -> Self {
+    pub fn new(name: impl Into<String>, cid: CId, team: Option<Team>) -> Self {
         ConnectionBroadcast {
             name: name.into(),
             cid,
+            team,
         }
     }
 }
@@ -45,45 +107,375 @@ pub struct DisconnectBroadcast {
 /// The response message.
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
 pub enum Response {
-    Accepted(CId, Option<(CId, String)>),
+    Accepted {
+        cid: CId,
+        /// The team that the client plays on, or `None` for spectators.
+        team: Option<Team>,
+        /// Everyone that is already connected.
+        others: Vec<ConnectionBroadcast>,
+        /// The token for rejoining after the connection drops.
+        session: u64,
+        /// The start of the game, if it has already started.
+        game: Option<StartGame>,
+    },
     Rejected(RejectReason),
 }
 
 #[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
 pub enum RejectReason {
     MaxPlayersReached,
+    /// The client was built with a different message table than the server.
+    VersionMismatch { server: u64, client: u64 },
+    /// The name is empty, too long, or contains invalid characters.
+    BadName,
+    /// The player is banned from this server.
+    Banned,
+    /// A game is currently being played on this server.
+    GameInProgress,
+    /// The lobby password was wrong.
+    WrongPassword,
+    /// The lobby needs a password, and none was sent.
+    PasswordRequired,
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::MaxPlayersReached => write!(f, "Server full"),
+            RejectReason::VersionMismatch { server, client } => write!(
+                f,
+                "Version mismatch (server {:016x}, client {:016x})",
+                server, client
+            ),
+            RejectReason::BadName => write!(f, "Invalid name"),
+            RejectReason::Banned => write!(f, "Banned"),
+            RejectReason::GameInProgress => write!(f, "Game in progress"),
+            RejectReason::WrongPassword => write!(f, "Wrong password"),
+            RejectReason::PasswordRequired => write!(f, "Password required"),
+        }
+    }
 }
 
 /// The disconnection message.
 #[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
-pub struct Disconnect {}
+pub struct Disconnect {
+    pub reason: DisconnectReason,
+}
+
+impl Disconnect {
+    pub fn new(reason: DisconnectReason) -> Self {
+        Disconnect { reason }
+    }
+}
+
+/// Why a connection was gracefully closed.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+pub enum DisconnectReason {
+    /// The server is shutting down.
+    ServerClosed,
+    /// The server removed this client.
+    Kicked,
+    /// The client left.
+    Left,
+}
+
+impl Display for DisconnectReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisconnectReason::ServerClosed => write!(f, "Server closed"),
+            DisconnectReason::Kicked => write!(f, "Kicked by the server"),
+            DisconnectReason::Left => write!(f, "Left the game"),
+        }
+    }
+}
 
-pub fn get_table() -> MsgTable {
+/// A hash of the types and transports of all networked messages, in registration order.
+///
+/// The server rejects clients with a different version, as the message ids wouldn't line up.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct ProtocolVersion(pub u64);
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        // FNV-1a offset basis.
+        ProtocolVersion(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl ProtocolVersion {
+    /// Adds a message type to the version.
+    ///
+    /// This must be called for every message, in the order that they are registered. The fields of
+    /// the message are part of the version too, so changing them changes the version.
+    pub fn add<T: DeserializeOwned + 'static>(&mut self, transport: Transport) {
+        let transport: &[u8] = match transport {
+            Transport::TCP => b"TCP",
+            Transport::UDP => b"UDP",
+        };
+        let schema = schema::describe::<T>();
+        // FNV-1a is used instead of `DefaultHasher` as it is the same on every build. The schema
+        // has the names of the message's types, which, unlike `type_name`, don't change between
+        // compiler versions.
+        for byte in schema.bytes().chain(transport.iter().copied()) {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Registers `T` in the `table`, and adds it to the `version`.
+fn register<T: Serialize + DeserializeOwned + Send + Sync + 'static>(
+    table: &mut MsgTable,
+    version: &mut ProtocolVersion,
+    transport: Transport,
+) {
+    table.register::<T>(transport).unwrap();
+    version.add::<T>(transport);
+}
+
+pub fn get_table() -> (MsgTable, ProtocolVersion) {
     let mut table = MsgTable::new();
-    table
-        .register::<ConnectionBroadcast>(Transport::TCP)
-        .unwrap();
-    table
-        .register::<DisconnectBroadcast>(Transport::TCP)
-        .unwrap();
-    table.register::<StartGame>(Transport::TCP).unwrap();
-    table.register::<BrickBreak>(Transport::TCP).unwrap();
-    table.register::<GameWin>(Transport::TCP).unwrap();
-    table.register::<Ping>(Transport::UDP).unwrap();
+    let mut version = ProtocolVersion::default();
+    register::<ConnectionBroadcast>(&mut table, &mut version, Transport::TCP);
+    register::<DisconnectBroadcast>(&mut table, &mut version, Transport::TCP);
+    register::<StartGame>(&mut table, &mut version, Transport::TCP);
+    register::<SetReady>(&mut table, &mut version, Transport::TCP);
+    register::<SideRequest>(&mut table, &mut version, Transport::TCP);
+    register::<LobbyStatus>(&mut table, &mut version, Transport::TCP);
+    register::<ChatSend>(&mut table, &mut version, Transport::TCP);
+    register::<ChatLine>(&mut table, &mut version, Transport::TCP);
+    register::<ChatHistory>(&mut table, &mut version, Transport::TCP);
+    register::<BrickUpdate>(&mut table, &mut version, Transport::TCP);
+    register::<GameWin>(&mut table, &mut version, Transport::TCP);
+    register::<Ping>(&mut table, &mut version, Transport::UDP);
+    register::<MatchPause>(&mut table, &mut version, Transport::TCP);
+    register::<MatchSnapshot>(&mut table, &mut version, Transport::TCP);
+    register::<SnapshotRequest>(&mut table, &mut version, Transport::TCP);
+    register::<Score>(&mut table, &mut version, Transport::TCP);
+    register::<NewRound>(&mut table, &mut version, Transport::TCP);
+    register::<RematchVote>(&mut table, &mut version, Transport::TCP);
+    register::<RematchStatus>(&mut table, &mut version, Transport::TCP);
+    register::<SwapSides>(&mut table, &mut version, Transport::TCP);
+    register::<BallSpawn>(&mut table, &mut version, Transport::TCP);
+    register::<BallDespawn>(&mut table, &mut version, Transport::TCP);
+    register::<PowerupSpawn>(&mut table, &mut version, Transport::TCP);
+    register::<PowerupDespawn>(&mut table, &mut version, Transport::TCP);
+    register::<ActiveEffects>(&mut table, &mut version, Transport::TCP);
 
-    table
+    (table, version)
 }
 
 /// A message that indicates that the game has been started by the server.
-#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
-pub struct StartGame;
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct StartGame {
+    /// The id of the level to play on.
+    pub level: String,
+    /// The layout to generate the bricks from, instead of using the level's.
+    pub layout: Option<Layout>,
+}
 
-/// A message that indicates that the game has been started by the server.
+/// A message that indicates that a team has won the match.
 #[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
 pub struct GameWin(pub Team);
 
+/// A message with the new hit points of a brick, which is broken once they reach 0.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+pub struct BrickUpdate {
+    pub id: u32,
+    pub hp: u32,
+}
+
+/// The number of rounds that each team has won.
+///
+/// This is sent whenever a round is won.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug, Hash, Default)]
+pub struct Score {
+    pub left: u32,
+    pub right: u32,
+}
+
+impl Score {
+    pub fn get(&self, team: Team) -> u32 {
+        match team {
+            Team::Left => self.left,
+            Team::Right => self.right,
+        }
+    }
+
+    /// Gives a point to `team`.
+    pub fn add(&mut self, team: Team) {
+        match team {
+            Team::Left => self.left += 1,
+            Team::Right => self.right += 1,
+        }
+    }
+}
+
+/// A message that indicates that the next round has been served.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+pub struct NewRound {
+    /// Whether the bricks were rebuilt.
+    pub rebuild_bricks: bool,
+}
+
+/// A message that spawns a ball on the clients.
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub struct BallSpawn {
+    /// The id of the ball's `NetEntity`.
+    pub id: u64,
+    pub transform: MyTransform,
+    pub velocity: MyVelocity,
+}
+
+/// A message that despawns a ball on the clients.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+pub struct BallDespawn {
+    /// The id of the ball's `NetEntity`.
+    pub id: u64,
+}
+
+/// A message that spawns a powerup on the clients.
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub struct PowerupSpawn {
+    /// The id of the powerup's `NetEntity`.
+    pub id: u64,
+    pub kind: PowerupKind,
+    pub transform: MyTransform,
+}
+
+/// A message that despawns a powerup on the clients, as it was collected or left the arena.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+pub struct PowerupDespawn {
+    /// The id of the powerup's `NetEntity`.
+    pub id: u64,
+}
+
+/// A powerup effect that is active on a team.
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub struct Effect {
+    pub team: Team,
+    pub kind: PowerupKind,
+    /// The seconds until the effect ends.
+    pub remaining: f32,
+}
+
+/// The powerup effects that are active.
+///
+/// This is sent whenever an effect starts or ends.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct ActiveEffects(pub Vec<Effect>);
+
+impl ActiveEffects {
+    /// Whether `team` has an active effect of the given `kind`.
+    pub fn has(&self, team: Team, kind: PowerupKind) -> bool {
+        self.0.iter().any(|e| e.team == team && e.kind == kind)
+    }
+
+    /// Gets how much faster than normal the ball is allowed to go.
+    pub fn speed_factor(&self) -> f32 {
+        self.0.iter().map(|e| e.kind.speed_factor()).product()
+    }
+
+    /// Gets how much taller than normal the paddle of `team` is.
+    pub fn paddle_factor(&self, team: Team) -> f32 {
+        self.0
+            .iter()
+            .filter(|e| e.team == team)
+            .map(|e| e.kind.paddle_factor())
+            .product()
+    }
+}
+
+/// A message from a player in the lobby, saying whether they are ready to start.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+pub struct SetReady(pub bool);
+
+/// A message from a player in the lobby, asking to play on the given team.
+///
+/// If the other player has that team, it asks them to swap sides, which happens once they ask too.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+pub struct SideRequest(pub Team);
+
+/// The state of the lobby, sent by the server whenever it changes.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug, Default)]
+pub struct LobbyStatus {
+    /// The teams whose player is ready.
+    pub ready: Vec<Team>,
+    /// The team whose player asked to swap sides.
+    pub swap: Option<Team>,
+    /// The seconds until the game starts, once both players are ready.
+    pub countdown: Option<u32>,
+}
+
+/// A chat message from a client, which the server relays to everyone.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct ChatSend(pub String);
+
+/// A chat message, relayed by the server with who sent it.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct ChatLine {
+    pub cid: CId,
+    pub name: String,
+    pub text: String,
+}
+
+/// The last chat messages, sent by the server to everyone that joins.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug, Default)]
+pub struct ChatHistory(pub Vec<ChatLine>);
+
+/// A player's answer to whether they want to play again after a match.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+pub struct RematchVote(pub bool);
+
+/// The state of the rematch vote, sent by the server whenever it changes.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug, Default)]
+pub struct RematchStatus {
+    /// The teams whose player wants a rematch.
+    pub accepted: Vec<Team>,
+    /// The teams whose player left.
+    pub left: Vec<Team>,
+}
+
+/// A message that swaps the teams of the players, in the lobby or before a rematch.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
+pub struct SwapSides;
+
+/// A message that pauses the game while waiting for a player to reconnect, or resumes it.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct MatchPause {
+    /// The name of the player that is being waited for, or `None` to resume.
+    pub waiting_for: Option<String>,
+}
+
+/// The phase of a match.
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub enum MatchPhase {
+    Playing,
+    /// Waiting for a player to reconnect.
+    Paused,
+    /// A team has won.
+    Over,
+}
+
+/// The full state of a match, used to resync clients.
+///
+/// It is sent when a client joins or requests it, and periodically.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct MatchSnapshot {
+    pub phase: MatchPhase,
+    pub score: Score,
+    pub effects: ActiveEffects,
+    /// The ids and hit points of the bricks that haven't been broken.
+    pub bricks: Vec<(u32, u32)>,
+    /// The balls, with the ids of their `NetEntity`s.
+    pub balls: Vec<(u64, MyTransform, MyVelocity)>,
+    pub paddles: Vec<(Team, MyTransform)>,
+    pub targets: Vec<(Team, MyTransform)>,
+}
+
+/// A message from a client, asking for a [`MatchSnapshot`].
 #[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
-pub struct BrickBreak(pub u32);
+pub struct SnapshotRequest;
 
 #[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
 pub struct Ping {
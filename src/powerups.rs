@@ -0,0 +1,648 @@
+//! Powerups that drop out of broken bricks, and the effects that they give.
+use crate::game::{
+    spawn_ball, Authority, Ball, BrickBrokenE, GameItem, NetIds, Paddle, Team, PADDLE_HEIGHT,
+    PADDLE_WIDTH,
+};
+use crate::level::CurrentLevel;
+use crate::lobby::Players;
+use crate::messages::{ActiveEffects, BallSpawn, Effect, PowerupDespawn, PowerupSpawn};
+use crate::rounds::RoundOverR;
+use crate::{GameState, MyTransform};
+use bevy::prelude::*;
+use bevy_pigeon::sync::{CNetDir, NetComp, NetEntity, SNetDir};
+use carrier_pigeon::{Client, Server};
+use heron::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// The chance that a broken brick drops a powerup.
+const POWERUP_CHANCE: f32 = 0.15;
+/// How fast a powerup drifts.
+const POWERUP_SPEED: f32 = 200.0;
+const POWERUP_SIZE: f32 = 40.0;
+/// How long a sticky paddle holds on to the ball.
+const STICK_TIME: Duration = Duration::from_secs(1);
+/// The speed that a sticky paddle launches the ball at.
+const LAUNCH_SPEED: f32 = 750.0;
+/// The most balls that multi-ball splits into.
+const MAX_BALLS: usize = 8;
+/// How far to the side of the ball that multi-ball spawns the new ones, so they don't overlap.
+const SPLIT_OFFSET: f32 = 25.0;
+
+pub struct PowerupPlugin {
+    /// Whether the game is running without a window or audio.
+    pub headless: bool,
+}
+
+impl Plugin for PowerupPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ActiveEffects>()
+            .add_system_set(SystemSet::on_enter(GameState::Game).with_system(setup_powerups))
+            .add_system_set(
+                SystemSet::on_update(GameState::Game)
+                    .with_system(spawn_powerups)
+                    .with_system(move_powerups)
+                    .with_system(collect_powerups)
+                    .with_system(follow_powerups)
+                    .with_system(tick_effects)
+                    .with_system(scale_ball_speed)
+                    .with_system(resize_paddles)
+                    .with_system(sync_shields)
+                    .with_system(stick_balls)
+                    .with_system(carry_stuck_balls),
+            );
+
+        if !self.headless {
+            app.add_system_set(
+                SystemSet::on_enter(GameState::Game).with_system(setup_effects_hud),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameState::Game).with_system(update_effects_hud),
+            );
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub enum PowerupKind {
+    /// Makes the collector's paddle taller.
+    Grow,
+    /// Makes the other team's paddle shorter.
+    Shrink,
+    /// Makes the ball faster.
+    SpeedUp,
+    /// Makes the ball slower.
+    SlowDown,
+    /// Splits the ball into three.
+    MultiBall,
+    /// Puts a wall in front of the collector's target.
+    Shield,
+    /// Makes the ball stick to the collector's paddle for a moment.
+    Sticky,
+}
+
+impl PowerupKind {
+    pub const ALL: [PowerupKind; 7] = [
+        PowerupKind::Grow,
+        PowerupKind::Shrink,
+        PowerupKind::SpeedUp,
+        PowerupKind::SlowDown,
+        PowerupKind::MultiBall,
+        PowerupKind::Shield,
+        PowerupKind::Sticky,
+    ];
+
+    /// Gets how long the effect lasts, or `None` if it happens once.
+    pub fn duration(&self) -> Option<Duration> {
+        match self {
+            PowerupKind::Grow | PowerupKind::Shrink => Some(Duration::from_secs(10)),
+            PowerupKind::SpeedUp | PowerupKind::SlowDown => Some(Duration::from_secs(8)),
+            PowerupKind::MultiBall => None,
+            PowerupKind::Shield | PowerupKind::Sticky => Some(Duration::from_secs(10)),
+        }
+    }
+
+    /// Gets the team that the effect is applied to, when `collector` collects it.
+    pub fn affects(&self, collector: Team) -> Team {
+        match self {
+            PowerupKind::Shrink => collector.other(),
+            _ => collector,
+        }
+    }
+
+    /// Gets how much the effect changes the speed of the ball.
+    pub fn speed_factor(&self) -> f32 {
+        match self {
+            PowerupKind::SpeedUp => 1.5,
+            PowerupKind::SlowDown => 0.6,
+            _ => 1.0,
+        }
+    }
+
+    /// Gets how much the effect changes the height of the paddle.
+    pub fn paddle_factor(&self) -> f32 {
+        match self {
+            PowerupKind::Grow => 1.5,
+            PowerupKind::Shrink => 0.6,
+            _ => 1.0,
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        match self {
+            PowerupKind::Grow => Color::GREEN,
+            PowerupKind::Shrink => Color::RED,
+            PowerupKind::SpeedUp => Color::ORANGE,
+            PowerupKind::SlowDown => Color::CYAN,
+            PowerupKind::MultiBall => Color::PINK,
+            PowerupKind::Shield => Color::BLUE,
+            PowerupKind::Sticky => Color::YELLOW,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            PowerupKind::Grow => "Grow",
+            PowerupKind::Shrink => "Shrink",
+            PowerupKind::SpeedUp => "Speed Up",
+            PowerupKind::SlowDown => "Slow Down",
+            PowerupKind::MultiBall => "Multi-Ball",
+            PowerupKind::Shield => "Shield",
+            PowerupKind::Sticky => "Sticky",
+        }
+    }
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A powerup that can be collected with a paddle.
+pub struct Powerup {
+    /// The id of the powerup's `NetEntity`.
+    pub id: u64,
+    pub kind: PowerupKind,
+}
+
+#[derive(Component, Copy, Clone, PartialEq, Debug)]
+/// The direction that a powerup drifts in along the x axis.
+///
+/// Only on the server, as the clients get the position synced.
+struct Drift(f32);
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A wall in front of the target of the team.
+struct Shield(Team);
+
+#[derive(Component, Clone, Debug)]
+/// A ball that is stuck to a sticky paddle.
+struct Stuck {
+    paddle: Entity,
+    /// The position of the ball relative to the paddle.
+    offset: Vec3,
+    timer: Timer,
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// The text field with the active effects.
+struct EffectsCounter;
+
+/// The speed factor of the effects that the velocity of the balls was last scaled with.
+///
+/// Only used by the server. Serving the ball uses this, so that [`scale_ball_speed`] only scales
+/// the balls when the effects change.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct AppliedSpeed(pub f32);
+
+impl Default for AppliedSpeed {
+    fn default() -> Self {
+        AppliedSpeed(1.0)
+    }
+}
+
+fn setup_powerups(mut commands: Commands) {
+    commands.insert_resource(ActiveEffects::default());
+    commands.insert_resource(AppliedSpeed::default());
+}
+
+fn setup_effects_hud(mut commands: Commands, assets: Res<AssetServer>) {
+    let font = assets.load("FiraMono-Medium.ttf");
+
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    bottom: Val::Px(0.0),
+                    left: Val::Px(0.0),
+                    right: Val::Auto,
+                    top: Val::Auto,
+                },
+                padding: Rect::all(Val::Px(5.0)),
+                ..default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font,
+                    font_size: 30.0,
+                    color: Color::BLACK,
+                },
+                TextAlignment::default(),
+            ),
+            ..default()
+        })
+        .insert(EffectsCounter)
+        .insert(GameItem);
+}
+
+/// Spawns a powerup that is synced with the given `id`.
+fn spawn_powerup(
+    commands: &mut Commands,
+    id: u64,
+    kind: PowerupKind,
+    transform: Transform,
+) -> Entity {
+    commands
+        .spawn()
+        .insert_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: kind.color(),
+                custom_size: Some(Vec2::new(POWERUP_SIZE, POWERUP_SIZE)),
+                ..Default::default()
+            },
+            transform,
+            ..Default::default()
+        })
+        .insert(Powerup { id, kind })
+        .insert(GameItem)
+        .insert(Name::new("Powerup"))
+        .insert(NetComp::<Transform, MyTransform>::new(true, CNetDir::From, SNetDir::to_all()))
+        .insert(NetEntity::new(id))
+        .id()
+}
+
+/// Gives broken bricks a chance to drop a powerup.
+fn spawn_powerups(
+    authority: Authority,
+    mut net_ids: ResMut<NetIds>,
+    mut e_brick_broken: EventReader<BrickBrokenE>,
+    mut commands: Commands,
+) {
+    if !authority.runs_match() {
+        return;
+    }
+    let mut rng = rand::thread_rng();
+
+    for broken in e_brick_broken.iter() {
+        if rng.gen::<f32>() >= POWERUP_CHANCE {
+            continue;
+        }
+        let kind = PowerupKind::ALL[rng.gen_range(0, PowerupKind::ALL.len())];
+        let id = net_ids.next();
+        let transform = Transform::from_translation(broken.0.extend(1.0));
+        let e = spawn_powerup(&mut commands, id, kind, transform);
+        // Drift away from the side that the brick was on, towards the team that broke it.
+        commands.entity(e).insert(Drift(-broken.0.x.signum()));
+
+        let spawn = PowerupSpawn {
+            id,
+            kind,
+            transform: transform.into(),
+        };
+        if let Err(e) = authority.broadcast(&spawn) {
+            warn!("Failed to broadcast the powerup spawn: {}", e);
+        }
+    }
+}
+
+/// Moves the powerups, and removes the ones that leave the arena.
+fn move_powerups(
+    authority: Authority,
+    time: Res<Time>,
+    level: Res<CurrentLevel>,
+    mut q_powerup: Query<(Entity, &Powerup, &Drift, &mut Transform)>,
+    mut commands: Commands,
+) {
+    if !authority.runs_match() {
+        return;
+    }
+
+    for (e, powerup, drift, mut transform) in q_powerup.iter_mut() {
+        transform.translation.x += drift.0 * POWERUP_SPEED * time.delta_seconds();
+        if transform.translation.x.abs() > level.0.size.x / 2.0 {
+            commands.entity(e).despawn();
+            if let Err(e) = authority.broadcast(&PowerupDespawn { id: powerup.id }) {
+                warn!("Failed to broadcast the powerup despawn: {}", e);
+            }
+        }
+    }
+}
+
+/// Gives the effect of a powerup to the team whose paddle touches it.
+fn collect_powerups(
+    authority: Authority,
+    mut net_ids: ResMut<NetIds>,
+    mut effects: ResMut<ActiveEffects>,
+    assets: Option<Res<AssetServer>>,
+    q_powerup: Query<(Entity, &Powerup, &Transform)>,
+    q_paddle: Query<(&Transform, &Sprite, &Paddle)>,
+    q_ball: Query<(&Transform, &Velocity, Option<&Stuck>), With<Ball>>,
+    mut commands: Commands,
+) {
+    if !authority.runs_match() {
+        return;
+    }
+
+    let mut changed = false;
+    for (e, powerup, transform) in q_powerup.iter() {
+        let pos = transform.translation;
+        let collector = q_paddle.iter().find(|(paddle_t, sprite, _)| {
+            let size = sprite.custom_size.unwrap_or_default();
+            (pos.x - paddle_t.translation.x).abs() < (size.x + POWERUP_SIZE) / 2.0
+                && (pos.y - paddle_t.translation.y).abs() < (size.y + POWERUP_SIZE) / 2.0
+        });
+        let collector = match collector {
+            Some((_, _, paddle)) => paddle.0,
+            None => continue,
+        };
+
+        commands.entity(e).despawn();
+        if let Err(e) = authority.broadcast(&PowerupDespawn { id: powerup.id }) {
+            warn!("Failed to broadcast the powerup despawn: {}", e);
+        }
+        let kind = powerup.kind;
+        let team = kind.affects(collector);
+        info!("{:?} collected {:?}", collector, kind);
+
+        let duration = match kind.duration() {
+            Some(duration) => duration.as_secs_f32(),
+            None => {
+                // Multi-ball is the only effect that happens once. Stuck balls don't move, so
+                // only the moving ones split.
+                let mut count = q_ball.iter().count();
+                let balls: Vec<_> = q_ball
+                    .iter()
+                    .filter(|(_, _, stuck)| stuck.is_none())
+                    .map(|(t, v, _)| (*t, *v))
+                    .collect();
+                for (transform, velocity) in balls {
+                    let dir = velocity.linear.normalize_or_zero();
+                    let side = Vec3::new(-dir.y, dir.x, 0.0);
+                    for angle in [-0.3_f32, 0.3] {
+                        if count >= MAX_BALLS {
+                            break;
+                        }
+                        count += 1;
+                        let id = net_ids.next();
+                        let velocity =
+                            Velocity::from_linear(Quat::from_rotation_z(angle) * velocity.linear);
+                        let mut transform = transform;
+                        transform.translation += side * angle.signum() * SPLIT_OFFSET;
+                        spawn_ball(&mut commands, assets.as_deref(), id, transform, velocity);
+                        let spawn = BallSpawn {
+                            id,
+                            transform: transform.into(),
+                            velocity: velocity.into(),
+                        };
+                        if let Err(e) = authority.broadcast(&spawn) {
+                            warn!("Failed to broadcast the ball spawn: {}", e);
+                        }
+                    }
+                }
+                continue;
+            }
+        };
+
+        changed = true;
+        match effects.0.iter_mut().find(|e| e.team == team && e.kind == kind) {
+            // Collecting the same powerup again only resets the time.
+            Some(effect) => effect.remaining = duration,
+            None => {
+                effects.0.push(Effect {
+                    team,
+                    kind,
+                    remaining: duration,
+                });
+            }
+        }
+    }
+
+    if changed {
+        if let Err(e) = authority.broadcast(&*effects) {
+            warn!("Failed to broadcast the effects: {}", e);
+        }
+    }
+}
+
+/// Spawns and despawns the powerups that the server spawned, and follows the effects.
+fn follow_powerups(
+    client: Option<Res<Client>>,
+    server: Option<Res<Server>>,
+    q_powerup: Query<(Entity, &Powerup)>,
+    mut commands: Commands,
+) {
+    // The host already has the real state.
+    if server.is_some() {
+        return;
+    }
+    let client = match client {
+        Some(client) => client,
+        None => return,
+    };
+
+    for msg in client.recv::<PowerupSpawn>() {
+        spawn_powerup(&mut commands, msg.id, msg.kind, msg.transform.into());
+    }
+    let despawned: Vec<_> = client.recv::<PowerupDespawn>().map(|msg| msg.id).collect();
+    for (e, powerup) in q_powerup.iter() {
+        if despawned.contains(&powerup.id) {
+            commands.entity(e).despawn();
+        }
+    }
+    for msg in client.recv::<ActiveEffects>() {
+        commands.insert_resource(msg.m.clone());
+    }
+}
+
+/// Counts down the effects, and ends the ones that are over.
+fn tick_effects(
+    authority: Authority,
+    time: Res<Time>,
+    mut effects: ResMut<ActiveEffects>,
+) {
+    let before = effects.0.len();
+    for effect in effects.0.iter_mut() {
+        effect.remaining -= time.delta_seconds();
+    }
+    effects.0.retain(|effect| effect.remaining > 0.0);
+
+    // The clients wait for the server to send the new effects.
+    if !authority.runs_match() || effects.0.len() == before {
+        return;
+    }
+    if let Err(e) = authority.broadcast(&*effects) {
+        warn!("Failed to broadcast the effects: {}", e);
+    }
+}
+
+/// Scales the velocity of the balls when the speed factor of the effects changes.
+fn scale_ball_speed(
+    authority: Authority,
+    effects: Res<ActiveEffects>,
+    mut applied: ResMut<AppliedSpeed>,
+    mut q_ball: Query<&mut Velocity, With<Ball>>,
+) {
+    // The clients get the velocity synced.
+    if !authority.runs_match() {
+        return;
+    }
+    let factor = effects.speed_factor();
+    if factor == applied.0 {
+        return;
+    }
+    for mut velocity in q_ball.iter_mut() {
+        velocity.linear *= factor / applied.0;
+    }
+    applied.0 = factor;
+}
+
+/// Makes the paddles match their grow and shrink effects.
+fn resize_paddles(
+    effects: Res<ActiveEffects>,
+    mut q_paddle: Query<(&mut Sprite, &mut CollisionShape, &Paddle)>,
+) {
+    for (mut sprite, mut shape, paddle) in q_paddle.iter_mut() {
+        let size = Vec2::new(PADDLE_WIDTH, PADDLE_HEIGHT * effects.paddle_factor(paddle.0));
+        // Only touch the shape when it changes, as that rebuilds the collider.
+        if sprite.custom_size != Some(size) {
+            sprite.custom_size = Some(size);
+            *shape = CollisionShape::Cuboid {
+                half_extends: (size / 2.0).extend(0.0),
+                border_radius: None,
+            };
+        }
+    }
+}
+
+/// Spawns the shields of the teams that have the effect, and despawns the others.
+fn sync_shields(
+    effects: Res<ActiveEffects>,
+    level: Res<CurrentLevel>,
+    q_shield: Query<(Entity, &Shield)>,
+    mut commands: Commands,
+) {
+    for team in [Team::Left, Team::Right] {
+        let shield = q_shield.iter().find(|(_, shield)| shield.0 == team);
+        match (effects.has(team, PowerupKind::Shield), shield) {
+            (true, None) => {
+                // Just in front of the target.
+                let target = level.0.target(team);
+                let size = Vec2::new(12.0, target.size + 55.0);
+                let x = target.center.x - target.center.x.signum() * (target.size / 2.0 + 12.0);
+                commands
+                    .spawn()
+                    .insert_bundle(SpriteBundle {
+                        sprite: Sprite {
+                            color: PowerupKind::Shield.color(),
+                            custom_size: Some(size),
+                            ..Default::default()
+                        },
+                        transform: Transform::from_xyz(x, target.center.y, 0.0),
+                        ..Default::default()
+                    })
+                    .insert(CollisionShape::Cuboid {
+                        half_extends: (size / 2.0).extend(0.0),
+                        border_radius: None,
+                    })
+                    .insert(RigidBody::Static)
+                    .insert(PhysicMaterial {
+                        restitution: 1.0,
+                        ..Default::default()
+                    })
+                    .insert(GameItem)
+                    .insert(Shield(team))
+                    .insert(Name::new("Shield"));
+            }
+            (false, Some((e, _))) => commands.entity(e).despawn(),
+            _ => {}
+        }
+    }
+}
+
+/// Catches the balls that hit a sticky paddle.
+fn stick_balls(
+    authority: Authority,
+    effects: Res<ActiveEffects>,
+    mut collisions: EventReader<CollisionEvent>,
+    mut q_ball: Query<(&Transform, &mut Velocity), (With<Ball>, Without<Stuck>)>,
+    q_paddle: Query<(&Transform, &Paddle)>,
+    mut commands: Commands,
+) {
+    if !authority.runs_match() {
+        return;
+    }
+
+    for collision in collisions.iter() {
+        if let CollisionEvent::Started(d1, d2) = collision {
+            let e1 = d1.rigid_body_entity();
+            let e2 = d2.rigid_body_entity();
+            let (ball_e, paddle_e) = if q_ball.get(e1).is_ok() { (e1, e2) } else { (e2, e1) };
+
+            let (paddle_t, paddle) = match q_paddle.get(paddle_e) {
+                Ok(paddle) => paddle,
+                Err(_) => continue,
+            };
+            if !effects.has(paddle.0, PowerupKind::Sticky) {
+                continue;
+            }
+            if let Ok((ball_t, mut velocity)) = q_ball.get_mut(ball_e) {
+                let mut offset = ball_t.translation - paddle_t.translation;
+                // Keep the ball just in front of the paddle.
+                offset.x = offset.x.signum() * (PADDLE_WIDTH / 2.0 + 12.0);
+                *velocity = Velocity::default();
+                commands.entity(ball_e).insert(Stuck {
+                    paddle: paddle_e,
+                    offset,
+                    timer: Timer::new(STICK_TIME, false),
+                });
+            }
+        }
+    }
+}
+
+/// Moves the stuck balls with their paddle, and launches them after a moment.
+fn carry_stuck_balls(
+    time: Res<Time>,
+    round_over: Option<Res<RoundOverR>>,
+    applied: Res<AppliedSpeed>,
+    mut q_ball: Query<(Entity, &mut Transform, &mut Velocity, &mut Stuck), With<Ball>>,
+    q_paddle: Query<&Transform, (With<Paddle>, Without<Ball>)>,
+    mut commands: Commands,
+) {
+    for (e, mut transform, mut velocity, mut stuck) in q_ball.iter_mut() {
+        let paddle_t = match q_paddle.get(stuck.paddle) {
+            // The ball is served again at the start of the next round.
+            Ok(paddle_t) if round_over.is_none() => paddle_t,
+            _ => {
+                commands.entity(e).remove::<Stuck>();
+                continue;
+            }
+        };
+        transform.translation = paddle_t.translation + stuck.offset;
+
+        if stuck.timer.tick(time.delta()).finished() {
+            commands.entity(e).remove::<Stuck>();
+            *velocity = Velocity::from_linear(Vec3::new(
+                stuck.offset.x.signum() * LAUNCH_SPEED * applied.0,
+                0.0,
+                0.0,
+            ));
+        }
+    }
+}
+
+fn update_effects_hud(
+    effects: Res<ActiveEffects>,
+    players: Res<Players>,
+    mut q_effects: Query<&mut Text, With<EffectsCounter>>,
+) {
+    let lines: Vec<_> = effects
+        .0
+        .iter()
+        .map(|effect| {
+            let name = players
+                .get(effect.team)
+                .map(|(_, name)| name.clone())
+                .unwrap_or_else(|| format!("{:?}", effect.team));
+            format!(
+                "{}: {} {}s",
+                name,
+                effect.kind.label(),
+                effect.remaining.ceil() as u32
+            )
+        })
+        .collect();
+
+    for mut text in q_effects.iter_mut() {
+        text.sections[0].value = lines.join("\n");
+    }
+}
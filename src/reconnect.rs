@@ -0,0 +1,344 @@
+//! Lets players rejoin a game in progress after their connection drops.
+use crate::game::{spawn_overlay, DisconnectedR, GameWinE, Paddle, Team};
+use crate::level::CurrentLevel;
+use crate::lobby::{
+    check_connection, Bans, JoinPassword, LobbyPassword, Players, SessionToken, Sessions,
+    SpectatorCap,
+};
+use crate::messages::{ConnectionBroadcast, MatchPause, ProtocolVersion, RejectReason};
+use crate::snapshot::MatchState;
+use crate::{Connection, GameIp, GameState, MyTransform, Name, Response};
+use bevy::prelude::*;
+use bevy_pigeon::sync::{CNetDir, NetComp, SNetDir};
+use carrier_pigeon::net::{CIdSpec, Config};
+use carrier_pigeon::{CId, Client, MsgTableParts, OptionPendingClient, Server};
+use heron::PhysicsTime;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a dropped player has to reconnect before they forfeit.
+pub const RECONNECT_GRACE: Duration = Duration::from_secs(30);
+/// How long the client waits between reconnection attempts.
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct ReconnectPlugin;
+
+impl Plugin for ReconnectPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Reconnecting>().add_system_set(
+            SystemSet::on_update(GameState::Game)
+                .with_system(handle_rejoins)
+                .with_system(follow_connections)
+                .with_system(forfeit_after_grace)
+                .with_system(reconnect_client)
+                .with_system(handle_pause),
+        );
+    }
+}
+
+/// The teams whose player dropped and may still reconnect, with the instant they dropped.
+///
+/// Only used by the server.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct Reconnecting(pub HashMap<Team, Instant>);
+
+/// The state of the client while it tries to reconnect.
+pub struct ReconnectR {
+    /// When the connection was lost.
+    pub since: Instant,
+    /// The timer for the next attempt.
+    pub retry: Timer,
+}
+
+impl ReconnectR {
+    /// Starts reconnecting now.
+    pub fn start() -> Self {
+        ReconnectR {
+            since: Instant::now(),
+            // Make the first attempt right away.
+            retry: Timer::new(Duration::ZERO, false),
+        }
+    }
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// The text that is shown while reconnecting.
+pub struct ReconnectOverlay;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// The text that is shown while the game is paused.
+struct PauseOverlay;
+
+/// Gets the direction that a paddle is synced in, on the server.
+pub fn paddle_s_dir(owner: CId) -> SNetDir {
+    SNetDir::ToFrom(CIdSpec::Except(owner), CIdSpec::Only(owner))
+}
+
+/// Accepts players that rejoin with their session token, and new spectators.
+fn handle_rejoins(
+    server: Option<ResMut<Server>>,
+    version: Res<ProtocolVersion>,
+    bans: Res<Bans>,
+    password: Res<LobbyPassword>,
+    spectator_cap: Res<SpectatorCap>,
+    sessions: Res<Sessions>,
+    current: Res<CurrentLevel>,
+    mut players: ResMut<Players>,
+    mut reconnecting: ResMut<Reconnecting>,
+    mut physics_time: ResMut<PhysicsTime>,
+    state: MatchState,
+    q_paddle: Query<(Entity, &Paddle)>,
+    mut commands: Commands,
+) {
+    let mut server = match server {
+        Some(server) => server,
+        None => return,
+    };
+
+    let mut rejoined = vec![];
+    let mut broadcasts = vec![];
+    server.handle_new_cons(|cid, c: Connection| {
+        let session = c.session.unwrap_or_default();
+        // Players that rejoin got in before, even if the password changed since.
+        let password = password.0.as_deref().filter(|_| !sessions.0.contains_key(&session));
+        if let Err(reason) = check_connection(&c, *version, &bans, password) {
+            info!("Rejecting connection {} ({}): {}", cid, c.name, reason);
+            return (false, Response::Rejected(reason));
+        }
+        let others = players.members();
+        match sessions.0.get(&session) {
+            Some(team) if reconnecting.0.remove(team).is_some() => {
+                info!("{} rejoined as connection {}", c.name, cid);
+                players.set_cid(*team, cid);
+                rejoined.push((cid, *team));
+                broadcasts.push(ConnectionBroadcast::new(c.name, cid, Some(*team)));
+                let accepted = Response::Accepted {
+                    cid,
+                    team: Some(*team),
+                    others: vec![],
+                    session,
+                    game: Some(current.0.start_msg()),
+                };
+                (true, accepted)
+            }
+            // The player is still connected, or already forfeited.
+            Some(_) => {
+                info!(
+                    "Rejecting connection {} ({}): game in progress",
+                    cid, c.name
+                );
+                (false, Response::Rejected(RejectReason::GameInProgress))
+            }
+            // Anyone else can still watch.
+            _ if players.add_spectator(cid, c.name.clone(), spectator_cap.0) => {
+                info!("{} joined as a spectator", c.name);
+                broadcasts.push(ConnectionBroadcast::new(c.name, cid, None));
+                let accepted = Response::Accepted {
+                    cid,
+                    team: None,
+                    others,
+                    session: rand::random(),
+                    game: Some(current.0.start_msg()),
+                };
+                (true, accepted)
+            }
+            _ => {
+                info!(
+                    "Rejecting connection {} ({}): game in progress",
+                    cid, c.name
+                );
+                (false, Response::Rejected(RejectReason::GameInProgress))
+            }
+        }
+    });
+
+    for broadcast in broadcasts {
+        if let Err(e) = server.broadcast(&broadcast) {
+            warn!("Failed to broadcast the new connection: {}", e);
+        }
+    }
+    if rejoined.is_empty() {
+        return;
+    }
+
+    // Sync the paddle with the new connection.
+    for (e, paddle) in q_paddle.iter() {
+        if let Some((cid, _)) = rejoined.iter().find(|(_, team)| *team == paddle.0) {
+            commands
+                .entity(e)
+                .insert(NetComp::<Transform, MyTransform>::new(
+                    true,
+                    CNetDir::From,
+                    paddle_s_dir(*cid),
+                ));
+        }
+    }
+
+    let snapshot = state.snapshot(!reconnecting.0.is_empty());
+    for (cid, _) in rejoined {
+        if let Err(e) = server.send_to(cid, &snapshot) {
+            warn!("Failed to send the snapshot to {}: {}", cid, e);
+        }
+    }
+
+    if reconnecting.0.is_empty() {
+        info!("All players are connected. Resuming.");
+        physics_time.resume();
+        if let Err(e) = server.broadcast(&MatchPause { waiting_for: None }) {
+            warn!("Failed to broadcast the resume: {}", e);
+        }
+    }
+}
+
+/// Adds the players that rejoin and the spectators that join during the game.
+fn follow_connections(
+    client: Option<Res<Client>>,
+    server: Option<Res<Server>>,
+    mut players: ResMut<Players>,
+) {
+    // The host shares the `Players` with its server.
+    let client = match client {
+        Some(client) if server.is_none() => client,
+        _ => return,
+    };
+    for msg in client.recv::<ConnectionBroadcast>() {
+        players.insert(msg.cid, msg.name.clone(), msg.team);
+    }
+}
+
+/// Awards the win to the other team when a dropped player doesn't rejoin in time.
+fn forfeit_after_grace(
+    mut reconnecting: ResMut<Reconnecting>,
+    mut players: ResMut<Players>,
+    mut e_game_win: EventWriter<GameWinE>,
+) {
+    let expired: Vec<_> = reconnecting
+        .0
+        .iter()
+        .filter(|(_, since)| since.elapsed() > RECONNECT_GRACE)
+        .map(|(team, _)| *team)
+        .collect();
+    for team in expired {
+        info!(
+            "{:?} didn't reconnect in time. {:?} wins.",
+            team,
+            team.other()
+        );
+        reconnecting.0.remove(&team);
+        if let Some((cid, _)) = players.get(team).cloned() {
+            players.remove_cid(cid);
+        }
+        e_game_win.send(GameWinE(team.other()));
+    }
+}
+
+/// Tries to reconnect to the server until it succeeds or the grace period is over.
+fn reconnect_client(
+    reconnect: Option<ResMut<ReconnectR>>,
+    pending: Option<ResMut<OptionPendingClient>>,
+    time: Res<Time>,
+    ip: Res<GameIp>,
+    name: Res<Name>,
+    parts: Res<MsgTableParts>,
+    version: Res<ProtocolVersion>,
+    password: Res<JoinPassword>,
+    token: Option<Res<SessionToken>>,
+    assets: Option<Res<AssetServer>>,
+    q_overlay: Query<Entity, With<ReconnectOverlay>>,
+    mut commands: Commands,
+) {
+    let (mut reconnect, token) = match (reconnect, token) {
+        (Some(reconnect), Some(token)) => (reconnect, token.0),
+        _ => return,
+    };
+
+    let give_up = |commands: &mut Commands, cause: String| {
+        info!("Failed to reconnect: {}", cause);
+        for e in q_overlay.iter() {
+            commands.entity(e).despawn_recursive();
+        }
+        commands.remove_resource::<ReconnectR>();
+        commands.insert_resource(DisconnectedR(Instant::now()));
+        if let Some(ref assets) = assets {
+            spawn_overlay(commands, assets, format!("Disconnected: {}", cause));
+        }
+    };
+
+    if let Some(mut pending) = pending {
+        if !pending.done().unwrap() {
+            return;
+        }
+        match pending.take::<Response>().unwrap() {
+            Ok((client, Response::Accepted { team: Some(_), .. })) => {
+                info!("Reconnected to the server");
+                for e in q_overlay.iter() {
+                    commands.entity(e).despawn_recursive();
+                }
+                commands.remove_resource::<ReconnectR>();
+                commands.insert_resource(client);
+            }
+            // The server may not have noticed that the old connection dropped yet.
+            Ok((_client, Response::Rejected(RejectReason::GameInProgress))) => {
+                info!("Reconnection attempt rejected: the server still has the old connection");
+            }
+            Ok((_client, Response::Rejected(reason))) => {
+                give_up(&mut commands, format!("Rejected: {}", reason));
+            }
+            // The server doesn't know the session anymore, so it only lets us watch.
+            Ok((_client, Response::Accepted { team: None, .. })) => {
+                give_up(&mut commands, "The server forgot the session".to_owned());
+            }
+            Err(e) => info!("Reconnection attempt failed: {}", e),
+        }
+        commands.remove_resource::<OptionPendingClient>();
+    } else if reconnect.since.elapsed() > RECONNECT_GRACE {
+        give_up(&mut commands, "Connection timed out".to_owned());
+    } else if reconnect.retry.tick(time.delta()).finished() {
+        reconnect.retry = Timer::new(RETRY_INTERVAL, false);
+        info!("Trying to reconnect to {}", ip.0);
+        commands.insert_resource(
+            Client::new(
+                ip.0,
+                parts.clone(),
+                Config::default(),
+                Connection::rejoin(name.0.clone(), *version, token)
+                    .with_password(password.0.clone()),
+            )
+            .option(),
+        );
+    }
+}
+
+/// Pauses or resumes the game when the server is waiting for a player to reconnect.
+fn handle_pause(
+    client: Option<Res<Client>>,
+    assets: Option<Res<AssetServer>>,
+    mut physics_time: ResMut<PhysicsTime>,
+    q_overlay: Query<Entity, With<PauseOverlay>>,
+    mut commands: Commands,
+) {
+    let client = match client {
+        Some(client) => client,
+        None => return,
+    };
+
+    for msg in client.recv::<MatchPause>() {
+        for e in q_overlay.iter() {
+            commands.entity(e).despawn_recursive();
+        }
+        match &msg.waiting_for {
+            Some(name) => {
+                physics_time.pause();
+                if let Some(ref assets) = assets {
+                    let overlay = spawn_overlay(
+                        &mut commands,
+                        assets,
+                        format!("Waiting for {} to reconnect...", name),
+                    );
+                    commands.entity(overlay).insert(PauseOverlay);
+                }
+            }
+            None => physics_time.resume(),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! The protocol of the registry, which lists the open games.
+//!
+//! Servers send [`Request::Register`] every [`HEARTBEAT_INTERVAL`] while they can be joined, and
+//! [`Request::Deregister`] when they close. The server browser sends [`Request::List`], which the
+//! registry answers with [`Reply::Games`]. Every packet is a RON string over UDP.
+//!
+//! This is shared with the `bong-registry` binary, so it doesn't use bevy.
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// The address that the registry listens on by default.
+pub const DEFAULT_REGISTRY: &str = "127.0.0.1:5597";
+/// How often a server tells the registry that it is still open.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// The max size of a packet.
+pub const MAX_PACKET: usize = 16 * 1024;
+/// The max number of games in a reply, so that it fits in a packet.
+const MAX_LISTED: usize = 64;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum Request {
+    /// Adds or refreshes the game of the server that sends this.
+    Register(GameInfo),
+    /// Removes the game on the `port` of the server that sends this.
+    Deregister { port: u16 },
+    List,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum Reply {
+    Games(Vec<Listing>),
+}
+
+/// What a server tells the registry about its game.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct GameInfo {
+    pub name: String,
+    /// The port of the game. The ip is the one that the registry got the packet from.
+    pub port: u16,
+    /// How many of the two player slots are taken.
+    pub players: usize,
+    /// The name of the selected level.
+    pub level: String,
+    /// The protocol version of the server.
+    pub version: u64,
+    /// Whether a password is needed to join.
+    pub password: bool,
+}
+
+/// A game in the registry.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct Listing {
+    pub addr: SocketAddr,
+    pub info: GameInfo,
+}
+
+/// Encodes a packet.
+pub fn to_bytes<T: Serialize>(packet: &T) -> Vec<u8> {
+    ron::to_string(packet).unwrap_or_default().into_bytes()
+}
+
+/// Decodes a packet, or `None` if it isn't one.
+pub fn from_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Option<T> {
+    ron::from_str(std::str::from_utf8(bytes).ok()?).ok()
+}
+
+/// Runs the registry on `socket`, listing every game until `expiry` after its last heartbeat.
+///
+/// This only returns if the read timeout can't be set.
+pub fn serve(socket: UdpSocket, expiry: Duration) -> std::io::Result<()> {
+    // Wake up now and then to expire the games, even when nothing is sent.
+    socket.set_read_timeout(Some(expiry.min(Duration::from_secs(1))))?;
+
+    let mut games: HashMap<SocketAddr, (Listing, Instant)> = HashMap::new();
+    let mut buf = [0; MAX_PACKET];
+    loop {
+        let received = socket.recv_from(&mut buf);
+
+        // Expire before answering, so that a list never has expired games.
+        games.retain(|addr, (_, last_seen)| {
+            let alive = last_seen.elapsed() < expiry;
+            if !alive {
+                println!("Expired {}", addr);
+            }
+            alive
+        });
+
+        match received {
+            Ok((len, from)) => match from_bytes(&buf[..len]) {
+                Some(Request::Register(info)) => {
+                    let addr = SocketAddr::new(from.ip(), info.port);
+                    if !games.contains_key(&addr) {
+                        println!("Registered \"{}\" on {}", info.name, addr);
+                    }
+                    games.insert(addr, (Listing { addr, info }, Instant::now()));
+                }
+                Some(Request::Deregister { port }) => {
+                    let addr = SocketAddr::new(from.ip(), port);
+                    if games.remove(&addr).is_some() {
+                        println!("Deregistered {}", addr);
+                    }
+                }
+                Some(Request::List) => {
+                    let mut listings: Vec<_> =
+                        games.values().map(|(listing, _)| listing.clone()).collect();
+                    listings.sort_by(|a, b| a.info.name.cmp(&b.info.name));
+                    listings.truncate(MAX_LISTED);
+                    if let Err(e) = socket.send_to(&to_bytes(&Reply::Games(listings)), from) {
+                        eprintln!("Failed to send the games to {}: {}", from, e);
+                    }
+                }
+                None => {}
+            },
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            // A browser that closed before its reply arrived.
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => {}
+            Err(e) => eprintln!("Failed to receive: {}", e),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Lets the players play again after a match without reconnecting.
+use crate::bot::BOT_CID;
+use crate::game::{Authority, Team};
+use crate::generator::LayoutSettings;
+use crate::level::{CurrentLevel, Levels};
+use crate::lobby::{
+    disconnect_players, leave_for_missing_level, start_game, swap_teams, Players, Sessions,
+};
+use crate::messages::{DisconnectReason, RematchStatus, RematchVote, StartGame, SwapSides};
+use crate::rounds::MatchRules;
+use crate::GameState;
+use bevy::prelude::*;
+use carrier_pigeon::{Client, Server};
+use std::collections::HashMap;
+
+pub struct RematchPlugin {
+    /// Whether the game is running without a window or audio.
+    pub headless: bool,
+}
+
+impl Plugin for RematchPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RematchVotes>()
+            .add_system_set(SystemSet::on_enter(GameState::GameOver).with_system(setup_votes))
+            .add_system_set(
+                SystemSet::on_update(GameState::GameOver)
+                    .with_system(handle_votes)
+                    .with_system(follow_rematch),
+            );
+
+        if self.headless {
+            app.add_system_set(
+                SystemSet::on_update(GameState::GameOver).with_system(close_after_leave),
+            );
+        }
+    }
+}
+
+/// The rematch votes of the players.
+///
+/// Only used by the server.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct RematchVotes(pub HashMap<Team, bool>);
+
+impl RematchVotes {
+    /// Gets the state of the vote, given the players that are still connected.
+    pub fn status(&self, players: &Players) -> RematchStatus {
+        let mut status = RematchStatus::default();
+        for team in [Team::Left, Team::Right] {
+            match (players.get(team), self.0.get(&team)) {
+                (None, _) | (_, Some(false)) => status.left.push(team),
+                (Some(_), Some(true)) => status.accepted.push(team),
+                (Some(_), None) => {}
+            }
+        }
+        status
+    }
+}
+
+fn setup_votes(mut commands: Commands, players: Res<Players>) {
+    let mut votes = RematchVotes::default();
+    // The bot is always up for another one.
+    if let Some(team) = players.team_of(BOT_CID) {
+        votes.0.insert(team, true);
+    }
+    commands.insert_resource(votes);
+    commands.insert_resource(RematchStatus::default());
+}
+
+/// Counts the votes, and starts the rematch once both players want one.
+fn handle_votes(
+    authority: Authority,
+    rules: Res<MatchRules>,
+    levels: Res<Levels>,
+    layout: Res<LayoutSettings>,
+    mut current: ResMut<CurrentLevel>,
+    mut players: ResMut<Players>,
+    mut sessions: ResMut<Sessions>,
+    mut votes: ResMut<RematchVotes>,
+    mut game_state: ResMut<State<GameState>>,
+) {
+    if !authority.runs_match() {
+        return;
+    }
+
+    // Someone leaving changes the status too, and local votes are made directly.
+    let mut changed = players.is_changed() || votes.is_changed();
+    if let Some(server) = authority.server() {
+        for msg in server.recv::<RematchVote>() {
+            // Spectators don't get a vote.
+            if let Some(team) = players.team_of(msg.cid) {
+                votes.0.insert(team, msg.0);
+                changed = true;
+            }
+        }
+    }
+    if !changed {
+        return;
+    }
+
+    let status = votes.status(&players);
+    if let Err(e) = authority.broadcast(&status) {
+        warn!("Failed to broadcast the rematch status: {}", e);
+    }
+
+    if status.accepted.len() == 2 {
+        info!("Both players want a rematch. Starting the game.");
+        if rules.swap_sides {
+            swap_teams(authority.server(), &mut players, &mut sessions);
+        }
+        // Play the same level again, with new bricks if they are generated.
+        if let Some(level) = levels.get(&current.0.id) {
+            start_game(authority.server(), level.with_layout(layout.pick()), &mut current);
+            let _ = game_state.set(GameState::Game);
+        }
+    }
+}
+
+/// Follows the rematch vote on the clients.
+fn follow_rematch(
+    client: Option<ResMut<Client>>,
+    server: Option<Res<Server>>,
+    levels: Res<Levels>,
+    mut current: ResMut<CurrentLevel>,
+    mut players: ResMut<Players>,
+    mut game_state: ResMut<State<GameState>>,
+    mut commands: Commands,
+) {
+    let mut client = match client {
+        Some(client) => client,
+        None => return,
+    };
+
+    for status in client.recv::<RematchStatus>() {
+        commands.insert_resource(status.m.clone());
+    }
+    // The host shares the `Players` with its server, which already swapped them.
+    if client.recv::<SwapSides>().count() >= 1 && server.is_none() {
+        players.swap_sides();
+    }
+    let start = client.recv::<StartGame>().last().map(|msg| msg.m.clone());
+    if let Some(start) = start {
+        match levels.start(&start) {
+            Some(level) => {
+                current.0 = level;
+                let _ = game_state.set(GameState::Game);
+            }
+            None => leave_for_missing_level(&mut client, &start.level, &mut commands),
+        }
+    }
+}
+
+/// Closes the match once a player leaves, so that the headless server can host another one.
+fn close_after_leave(
+    server: Option<ResMut<Server>>,
+    players: Res<Players>,
+    votes: Res<RematchVotes>,
+    mut game_state: ResMut<State<GameState>>,
+    mut commands: Commands,
+) {
+    let mut server = match server {
+        Some(server) => server,
+        None => return,
+    };
+    if votes.status(&players).left.is_empty() {
+        return;
+    }
+
+    info!("A player left. Closing the match.");
+    disconnect_players(&mut server, &players, DisconnectReason::ServerClosed);
+    commands.remove_resource::<Server>();
+    // Goes back to the lobby through the menu, so that the port is free again.
+    let _ = game_state.set(GameState::Menu);
+}
@@ -0,0 +1,290 @@
+//! Splits a match into rounds, so that the first team to win enough rounds wins the match.
+use crate::game::{spawn_bricks, Authority, Ball, Brick, GameItem, GameWinE, GameWinR, Team};
+use crate::level::{CurrentLevel, Level};
+use crate::messages::{BallDespawn, NewRound, Score};
+use crate::powerups::AppliedSpeed;
+use crate::GameState;
+use bevy::prelude::*;
+use carrier_pigeon::Client;
+use heron::Velocity;
+use std::time::{Duration, Instant};
+
+/// How long the ball waits in the middle before the next round is served.
+const ROUND_DELAY: Duration = Duration::from_secs(2);
+/// The speed that the ball is served at.
+const SERVE_SPEED: f32 = 750.0;
+
+pub struct RoundsPlugin;
+
+impl Plugin for RoundsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<RoundWinE>()
+            .init_resource::<MatchRules>()
+            .init_resource::<Score>()
+            .add_system_set(
+                SystemSet::on_enter(GameState::Game)
+                    .with_system(setup_rounds)
+                    .with_system(setup_scoreboard),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameState::Game)
+                    .with_system(score_rounds)
+                    .with_system(serve_next_round)
+                    .with_system(handle_rounds)
+                    .with_system(update_scoreboard),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Game).with_system(clean_up));
+    }
+}
+
+/// How a match is played.
+///
+/// Only used by the server.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+pub struct MatchRules {
+    /// The number of rounds that a team has to win to win the match.
+    pub first_to: u32,
+    /// Whether the bricks are rebuilt at the start of every round.
+    pub rebuild_bricks: bool,
+    /// Whether the players swap sides for a rematch.
+    pub swap_sides: bool,
+}
+
+impl Default for MatchRules {
+    fn default() -> Self {
+        MatchRules {
+            first_to: 3,
+            rebuild_bricks: true,
+            swap_sides: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// The round win event.
+pub struct RoundWinE(pub Team);
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// The instant that the last round was won, and the team that won it.
+///
+/// The ball waits in the middle while this exists.
+pub struct RoundOverR(pub Instant, pub Team);
+
+/// The winner of the match, which is shown on the game over screen with the [`Score`].
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub struct MatchResult {
+    pub winner: String,
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// The text field with the score.
+struct ScoreCounter;
+
+fn setup_rounds(mut commands: Commands) {
+    commands.insert_resource(Score::default());
+    commands.remove_resource::<RoundOverR>();
+    commands.remove_resource::<MatchResult>();
+}
+
+fn setup_scoreboard(mut commands: Commands, assets: Option<Res<AssetServer>>) {
+    // There is no UI when headless.
+    let assets = match assets {
+        Some(assets) => assets,
+        None => return,
+    };
+    let font = assets.load("FiraMono-Medium.ttf");
+
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(0.0),
+                    right: Val::Px(0.0),
+                    left: Val::Auto,
+                    bottom: Val::Auto,
+                },
+                padding: Rect::all(Val::Px(5.0)),
+                ..default()
+            },
+            text: Text::with_section(
+                score_text(Score::default()),
+                TextStyle {
+                    font,
+                    font_size: 40.0,
+                    color: Color::BLACK,
+                },
+                TextAlignment::default(),
+            ),
+            ..default()
+        })
+        .insert(ScoreCounter)
+        .insert(GameItem);
+}
+
+fn score_text(score: Score) -> String {
+    format!("Score: {} - {}", score.left, score.right)
+}
+
+/// Moves a ball to the middle and stops it until the next round.
+///
+/// The server despawns the other balls, so that every round starts with one.
+fn hold_ball(
+    commands: &mut Commands,
+    authority: &Authority,
+    q_ball: &mut Query<(Entity, &mut Transform, &mut Velocity, &Ball)>,
+) {
+    let mut balls = q_ball.iter_mut();
+    if let Some((_, mut transform, mut velocity, _)) = balls.next() {
+        *transform = Transform::default();
+        *velocity = Velocity::default();
+    }
+    if !authority.runs_match() {
+        return;
+    }
+    for (e, _, _, ball) in balls {
+        commands.entity(e).despawn();
+        if let Err(e) = authority.broadcast(&BallDespawn { id: ball.0 }) {
+            warn!("Failed to broadcast the ball despawn: {}", e);
+        }
+    }
+}
+
+/// Counts the round wins, and ends the match when a team has won enough rounds.
+fn score_rounds(
+    authority: Authority,
+    rules: Res<MatchRules>,
+    mut score: ResMut<Score>,
+    round_over: Option<Res<RoundOverR>>,
+    game_win: Option<Res<GameWinR>>,
+    mut q_ball: Query<(Entity, &mut Transform, &mut Velocity, &Ball)>,
+    mut e_round_win: EventReader<RoundWinE>,
+    mut e_game_win: EventWriter<GameWinE>,
+    mut commands: Commands,
+) {
+    // The ball can touch a target for more than one frame, so only count one win.
+    let team = match e_round_win.iter().last() {
+        Some(win) => win.0,
+        None => return,
+    };
+    if round_over.is_some() || game_win.is_some() {
+        return;
+    }
+    if !authority.runs_match() {
+        return;
+    }
+
+    score.add(team);
+    info!(
+        "{:?} wins the round. The score is {} - {}.",
+        team, score.left, score.right
+    );
+    if let Err(e) = authority.broadcast(&*score) {
+        warn!("Failed to broadcast the score: {}", e);
+    }
+
+    if score.get(team) >= rules.first_to {
+        e_game_win.send(GameWinE(team));
+    } else {
+        commands.insert_resource(RoundOverR(Instant::now(), team));
+        hold_ball(&mut commands, &authority, &mut q_ball);
+    }
+}
+
+/// Serves the ball for the next round, after a short delay.
+fn serve_next_round(
+    authority: Authority,
+    rules: Res<MatchRules>,
+    round_over: Option<Res<RoundOverR>>,
+    level: Res<CurrentLevel>,
+    applied: Res<AppliedSpeed>,
+    mut q_ball: Query<&mut Velocity, With<Ball>>,
+    q_brick: Query<Entity, With<Brick>>,
+    mut commands: Commands,
+) {
+    let round_over = match round_over {
+        Some(round_over) if authority.runs_match() => round_over,
+        _ => return,
+    };
+    if round_over.0.elapsed() < ROUND_DELAY {
+        return;
+    }
+
+    // Serve towards the winner, so that the loser attacks first.
+    let dir = match round_over.1 {
+        Team::Left => -1.0,
+        Team::Right => 1.0,
+    };
+    for mut velocity in q_ball.iter_mut() {
+        *velocity = Velocity::from_linear(Vec3::new(SERVE_SPEED * applied.0 * dir, 0.0, 0.0));
+    }
+    if rules.rebuild_bricks {
+        rebuild_bricks(&mut commands, &level.0, &q_brick);
+    }
+
+    commands.remove_resource::<RoundOverR>();
+    let new_round = NewRound {
+        rebuild_bricks: rules.rebuild_bricks,
+    };
+    if let Err(e) = authority.broadcast(&new_round) {
+        warn!("Failed to broadcast the new round: {}", e);
+    }
+}
+
+/// Follows the rounds that the server announces.
+fn handle_rounds(
+    client: Option<Res<Client>>,
+    authority: Authority,
+    old_score: Res<Score>,
+    level: Res<CurrentLevel>,
+    mut q_ball: Query<(Entity, &mut Transform, &mut Velocity, &Ball)>,
+    q_brick: Query<Entity, With<Brick>>,
+    mut commands: Commands,
+) {
+    // The host already has the real state.
+    if authority.runs_match() {
+        return;
+    }
+    let client = match client {
+        Some(client) => client,
+        None => return,
+    };
+
+    for score in client.recv::<Score>() {
+        commands.insert_resource(*score);
+        let team = if score.left > old_score.left {
+            Team::Left
+        } else {
+            Team::Right
+        };
+        commands.insert_resource(RoundOverR(Instant::now(), team));
+        hold_ball(&mut commands, &authority, &mut q_ball);
+    }
+    for new_round in client.recv::<NewRound>() {
+        commands.remove_resource::<RoundOverR>();
+        if new_round.rebuild_bricks {
+            rebuild_bricks(&mut commands, &level.0, &q_brick);
+        }
+    }
+}
+
+/// Replaces the remaining bricks with a full set.
+fn rebuild_bricks(commands: &mut Commands, level: &Level, q_brick: &Query<Entity, With<Brick>>) {
+    for e in q_brick.iter() {
+        commands.entity(e).despawn();
+    }
+    spawn_bricks(commands, level);
+}
+
+fn update_scoreboard(score: Res<Score>, mut q_score: Query<&mut Text, With<ScoreCounter>>) {
+    if !score.is_changed() {
+        return;
+    }
+    for mut text in q_score.iter_mut() {
+        text.sections[0].value = score_text(*score);
+    }
+}
+
+fn clean_up(mut commands: Commands) {
+    commands.remove_resource::<RoundOverR>();
+}
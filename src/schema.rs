@@ -0,0 +1,415 @@
+//! Describes the shape of a message type, so that the [`ProtocolVersion`] changes with its fields.
+//!
+//! [`describe`] runs the type's `Deserialize` impl against a deserializer that records every struct,
+//! field, enum and variant it is asked for, and hands back placeholder values. Each variant of
+//! every enum is visited once, so the description covers all of them.
+//!
+//! [`ProtocolVersion`]: crate::messages::ProtocolVersion
+use serde::de::value::{StrDeserializer, U32Deserializer};
+use serde::de::{
+    DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
+};
+use serde::Deserializer;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+/// Gets a description of the serialized shape of `T`.
+pub fn describe<T: DeserializeOwned>() -> String {
+    let mut state = State::default();
+    loop {
+        state.out.push('|');
+        // Types with their own validation can fail on the placeholders, which is still the same
+        // every time.
+        if let Err(e) = T::deserialize(Tracer(&mut state)) {
+            state.out.push_str(&e.0);
+        }
+        // Visit the next variant of every enum, until all of them were visited.
+        let mut done = true;
+        for (next, len) in state.enums.values_mut() {
+            if *next + 1 < *len {
+                *next += 1;
+                done = false;
+            }
+        }
+        if done {
+            return state.out;
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    out: String,
+    /// The variant to visit next and the number of variants, by enum name and variants. Enums from
+    /// different modules can share a name.
+    enums: HashMap<(&'static str, &'static [&'static str]), (usize, usize)>,
+}
+
+#[derive(Debug)]
+struct Error(String);
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl serde::de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+struct Tracer<'a>(&'a mut State);
+
+macro_rules! trace_primitive {
+    ($($method:ident => $visit:ident($($value:expr)?)),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                self.0.out.push_str(concat!(stringify!($method), " "));
+                visitor.$visit($($value)?)
+            }
+        )*
+    };
+}
+
+impl<'a, 'de> Deserializer<'de> for Tracer<'a> {
+    type Error = Error;
+
+    trace_primitive! {
+        deserialize_bool => visit_bool(false),
+        deserialize_i8 => visit_i8(0),
+        deserialize_i16 => visit_i16(0),
+        deserialize_i32 => visit_i32(0),
+        deserialize_i64 => visit_i64(0),
+        deserialize_u8 => visit_u8(0),
+        deserialize_u16 => visit_u16(0),
+        deserialize_u32 => visit_u32(0),
+        deserialize_u64 => visit_u64(0),
+        deserialize_f32 => visit_f32(0.0),
+        deserialize_f64 => visit_f64(0.0),
+        deserialize_char => visit_char('\0'),
+        deserialize_str => visit_str(""),
+        deserialize_string => visit_string(String::new()),
+        deserialize_bytes => visit_bytes(&[]),
+        deserialize_byte_buf => visit_byte_buf(Vec::new()),
+        deserialize_unit => visit_unit(),
+        deserialize_any => visit_unit(),
+        deserialize_identifier => visit_unit(),
+        deserialize_ignored_any => visit_unit(),
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.0.out.push_str("option ");
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.0.out.push_str(name);
+        self.0.out.push(' ');
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.0.out.push_str(name);
+        self.0.out.push(' ');
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.0.out.push_str("seq ");
+        visitor.visit_seq(Elements {
+            state: self.0,
+            left: 1,
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.0.out.push_str(&format!("tuple{} ", len));
+        visitor.visit_seq(Elements {
+            state: self.0,
+            left: len,
+        })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.0.out.push_str(&format!("{}{} ", name, len));
+        visitor.visit_seq(Elements {
+            state: self.0,
+            left: len,
+        })
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.0.out.push_str("map ");
+        visitor.visit_map(Entry {
+            state: self.0,
+            left: true,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.0.out.push_str(&format!("{}{:?} ", name, fields));
+        visitor.visit_map(Fields {
+            state: self.0,
+            fields,
+        })
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let (next, _) = *self
+            .0
+            .enums
+            .entry((name, variants))
+            .or_insert((0, variants.len()));
+        self.0
+            .out
+            .push_str(&format!("{}{:?}.{} ", name, variants, next));
+        visitor.visit_enum(Variant {
+            state: self.0,
+            index: next as u32,
+        })
+    }
+}
+
+/// The elements of a sequence or tuple.
+struct Elements<'a> {
+    state: &'a mut State,
+    left: usize,
+}
+
+impl<'a, 'de> SeqAccess<'de> for Elements<'a> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(Tracer(self.state)).map(Some)
+    }
+}
+
+/// The single entry of a map.
+struct Entry<'a> {
+    state: &'a mut State,
+    left: bool,
+}
+
+impl<'a, 'de> MapAccess<'de> for Entry<'a> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        if !self.left {
+            return Ok(None);
+        }
+        self.left = false;
+        seed.deserialize(Tracer(self.state)).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        seed.deserialize(Tracer(self.state))
+    }
+}
+
+/// The fields of a struct, by name.
+struct Fields<'a> {
+    state: &'a mut State,
+    fields: &'static [&'static str],
+}
+
+impl<'a, 'de> MapAccess<'de> for Fields<'a> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.fields.split_first() {
+            Some((field, rest)) => {
+                self.fields = rest;
+                let key: StrDeserializer<Error> = field.into_deserializer();
+                seed.deserialize(key).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        seed.deserialize(Tracer(self.state))
+    }
+}
+
+/// The variant of an enum that is visited in this pass.
+struct Variant<'a> {
+    state: &'a mut State,
+    index: u32,
+}
+
+impl<'a, 'de> EnumAccess<'de> for Variant<'a> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let index: U32Deserializer<Error> = self.index.into_deserializer();
+        Ok((seed.deserialize(index)?, self))
+    }
+}
+
+impl<'a, 'de> VariantAccess<'de> for Variant<'a> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(Tracer(self.state))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        Tracer(self.state).deserialize_tuple(len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_map(Fields {
+            state: self.state,
+            fields,
+        })
+    }
+}
+
+#[cfg(test)]
+// The messages in the tests are only described, never read.
+#[allow(dead_code)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    mod old {
+        use super::*;
+
+        #[derive(Deserialize)]
+        pub struct Message {
+            pub name: String,
+            pub kind: Kind,
+        }
+
+        #[derive(Deserialize)]
+        pub enum Kind {
+            A,
+            B { x: u32 },
+        }
+    }
+
+    mod new_field {
+        use super::*;
+
+        #[derive(Deserialize)]
+        pub struct Message {
+            pub name: String,
+            pub kind: old::Kind,
+            pub password: Option<String>,
+        }
+    }
+
+    mod new_variant_field {
+        use super::*;
+
+        #[derive(Deserialize)]
+        pub struct Message {
+            pub name: String,
+            pub kind: Kind,
+        }
+
+        #[derive(Deserialize)]
+        pub enum Kind {
+            A,
+            B { x: u32, y: u32 },
+        }
+    }
+
+    mod same_name {
+        use super::*;
+
+        #[derive(Deserialize)]
+        pub struct Message {
+            pub first: old::Kind,
+            pub second: Kind,
+        }
+
+        #[derive(Deserialize)]
+        pub enum Kind {
+            C,
+            D,
+            E { z: u32 },
+        }
+    }
+
+    #[test]
+    fn describe_is_stable() {
+        assert_eq!(describe::<old::Message>(), describe::<old::Message>());
+    }
+
+    #[test]
+    fn describe_sees_new_fields() {
+        let old = describe::<old::Message>();
+        assert_ne!(old, describe::<new_field::Message>());
+        // The field is in a variant that isn't the first one.
+        assert_ne!(old, describe::<new_variant_field::Message>());
+    }
+
+    #[test]
+    fn describe_enums_with_the_same_name() {
+        let same_name = describe::<same_name::Message>();
+        // Every variant of both enums is visited.
+        assert!(same_name.contains(r#"Kind["A", "B"].1"#));
+        assert!(same_name.contains(r#"Kind["C", "D", "E"].2"#));
+    }
+
+    #[test]
+    fn describe_messages() {
+        let connection = describe::<crate::messages::Connection>();
+        assert!(connection.contains(r#"Connection["version", "name", "session", "password"]"#));
+        assert_eq!(
+            crate::messages::get_table().1,
+            crate::messages::get_table().1
+        );
+    }
+}
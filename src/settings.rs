@@ -0,0 +1,293 @@
+//! The settings screen, for the audio and video settings.
+//!
+//! The name and address are changed in the menu instead.
+use crate::config::Settings;
+use crate::GameState;
+use bevy::prelude::PositionType::Absolute;
+use bevy::prelude::*;
+use bevy::window::PresentMode;
+
+/// The window sizes that can be picked.
+const RESOLUTIONS: [(u32, u32); 5] = [
+    (1280, 720),
+    (1600, 900),
+    (1920, 1080),
+    (2560, 1440),
+    (3840, 2160),
+];
+/// How much a volume changes with each click.
+const VOLUME_STEP: f32 = 0.1;
+
+pub struct SettingsPlugin;
+
+impl Plugin for SettingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(SystemSet::on_enter(GameState::Settings).with_system(setup_settings))
+            .add_system_set(
+                SystemSet::on_update(GameState::Settings)
+                    .with_system(handle_ui)
+                    .with_system(update_labels),
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Settings).with_system(clean_up));
+    }
+}
+
+/// Gets how the window waits for the screen, with or without vsync.
+pub fn present_mode(vsync: bool) -> PresentMode {
+    if vsync {
+        PresentMode::Fifo
+    } else {
+        PresentMode::Immediate
+    }
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// All settings screen items have this so that they can be cleaned up easily.
+struct SettingsItem;
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// A setting on the screen.
+enum Setting {
+    MasterVolume,
+    SfxVolume,
+    WindowMode,
+    Resolution,
+    Vsync,
+}
+
+impl Setting {
+    const ALL: [Setting; 5] = [
+        Setting::MasterVolume,
+        Setting::SfxVolume,
+        Setting::WindowMode,
+        Setting::Resolution,
+        Setting::Vsync,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Setting::MasterVolume => "Volume",
+            Setting::SfxVolume => "Effects",
+            Setting::WindowMode => "Window",
+            Setting::Resolution => "Resolution",
+            Setting::Vsync => "VSync",
+        }
+    }
+
+    /// Whether the setting is changed with - and + buttons, instead of by clicking it.
+    fn is_volume(&self) -> bool {
+        matches!(self, Setting::MasterVolume | Setting::SfxVolume)
+    }
+
+    fn value(&self, settings: &Settings) -> String {
+        match self {
+            Setting::MasterVolume => format!("{:.0}%", settings.master_volume * 100.0),
+            Setting::SfxVolume => format!("{:.0}%", settings.sfx_volume * 100.0),
+            Setting::WindowMode => settings.window_mode.label().to_owned(),
+            Setting::Resolution => {
+                format!("{}x{}", settings.resolution.0, settings.resolution.1)
+            }
+            Setting::Vsync => if settings.vsync { "On" } else { "Off" }.to_owned(),
+        }
+    }
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+enum SettingsButton {
+    /// Changes the setting, by a step in the direction for volumes.
+    Change(Setting, i8),
+    Back,
+}
+
+#[derive(Component, Copy, Clone, Eq, PartialEq, Debug, Hash)]
+/// The text that shows the value of a setting.
+struct ValueText(Setting);
+
+fn setup_settings(mut commands: Commands, assets: Res<AssetServer>) {
+    let font = assets.load("FiraMono-Medium.ttf");
+    let text_style = TextStyle {
+        font,
+        color: Color::BLACK,
+        font_size: 60.0,
+    };
+    let button_style = Style {
+        size: Size::new(Val::Px(100.0), Val::Px(80.0)),
+        margin: Rect::all(Val::Px(10.0)),
+        justify_content: JustifyContent::Center,
+        align_items: AlignItems::Center,
+        ..default()
+    };
+    let value_style = Style {
+        size: Size::new(Val::Px(500.0), Val::Px(80.0)),
+        ..button_style.clone()
+    };
+    let label_style = Style {
+        size: Size::new(Val::Px(380.0), Val::Auto),
+        margin: Rect::all(Val::Px(10.0)),
+        ..default()
+    };
+
+    // parent
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: Absolute,
+                margin: Rect::all(Val::Auto),
+                padding: Rect::all(Val::Px(10.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                align_items: AlignItems::Center,
+                align_self: AlignSelf::Center,
+                size: Size {
+                    width: Val::Percent(100.0),
+                    height: Val::Auto,
+                },
+                ..default()
+            },
+            color: Color::CRIMSON.into(),
+            ..default()
+        })
+        .insert(SettingsItem)
+        .with_children(|parent| {
+            parent.spawn_bundle(TextBundle {
+                text: Text::with_section("Settings", text_style.clone(), TextAlignment::default()),
+                ..default()
+            });
+
+            for setting in Setting::ALL {
+                parent
+                    .spawn_bundle(NodeBundle {
+                        style: Style {
+                            flex_direction: FlexDirection::Row,
+                            align_items: AlignItems::Center,
+                            ..default()
+                        },
+                        color: Color::NONE.into(),
+                        ..default()
+                    })
+                    .with_children(|parent| {
+                        parent.spawn_bundle(TextBundle {
+                            style: label_style.clone(),
+                            text: Text::with_section(
+                                setting.label(),
+                                text_style.clone(),
+                                TextAlignment::default(),
+                            ),
+                            ..default()
+                        });
+
+                        let mut spawn_button = |label: &str, button: SettingsButton, style: &Style| {
+                            parent
+                                .spawn_bundle(ButtonBundle {
+                                    color: UiColor(Color::rgb_u8(230, 230, 230)),
+                                    style: style.clone(),
+                                    ..default()
+                                })
+                                .insert(button)
+                                .with_children(|parent| {
+                                    let mut text = parent.spawn_bundle(TextBundle {
+                                        text: Text::with_section(
+                                            label,
+                                            text_style.clone(),
+                                            TextAlignment::default(),
+                                        ),
+                                        ..default()
+                                    });
+                                    // The buttons without a label show the value.
+                                    if label.is_empty() {
+                                        text.insert(ValueText(setting));
+                                    }
+                                });
+                        };
+
+                        if setting.is_volume() {
+                            spawn_button("-", SettingsButton::Change(setting, -1), &button_style);
+                            spawn_button("", SettingsButton::Change(setting, 0), &value_style);
+                            spawn_button("+", SettingsButton::Change(setting, 1), &button_style);
+                        } else {
+                            spawn_button("", SettingsButton::Change(setting, 1), &value_style);
+                        }
+                    });
+            }
+
+            parent
+                .spawn_bundle(ButtonBundle {
+                    color: UiColor(Color::rgb_u8(255, 255, 255)),
+                    style: Style {
+                        size: Size::new(Val::Px(400.0), Val::Px(100.0)),
+                        margin: Rect::all(Val::Px(20.0)),
+                        ..button_style.clone()
+                    },
+                    ..default()
+                })
+                .insert(SettingsButton::Back)
+                .with_children(|parent| {
+                    parent.spawn_bundle(TextBundle {
+                        text: Text::with_section("Back", text_style.clone(), TextAlignment::default()),
+                        ..default()
+                    });
+                });
+        });
+}
+
+/// Changes the clicked setting, applies it to the window, and saves it.
+fn handle_ui(
+    q_interaction: Query<(&Interaction, &SettingsButton), Changed<Interaction>>,
+    mut game_state: ResMut<State<GameState>>,
+    mut settings: ResMut<Settings>,
+    mut windows: ResMut<Windows>,
+) {
+    for (interaction, button) in q_interaction.iter() {
+        if *interaction != Interaction::Clicked {
+            continue;
+        }
+        let (setting, step) = match *button {
+            SettingsButton::Change(setting, step) => (setting, step),
+            SettingsButton::Back => {
+                let _ = game_state.set(GameState::Menu);
+                return;
+            }
+        };
+        if step == 0 {
+            continue;
+        }
+
+        let volume_step = VOLUME_STEP * step as f32;
+        match setting {
+            Setting::MasterVolume => {
+                settings.master_volume = (settings.master_volume + volume_step).clamp(0.0, 1.0);
+            }
+            Setting::SfxVolume => {
+                settings.sfx_volume = (settings.sfx_volume + volume_step).clamp(0.0, 1.0);
+            }
+            Setting::WindowMode => settings.window_mode = settings.window_mode.next(),
+            Setting::Resolution => {
+                let current = RESOLUTIONS.iter().position(|r| *r == settings.resolution);
+                let next = current.map_or(0, |i| (i + 1) % RESOLUTIONS.len());
+                settings.resolution = RESOLUTIONS[next];
+            }
+            Setting::Vsync => settings.vsync = !settings.vsync,
+        }
+
+        if let Some(window) = windows.get_primary_mut() {
+            let (width, height) = settings.resolution;
+            window.set_mode(settings.window_mode.into());
+            window.set_resolution(width as f32, height as f32);
+            window.set_present_mode(present_mode(settings.vsync));
+        }
+        if let Err(e) = settings.save() {
+            warn!("Failed to save the settings: {}", e);
+        }
+    }
+}
+
+fn update_labels(settings: Res<Settings>, mut q_value: Query<(&mut Text, &ValueText)>) {
+    for (mut text, value) in q_value.iter_mut() {
+        text.sections[0].value = value.0.value(&settings);
+    }
+}
+
+fn clean_up(mut commands: Commands, q_items: Query<Entity, With<SettingsItem>>) {
+    for e in q_items.iter() {
+        commands.entity(e).despawn_recursive();
+    }
+}
@@ -0,0 +1,227 @@
+//! Keeps the clients' view of the match in sync with the server.
+use crate::game::{spawn_ball, spawn_brick, Ball, Brick, BrickHp, GameWinR, Paddle, Target};
+use crate::level::CurrentLevel;
+use crate::lobby::Players;
+use crate::messages::{ActiveEffects, MatchPhase, MatchSnapshot, Score, SnapshotRequest};
+use crate::reconnect::Reconnecting;
+use crate::GameState;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use carrier_pigeon::{Client, Server};
+use heron::{PhysicsTime, Velocity};
+use std::time::Duration;
+
+/// How often the server sends a snapshot to every client.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct SnapshotPlugin;
+
+impl Plugin for SnapshotPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(
+            SystemSet::on_enter(GameState::Game)
+                .with_system(setup_snapshots)
+                .with_system(request_snapshot),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameState::Game)
+                .with_system(send_snapshots)
+                .with_system(apply_snapshot),
+        );
+    }
+}
+
+/// The timer for sending periodic snapshots.
+struct SnapshotTimer(Timer);
+
+/// The parts of the world that make up a [`MatchSnapshot`].
+#[derive(SystemParam)]
+pub struct MatchState<'w, 's> {
+    q_brick: Query<'w, 's, (&'static Brick, &'static BrickHp)>,
+    q_ball: Query<'w, 's, (&'static Transform, &'static Velocity, &'static Ball)>,
+    q_paddle: Query<'w, 's, (&'static Transform, &'static Paddle)>,
+    q_target: Query<'w, 's, (&'static Transform, &'static Target)>,
+    game_win: Option<Res<'w, GameWinR>>,
+    score: Option<Res<'w, Score>>,
+    effects: Option<Res<'w, ActiveEffects>>,
+}
+
+impl<'w, 's> MatchState<'w, 's> {
+    /// Takes a snapshot of the match.
+    ///
+    /// `paused` is whether the server is waiting for a player to reconnect.
+    pub fn snapshot(&self, paused: bool) -> MatchSnapshot {
+        let phase = if self.game_win.is_some() {
+            MatchPhase::Over
+        } else if paused {
+            MatchPhase::Paused
+        } else {
+            MatchPhase::Playing
+        };
+
+        MatchSnapshot {
+            phase,
+            score: self.score.as_deref().copied().unwrap_or_default(),
+            effects: self.effects.as_deref().cloned().unwrap_or_default(),
+            bricks: self.q_brick.iter().map(|(b, hp)| (b.0, hp.hp)).collect(),
+            balls: self
+                .q_ball
+                .iter()
+                .map(|(t, v, ball)| (ball.0, (*t).into(), (*v).into()))
+                .collect(),
+            paddles: self
+                .q_paddle
+                .iter()
+                .map(|(t, paddle)| (paddle.0, (*t).into()))
+                .collect(),
+            targets: self
+                .q_target
+                .iter()
+                .map(|(t, target)| (target.0, (*t).into()))
+                .collect(),
+        }
+    }
+}
+
+fn setup_snapshots(mut commands: Commands) {
+    commands.insert_resource(SnapshotTimer(Timer::new(SNAPSHOT_INTERVAL, true)));
+}
+
+/// Asks the server for the current state when joining a match.
+fn request_snapshot(client: Option<Res<Client>>, server: Option<Res<Server>>) {
+    // The host already has the real state.
+    if server.is_some() {
+        return;
+    }
+    if let Some(client) = client {
+        if let Err(e) = client.send(&SnapshotRequest) {
+            warn!("Failed to request a snapshot: {}", e);
+        }
+    }
+}
+
+/// Answers snapshot requests, and periodically sends a snapshot to everyone.
+fn send_snapshots(
+    server: Option<Res<Server>>,
+    time: Res<Time>,
+    mut timer: ResMut<SnapshotTimer>,
+    reconnecting: Res<Reconnecting>,
+    state: MatchState,
+) {
+    let server = match server {
+        Some(server) => server,
+        None => return,
+    };
+    let paused = !reconnecting.0.is_empty();
+
+    let requests: Vec<_> = server
+        .recv::<SnapshotRequest>()
+        .map(|msg| msg.cid)
+        .collect();
+    if !requests.is_empty() {
+        let snapshot = state.snapshot(paused);
+        for cid in requests {
+            if let Err(e) = server.send_to(cid, &snapshot) {
+                warn!("Failed to send a snapshot to {}: {}", cid, e);
+            }
+        }
+    }
+
+    if timer.0.tick(time.delta()).just_finished() {
+        if let Err(e) = server.broadcast(&state.snapshot(paused)) {
+            warn!("Failed to broadcast a snapshot: {}", e);
+        }
+    }
+}
+
+/// Makes the client's match match the snapshots from the server.
+fn apply_snapshot(
+    client: Option<Res<Client>>,
+    server: Option<Res<Server>>,
+    players: Res<Players>,
+    level: Res<CurrentLevel>,
+    mut physics_time: ResMut<PhysicsTime>,
+    mut q_brick: Query<(Entity, &Brick, &mut BrickHp)>,
+    assets: Option<Res<AssetServer>>,
+    mut q_ball: Query<(Entity, &mut Transform, &mut Velocity, &Ball)>,
+    mut q_paddle: Query<(&mut Transform, &Paddle), Without<Ball>>,
+    mut q_target: Query<(&mut Transform, &Target), (Without<Ball>, Without<Paddle>)>,
+    mut commands: Commands,
+) {
+    // The host already has the real state.
+    if server.is_some() {
+        return;
+    }
+    let client = match client {
+        Some(client) => client,
+        None => return,
+    };
+
+    for snapshot in client.recv::<MatchSnapshot>() {
+        commands.insert_resource(snapshot.score);
+        commands.insert_resource(snapshot.effects.clone());
+
+        // Despawn the bricks that were broken, and respawn the ones that shouldn't have been.
+        let mut missing = snapshot.bricks.clone();
+        for (e, brick, mut hp) in q_brick.iter_mut() {
+            if let Some(i) = missing.iter().position(|(id, _)| *id == brick.0) {
+                let (_, new_hp) = missing.swap_remove(i);
+                if hp.hp != new_hp {
+                    hp.hp = new_hp;
+                }
+            } else {
+                commands.entity(e).despawn();
+            }
+        }
+        for brick in level.0.brick_defs() {
+            if let Some((_, hp)) = missing.iter().find(|(id, _)| *id == brick.id) {
+                spawn_brick(&mut commands, &brick, *hp);
+            }
+        }
+
+        // Despawn the balls that the server doesn't have, and spawn the ones that are missing.
+        let mut missing = snapshot.balls.clone();
+        for (e, mut t, mut v, ball) in q_ball.iter_mut() {
+            if let Some(i) = missing.iter().position(|(id, _, _)| *id == ball.0) {
+                let (_, transform, velocity) = missing.swap_remove(i);
+                *t = transform.into();
+                *v = velocity.into();
+            } else {
+                commands.entity(e).despawn();
+            }
+        }
+        for (id, transform, velocity) in missing {
+            spawn_ball(
+                &mut commands,
+                assets.as_deref(),
+                id,
+                transform.into(),
+                velocity.into(),
+            );
+        }
+        for (mut t, paddle) in q_paddle.iter_mut() {
+            // Our own paddle is more up to date than the server's copy.
+            if Some(paddle.0) == players.me {
+                continue;
+            }
+            if let Some((_, transform)) =
+                snapshot.paddles.iter().find(|(team, _)| *team == paddle.0)
+            {
+                *t = (*transform).into();
+            }
+        }
+        for (mut t, target) in q_target.iter_mut() {
+            if let Some((_, transform)) =
+                snapshot.targets.iter().find(|(team, _)| *team == target.0)
+            {
+                *t = (*transform).into();
+            }
+        }
+
+        match snapshot.phase {
+            MatchPhase::Playing => physics_time.resume(),
+            MatchPhase::Paused => physics_time.pause(),
+            MatchPhase::Over => {}
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use bong::registry::{self, GameInfo, Listing, Reply, Request, MAX_PACKET};
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+const EXPIRY: Duration = Duration::from_millis(500);
+
+/// Starts a registry on a free port, and returns its address.
+fn start_registry() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || registry::serve(socket, EXPIRY));
+    addr
+}
+
+fn game(name: &str, port: u16) -> GameInfo {
+    GameInfo {
+        name: name.to_owned(),
+        port,
+        players: 1,
+        level: "Classic".to_owned(),
+        version: 0,
+        password: false,
+    }
+}
+
+struct Peer {
+    socket: UdpSocket,
+    registry: SocketAddr,
+}
+
+impl Peer {
+    fn new(registry: SocketAddr) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        Peer { socket, registry }
+    }
+
+    fn send(&self, request: &Request) {
+        self.socket
+            .send_to(&registry::to_bytes(request), self.registry)
+            .unwrap();
+    }
+
+    fn list(&self) -> Vec<Listing> {
+        self.send(&Request::List);
+        let mut buf = [0; MAX_PACKET];
+        let (len, _) = self.socket.recv_from(&mut buf).unwrap();
+        match registry::from_bytes(&buf[..len]) {
+            Some(Reply::Games(games)) => games,
+            None => panic!("Not a reply"),
+        }
+    }
+}
+
+#[test]
+fn register_list_deregister() {
+    let registry = start_registry();
+    let server = Peer::new(registry);
+    let browser = Peer::new(registry);
+    assert_eq!(browser.list(), vec![]);
+
+    server.send(&Request::Register(game("b", 4455)));
+    server.send(&Request::Register(game("a", 4456)));
+    let listed = browser.list();
+    let names: Vec<_> = listed.iter().map(|l| l.info.name.as_str()).collect();
+    assert_eq!(names, ["a", "b"]);
+    assert_eq!(listed[0].addr, "127.0.0.1:4456".parse().unwrap());
+
+    server.send(&Request::Deregister { port: 4455 });
+    let listed = browser.list();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].info, game("a", 4456));
+}
+
+#[test]
+fn games_expire_without_heartbeats() {
+    let registry = start_registry();
+    let server = Peer::new(registry);
+    let browser = Peer::new(registry);
+
+    server.send(&Request::Register(game("a", 4455)));
+    assert_eq!(browser.list().len(), 1);
+
+    thread::sleep(EXPIRY * 2);
+    assert_eq!(browser.list(), vec![]);
+}